@@ -1,17 +1,72 @@
 #!/usr/bin/env python3
 from copy import deepcopy
-from dataclasses import dataclass
-from os import path, makedirs, mkdir, symlink, listdir, stat as os_stat, chmod, unlink, environ
+from functools import partial
+from itertools import count
+from dataclasses import dataclass, field, fields, is_dataclass
+from enum import Enum
+from os import path, makedirs, mkdir, symlink, listdir, stat as os_stat, chmod, unlink, environ, getcwd, rename, walk, readlink, lstat, link, rmdir, replace, getpid, utime
 from queue import SimpleQueue
-from shutil import rmtree
-from sys import stdout, orig_argv
-from typing import List, Dict, Optional, Any, Tuple, Iterable, Union
+from secrets import token_hex
+from shutil import rmtree, unpack_archive, move, which, copytree, copy2
+from sys import stdin, stdout, stderr, orig_argv, platform, executable
+from typing import List, Dict, Optional, Any, Tuple, Iterable, Iterator, Union, Callable, Set
 from tempfile import TemporaryDirectory
+from threading import Lock, Event
+from concurrent.futures import ThreadPoolExecutor
+from contextlib import contextmanager
+from contextvars import ContextVar
+from datetime import datetime, timezone
+from time import time
+import base64
+import difflib
+import hashlib
+import io
 import json
+import re
 import logging
 import stat
 import string
+import shlex
 import subprocess
+import tarfile
+import warnings
+try:
+    import fcntl
+except ImportError:  # windows
+    fcntl = None  # type: ignore
+    import msvcrt
+
+
+# what numng is currently working on (package, git repo, ..) -> attached to every log record
+_log_span: ContextVar[Dict[str, str]] = ContextVar("numng_log_span", default={})
+
+
+@contextmanager
+def log_span(**fields: str):
+    token = _log_span.set({**_log_span.get(), **fields})
+    try:
+        yield
+    finally:
+        _log_span.reset(token)
+
+
+class LogSpanFilter(logging.Filter):
+    def filter(self, record: logging.LogRecord) -> bool:
+        record.span = _log_span.get()
+        record.span_prefix = "".join(f"[{key}={value}] " for key, value in record.span.items())
+        return True
+
+
+class JsonLogFormatter(logging.Formatter):
+    def format(self, record: logging.LogRecord) -> str:
+        return json.dumps({
+            "time": datetime.fromtimestamp(record.created, timezone.utc).isoformat(),
+            "level": record.levelname,
+            "message": record.getMessage(),
+            "thread": record.threadName,
+            **getattr(record, "span", {}),
+            **({"code": error_to_json(record.numng_error)["code"]} if hasattr(record, "numng_error") else {}),
+        })
 
 
 logger = logging.getLogger(__name__)
@@ -20,48 +75,150 @@ log_handler = logging.StreamHandler(stdout)
 log_handler.setLevel(logging.INFO)
 log_formatter = logging.Formatter('%(asctime)s::%(levelname)s: %(message)s')
 log_handler.setFormatter(log_formatter)
+log_handler.addFilter(LogSpanFilter())
 logger.addHandler(log_handler)
 del log_formatter
 
 
 VALID_FILESYSTEM_CHARACTERS: str = "-_. %s%s" % (string.ascii_letters, string.digits)
-BASEDIRECTORY: str = path.join(path.expanduser('~'), ".local", "share", "nushell", "numng")
+
+
+def get_base_directory() -> str:
+    # NUMNG_DATA_DIR > XDG_DATA_HOME > platform default
+    if environ.get("NUMNG_DATA_DIR"):
+        return path.abspath(path.expanduser(environ["NUMNG_DATA_DIR"]))
+    if environ.get("XDG_DATA_HOME"):
+        return path.join(path.abspath(environ["XDG_DATA_HOME"]), "nushell", "numng")
+    if platform == "win32" and environ.get("APPDATA"):
+        return path.join(environ["APPDATA"], "nushell", "numng")
+    return path.join(path.expanduser('~'), ".local", "share", "nushell", "numng")
+
+
+BASEDIRECTORY: str = get_base_directory()  # default data directory (RunContext.base_directory)
+# assumption: nu-config in ~/.config/nushell: https://github.com/nushell/nushell/discussions/9019
+CONFIGDIRECTORY: str = path.join(path.expanduser('~'), ".config", "nushell", "numng")  # default config directory (RunContext.config_directory)
+TRUSTED_BUILDS_FILE_NAME: str = "trusted_builds.json"  # within the config directory
+CONFIG_FILE_NAME: str = "config.json"  # within the config directory
+SELF_UPDATE_URL: str = "https://api.github.com/repos/Jan9103/numng/contents/numng.py?ref=main"
+CONNECTION_POLICIES: Tuple[str, ...] = ("download", "update", "offline")  # download: only missing packages, update: also pull updates
+COMMAND_LOG_FILE_NAME: str = "last_run_commands.jsonl"  # within the data directory
+STORE_LOCK_FILE_NAME: str = "store.lock"  # within the data directory
+STATE_FILE_NAME: str = "numng_state.json"  # within nupm_home
+BUNDLE_FORMAT_VERSION: int = 1
+ENVIRONMENTS_FILE_NAME: str = "environments.json"  # within the data directory: which store paths each package file uses (for gc)
+RESOLUTION_CACHE_FILE_NAME: str = "resolution_cache.json"  # within the data directory: registry lookups (invalidated by the registries commit)
+ARTIFACT_CACHE_DIR_NAME: str = "artifacts"  # within the data directory: build outputs of git packages (commit + build_command + nu/cargo version -> tar)
+ARTIFACT_CACHE_MAX_AGE_DAYS: float = 30  # unused artifacts get removed by `numng gc`
+BUILD_PROGRESS_FILE_NAME: str = "build_progress.json"  # within the data directory: builds of unfinished runs (nupm_home -> {base_path: build_stamp})
+SHOWN_MESSAGES_FILE_NAME: str = "shown_messages.json"  # within the data directory: printed post_install_messages (package file -> {package name: hash})
+RAW_FILE_CACHE_DIR_NAME: str = "raw_files"  # within the data directory: registry files downloaded without cloning the registry (url hash -> content, commits.json: branch -> commit)
+RESOLUTION_CACHE_TTL: float = 30 * 24 * 60 * 60  # seconds (older lookups get redone, even if the registry didn't change)
+LOCAL_ENVIRONMENT_DIR_NAME: str = ".numng"  # next to the package file
+VENDOR_DIR_NAME: str = "vendor"  # next to the package file
+
+
+@contextmanager
+def temporary_store() -> Iterator[str]:
+    # an empty data directory (store, caches, ..) and config directory, which get deleted afterwards (for tests and tools embedding numng)
+    # only used by the current thread (and Loaders started from it)
+    context: RunContext = current_run_context()
+    with TemporaryDirectory(prefix="numng-store-") as tmp_dir:
+        with use_run_context(RunContext(
+            config=context.config,
+            runner=context.runner,
+            cancellation=context.cancellation,
+            base_directory=tmp_dir,
+            config_directory=path.join(tmp_dir, "config"),
+        )):
+            yield tmp_dir
+
+
+SEMVER_COMPARISON_OPS: Tuple[str, ...] = (">=", "<=", "!=", ">", "<")
+
+
+class VersionComparator:
+    # ordering (and matching) for custom versions (`nightly-20240501`, `v2-beta3`, ..), which would otherwise be unordered names
+    # -> registries can pick the newest matching one. register using `register_version_comparator`
+    def handles(self, version: str) -> bool:
+        raise NotImplementedError()
+
+    def sort_key(self, version: str) -> Tuple[Any, ...]:
+        raise NotImplementedError()
+
+    def matches(self, requirement: str, version: str) -> bool:
+        return requirement == version
+
+
+VERSION_COMPARATORS: List[VersionComparator] = []
+
+
+def register_version_comparator(comparator: VersionComparator) -> None:
+    # the first registered comparator handling a version wins (they take priority over the normal parsing)
+    VERSION_COMPARATORS.append(comparator)
+
+
+def find_version_comparator(text: str) -> Optional[VersionComparator]:
+    return next((i for i in VERSION_COMPARATORS if i.handles(text)), None)
 
 
 class SemVer:
     def __init__(self, text: Optional[str]) -> None:
         text = text or ""
+        self.text: str = text  # the original requirement / version (used for json)
+        original_text: str = text
+        self.comparator: Optional[VersionComparator] = find_version_comparator(text)
         c0: str = text[0] if len(text) != 0 else ""
         self.op: Optional[str] = c0 if c0 in "<>^~" else None
+        if text[:2] in (">=", "<=", "!="):
+            self.op = text[:2]
+        # build metadata: `1.2.3+build.5` (ignored for matching and ordering)
+        self.build: Optional[str] = None
+        if "+" in text:
+            text, self.build = text.split("+", 1)
+        # prerelease: `1.2.3-alpha.2`
+        self.prerelease: Optional[str] = None
+        if "-" in text.lstrip("<>=!^~")[1:]:
+            text, self.prerelease = text.split("-", 1)
+        sections: List[str] = text.split(".")
+        # wildcards (`1.x`, `1.2.*`, `*`): everything from the first one on is "any"
+        wildcard: int = next((n for n, section in enumerate(sections) if section.strip().lstrip("<>=!^~") in ("x", "X", "*")), len(sections))
         numbers: List[int] = [
             int(a) for a in (
                 "".join([i for i in section if i in string.digits])
-                for section in text.split(".")
+                for section in sections[:wildcard]
             ) if a != ""
         ]
         self.major = numbers[0] if len(numbers) != 0 else None
         self.minor = numbers[1] if len(numbers) > 1 else None
         self.patch = numbers[2] if len(numbers) > 2 else None
-        if len(text) > 0 and all(i in string.ascii_letters for i in text):
+        if len(text) > 0 and text not in ("x", "X") and all(i in string.ascii_letters for i in text):
             self.op = text
+        if self.comparator is not None:
+            self.op, self.major, self.minor, self.patch, self.prerelease, self.build = original_text, None, None, None, None, None
 
     def __eq__(self, other: Any) -> bool:
+        return self.matches(other)
+
+    def matches(self, other: Any, allow_prerelease: bool = False) -> bool:
+        # allow_prerelease: match prereleases even if this doesn't mention one (`<1.2.3` in `>=1.2.3-alpha, <1.2.3`)
         if not isinstance(other, SemVer):
             return False
+        if self.comparator is not None or other.comparator is not None:
+            return self.comparator is other.comparator and self.comparator is not None and self.comparator.matches(self.op or "", other.op or "")
+        if (prerelease_matches := self._prerelease_matches(other, allow_prerelease)) is not None:
+            return prerelease_matches
+        if self.op in SEMVER_COMPARISON_OPS or other.op in SEMVER_COMPARISON_OPS:
+            return self._comparison_matches(other) if self.op in SEMVER_COMPARISON_OPS else other._comparison_matches(self)
         if (self.op or '') not in "<>^~" or (other.op or '') not in "<>^~":
             return self.op == other.op
-        if (
-            self.major is None or other.major is None
-            or ((self.op == ">" or other.op == "<") and self.major < other.major)
-            or ((other.op == ">" or self.op == "<") and other.major < self.major)
-        ):
+        if self.major is None or other.major is None:
             return True
         if self.major != other.major:
             return False
         if (
             self.minor is None or other.minor is None
-            or ((self.op in (">", "^") or other.op == "<") and self.minor < other.minor)
-            or ((other.op in (">", "^") or self.op == "<") and other.minor < self.minor)
+            or (self.op == "^" and self.minor < other.minor)
+            or (other.op == "^" and other.minor < self.minor)
         ):
             return True
         if self.minor != other.minor:
@@ -69,38 +226,226 @@ class SemVer:
         return (
             self.patch is None or other.patch is None
             or self.patch == other.patch
-            or ((self.op in (">", "^", "~") or other.op == "<") and self.patch < other.patch)
-            or ((other.op in (">", "^", "~") or self.op == "<") and other.patch < self.patch)
+            or (self.op in ("^", "~") and self.patch < other.patch)
+            or (other.op in ("^", "~") and other.patch < self.patch)
         )
 
-    def __gt__(self, other: Any) -> bool:
-        if not isinstance(other, SemVer) or other.major is None:
-            return True
-        if self.op == "latest":
-            return True
-        if other.op == "latest":
-            return False
-        if self.major is None or self.major < other.major:
-            return False
-        if self.major > other.major or other.minor is None:
-            return True
-        if self.minor is None or self.minor < other.minor:
+    def _prerelease_matches(self, other: "SemVer", allow_prerelease: bool = False) -> Optional[bool]:
+        # prereleases only match requirements, which explicitly mention a prerelease of the same version
+        # None -> no prerelease specific decision (compare like normal versions)
+        if self.prerelease is None and other.prerelease is None:
+            return None
+        if self.prerelease is None or other.prerelease is None:
+            with_prerelease: SemVer = self if self.prerelease is not None else other
+            return None if with_prerelease.op is not None or allow_prerelease else False
+        if (self.major, self.minor, self.patch) != (other.major, other.minor, other.patch):
             return False
-        if self.minor > other.minor or other.patch is None:
+        requirement, version = (self, other) if self.op is not None else (other, self)
+        wanted, actual = _prerelease_key(requirement.prerelease), _prerelease_key(version.prerelease)
+        if requirement.op in ("^", "~", ">="):
+            return actual >= wanted
+        if requirement.op == "<=":
+            return actual <= wanted
+        if requirement.op == ">":
+            return actual > wanted
+        if requirement.op == "<":
+            return actual < wanted
+        if requirement.op == "!=":
+            return actual != wanted
+        return actual == wanted
+
+    def _comparison_matches(self, version: "SemVer") -> bool:
+        # >=, <=, !=, >, < (only compares the parts specified in the requirement -> `<=1.2` includes `1.2.9` and `>1.2` doesn't)
+        if (version.op or '') not in "<>^~":
+            return False  # named versions (`latest`, `githead`, ..) have to be requested explicitly
+        wanted: Tuple[Any, ...] = tuple(i for i in (self.major, self.minor, self.patch) if i is not None)
+        actual: Tuple[Any, ...] = (version.major, version.minor, version.patch)[:len(wanted)]
+        if len(wanted) == 0 or None in actual:
             return True
-        return not (self.patch is None or self.patch < other.patch)
+        if len(wanted) == 3:
+            # releases are newer than their prereleases
+            wanted = (*wanted, (1,) if self.prerelease is None else (0, *_prerelease_key(self.prerelease)))
+            actual = (*actual, (1,) if version.prerelease is None else (0, *_prerelease_key(version.prerelease)))
+        if self.op == ">=":
+            return actual >= wanted  # type: ignore
+        if self.op == "<=":
+            return actual <= wanted  # type: ignore
+        if self.op == ">":
+            return actual > wanted  # type: ignore
+        if self.op == "<":
+            return actual < wanted  # type: ignore
+        return actual != wanted
+
+    def sort_key(self) -> Tuple[Any, ...]:
+        # total ordering (`sorted`, `max`, `<`, ..):
+        # * custom names (`githead`, ..) < numeric versions < `latest` (custom names are sorted alphabetically)
+        # * versions handled by a VersionComparator are sorted by it (and grouped by comparator)
+        # * missing parts count as 0, but rank below a explicit 0 (`1` < `1.0` < `1.0.0` < `1.2.3`)
+        # * prereleases < their release (`1.2.3-alpha` < `1.2.3-alpha.1` < `1.2.3-beta` < `1.2.3`)
+        # * operators and build metadata are ignored
+        if self.comparator is not None:
+            return (0, 1 + VERSION_COMPARATORS.index(self.comparator), *self.comparator.sort_key(self.op or ""))
+        if self.op == "latest":
+            return (2,)
+        if self.op is not None and self.op not in SEMVER_COMPARISON_OPS and self.op not in "^~":
+            return (0, 0, self.op)
+        return (
+            1,
+            *((-1, 0) if i is None else (0, i) for i in (self.major, self.minor, self.patch)),
+            (1,) if self.prerelease is None else (0, *_prerelease_key(self.prerelease)),
+        )
+
+    # `==` means "matches" (see __eq__) -> the ordering operators are implemented explicitly instead of using functools.total_ordering
+    def __lt__(self, other: Any) -> bool:
+        return self.sort_key() < other.sort_key() if isinstance(other, SemVer) else NotImplemented
+
+    def __le__(self, other: Any) -> bool:
+        return self.sort_key() <= other.sort_key() if isinstance(other, SemVer) else NotImplemented
+
+    def __gt__(self, other: Any) -> bool:
+        return self.sort_key() > other.sort_key() if isinstance(other, SemVer) else NotImplemented
+
+    def __ge__(self, other: Any) -> bool:
+        return self.sort_key() >= other.sort_key() if isinstance(other, SemVer) else NotImplemented
+
+    # "matches" isn't transitive (`^1` matches `1.2` and `1.3`) -> there is no hash that agrees with __eq__
+    __hash__ = None  # type: ignore
+
+    def greater_than(self, other: Any) -> bool:
+        # deprecated: the old ad-hoc comparison (`>` used to treat `1` and `1.2.3` as equal in both directions); use `>` / `sort_key`
+        warnings.warn("SemVer.greater_than is deprecated, use `>` (or `max`/`sorted`) instead", DeprecationWarning, stacklevel=2)
+        return isinstance(other, SemVer) and self > other
 
     def __str__(self) -> str:
-        return f"SemVer({self.op or ''}{'.'.join([str(self.major or ''), str(self.minor or ''), str(self.patch or '')])})"
+        return f"SemVer({self.op or ''}{'.'.join(str(i) for i in (self.major, self.minor, self.patch) if i is not None)}{'' if self.prerelease is None else '-' + self.prerelease}{'' if self.build is None else '+' + self.build})"
 
     def latest_matching_dict_entry(self, options: Dict[Union[str, "SemVer", None], Any]) -> Optional[Any]:
-        biggest_available: Optional[Tuple[SemVer, Any]] = None
-        for option in (((k if isinstance(k, SemVer) else SemVer(k)), v) for k, v in options.items() if k != "_"):
-            if self.op == "latest" or self.__eq__(option[0]):
-                if biggest_available is None or option[0].__gt__(biggest_available[0]):
+        biggest_available: Optional[Tuple[Any, Any]] = self.latest_matching_dict_item(options)
+        return None if biggest_available is None else biggest_available[1]
+
+    def latest_matching_dict_item(self, options: Dict[Union[str, "SemVer", None], Any]) -> Optional[Tuple[Any, Any]]:
+        biggest_available: Optional[Tuple[SemVer, Any, Any]] = None
+        for option in (((k if isinstance(k, SemVer) else type(self)(k)), k, v) for k, v in options.items() if k != "_"):
+            if (self.op == "latest" and option[0].prerelease is None) or self.__eq__(option[0]):
+                if biggest_available is None or option[0] > biggest_available[0]:
                     biggest_available = option
+        return None if biggest_available is None else (biggest_available[1], biggest_available[2])
+
+
+class CalVer(SemVer):
+    # date based versions (`2024.05.01`, `24.5`, `2024-05-01`): any amount of parts, no prereleases
+    # `^` and `~` make no sense for dates -> only comparisons (`>=2024.05`) and prefixes (`2024.05` matches `2024.05.01`)
+    def __init__(self, text: Optional[str]) -> None:
+        text = (text or "").strip()
+        self.text: str = text
+        original_text: str = text
+        self.comparator: Optional[VersionComparator] = find_version_comparator(text)
+        self.op: Optional[str] = next((i for i in SEMVER_COMPARISON_OPS if text.startswith(i)), None)
+        text = text[len(self.op or ""):]
+        self.build: Optional[str] = None
+        self.prerelease: Optional[str] = None
+        sections: List[str] = re.split(r"[.-]", text)
+        wildcard: int = next((n for n, section in enumerate(sections) if section.strip() in ("x", "X", "*")), len(sections))
+        self.parts: List[int] = [int(i) for i in sections[:wildcard] if i.strip().isdigit()]
+        self.major, self.minor, self.patch = (*self.parts, None, None, None)[:3]
+        if len(text) > 0 and text not in ("x", "X") and all(i in string.ascii_letters for i in text):
+            self.op = text
+        if self.comparator is not None:
+            self.op, self.parts, self.major, self.minor, self.patch = original_text, [], None, None, None
+
+    def matches(self, other: Any, allow_prerelease: bool = False) -> bool:
+        if not isinstance(other, CalVer):
+            return False
+        if self.comparator is not None or other.comparator is not None:
+            return self.comparator is other.comparator and self.comparator is not None and self.comparator.matches(self.op or "", other.op or "")
+        if self.op in SEMVER_COMPARISON_OPS or other.op in SEMVER_COMPARISON_OPS:
+            return self._comparison_matches(other) if self.op in SEMVER_COMPARISON_OPS else other._comparison_matches(self)
+        if self.op is not None or other.op is not None:
+            return self.op == other.op
+        return all(a == b for a, b in zip(self.parts, other.parts))
+
+    def _comparison_matches(self, version: "SemVer") -> bool:
+        assert isinstance(version, CalVer)
+        if version.op is not None and version.op not in SEMVER_COMPARISON_OPS:
+            return False  # named versions (`latest`, `githead`, ..) have to be requested explicitly
+        wanted: Tuple[int, ...] = tuple(self.parts)
+        actual: Tuple[int, ...] = tuple(version.parts[:len(wanted)])
+        if len(wanted) == 0:
+            return True
+        if self.op == ">=":
+            return actual >= wanted
+        if self.op == "<=":
+            return actual <= wanted
+        if self.op == ">":
+            return actual > wanted
+        if self.op == "<":
+            return actual < wanted
+        return actual != wanted
+
+    def sort_key(self) -> Tuple[Any, ...]:
+        if self.comparator is not None:
+            return (0, 1 + VERSION_COMPARATORS.index(self.comparator), *self.comparator.sort_key(self.op or ""))
+        if self.op == "latest":
+            return (2,)
+        if self.op is not None and self.op not in SEMVER_COMPARISON_OPS:
+            return (0, 0, self.op)
+        return (1, *self.parts)
+
+    def __str__(self) -> str:
+        return f"CalVer({self.op or ''}{'.'.join(str(i) for i in self.parts)})"
+
+
+# `commit-date` versions are the (utc) date of the used commit (`2024.05.01`)
+VERSION_SCHEMES: Dict[str, Callable[[Optional[str]], SemVer]] = {"semver": SemVer, "calver": CalVer, "commit-date": CalVer}
+
+
+def _prerelease_key(prerelease: Optional[str]) -> Tuple[Tuple[int, Union[int, str]], ...]:
+    # semver: numeric identifiers are lower than alphanumeric ones, more identifiers are higher (alpha < alpha.1 < beta)
+    return tuple((0, int(i)) if i.isdigit() else (1, i) for i in (prerelease or "").split(".") if i != "")
+
+
+class VersionReq:
+    # compound requirements: `>=1.2, <2.0` (all have to match), `1.2 - 1.5` (inclusive range), `^1 || ^2` (any has to match)
+    def __init__(self, text: Optional[str], scheme: str = "semver") -> None:
+        assert scheme in VERSION_SCHEMES, f"Unknown version_scheme {scheme} (available: {', '.join(VERSION_SCHEMES)})"
+        self.text: str = text or ""
+        self._version_class: Callable[[Optional[str]], SemVer] = VERSION_SCHEMES[scheme]
+        self.alternatives: List[List[SemVer]] = []
+        for alternative in self.text.split("||"):
+            if " - " in alternative:
+                lower, upper = alternative.split(" - ", 1)
+                self.alternatives.append([self._version_class(">=" + lower.strip()), self._version_class("<=" + upper.strip())])
+            else:
+                self.alternatives.append([self._version_class(i.strip()) for i in alternative.split(",") if i.strip() != ""] or [self._version_class("")])
+
+    def matches(self, version: SemVer) -> bool:
+        for alternative in self.alternatives:
+            # a prerelease in one part allows prereleases of the same version for the others
+            allow_prerelease: bool = version.prerelease is not None and any(
+                i.prerelease is not None and (i.major, i.minor, i.patch) == (version.major, version.minor, version.patch) for i in alternative
+            )
+            if all(requirement.matches(version, allow_prerelease=allow_prerelease) for requirement in alternative):
+                return True
+        return False
+
+    def __str__(self) -> str:
+        return f"VersionReq({self.text})"
+
+    def latest_matching_dict_entry(self, options: Dict[Union[str, SemVer, None], Any]) -> Optional[Any]:
+        biggest_available: Optional[Tuple[Any, Any]] = self.latest_matching_dict_item(options)
         return None if biggest_available is None else biggest_available[1]
 
+    def latest_matching_dict_item(self, options: Dict[Union[str, SemVer, None], Any]) -> Optional[Tuple[Any, Any]]:
+        if len(self.alternatives) == 1 and len(self.alternatives[0]) == 1:
+            # plain requirement (keeps the special handling of `latest`, etc)
+            return self.alternatives[0][0].latest_matching_dict_item(options)
+        biggest_available: Optional[Tuple[SemVer, Any, Any]] = None
+        for option in (((k if isinstance(k, SemVer) else self._version_class(k)), k, v) for k, v in options.items() if k != "_"):
+            if self.matches(option[0]):
+                if biggest_available is None or option[0] > biggest_available[0]:
+                    biggest_available = option
+        return None if biggest_available is None else (biggest_available[1], biggest_available[2])
+
 
 @dataclass(kw_only=True)
 class Package:
@@ -112,11 +457,16 @@ class Package:
     source_path_offset: Optional[str] = None
     registries: Optional[List["Package"]] = None
     package_format: Optional[str] = None
+    resolved_version: Optional[str] = None
     extra_data: Optional[Dict[str, Any]] = None
 
     def include_data(self, other: "Package") -> None:
+        # other (registry entry, etc) only fills what self doesn't set, unless self selects a different `merge` strategy for a key
         if self.depends is None:
             self.depends = other.depends
+        elif other.depends and merge_strategy(self.extra_data, "depends") == "deep":
+            # same name -> the one of self wins
+            self.depends = [*(i for i in other.depends if not any(i.name == j.name for j in self.depends)), *self.depends]
         if self.source_type is None:
             self.source_type = other.source_type
         if self.source_uri is None:
@@ -127,42 +477,201 @@ class Package:
             self.source_path_offset = other.source_path_offset
         if self.package_format is None:
             self.package_format = other.package_format
+        if self.resolved_version is None:
+            self.resolved_version = other.resolved_version
         if other.extra_data:
-            self.extra_data = {**other.extra_data, **(self.extra_data or {})}
+            self.extra_data = {
+                **other.extra_data,
+                **{
+                    key: deep_merge(other.extra_data[key], value) if key in other.extra_data and merge_strategy(self.extra_data, key) == "deep" else value
+                    for key, value in (self.extra_data or {}).items()
+                },
+            }
+
+    @staticmethod
+    def builder(name: Optional[str] = None) -> "PackageBuilder":
+        return PackageBuilder(name)
+
+    def validate(self, base_path: Optional[str] = None) -> List["Diagnostic"]:
+        return validate_package_json(package_to_json(self), base_path=base_path)
 
 class PackageRegistry:
+    # set by the loader: which registry (source_uri + path_offset) at which commit / archive hash -> lookups can be cached across runs
+    cache_id: Optional[str] = None
+    cache_revision: Optional[str] = None
+    # set by the loader: where the registry came from (for messages)
+    name: Optional[str] = None
+    source: Optional[str] = None
+
     # why does pyright not have a option to disable unused variable? https://github.com/microsoft/pyright/blob/main/docs/configuration.md
     def get_by_name(self, name: str, version: Optional[str] = None, **_) -> Optional[Package]:
         raise NotImplementedError()
 
+    def package_names(self) -> List[str]:
+        raise NotImplementedError()
+
+    def list_versions(self, name: str) -> List[str]:
+        # oldest first (aliases are not included)
+        raise NotImplementedError()
+
+    def search_index(self) -> List[Dict[str, Any]]:
+        # [{"name": .., "description": .., "keywords": [..]}] (without a search index only the names are known)
+        return [{"name": i} for i in self.package_names()]
+
+
+REGISTRY_MANIFEST_FILE: str = "numng-registry.json"
+SEARCH_INDEX_FILE: str = "search-index.json"  # optional: name, description, and keywords of every package of a numng registry
+# flat: `NAME.json`, sharded: `NAME[0..2]/NAME.json` (for registries with thousands of packages)
+REGISTRY_LAYOUTS: Tuple[str, ...] = ("flat", "sharded")
+
+
+def registry_file_parts(name: str, layout: str) -> List[str]:
+    parts: List[str] = [filesystem_safe(i) for i in name.split("/") if i and i != ".."]
+    return [filesystem_safe(name[:2]), *parts] if layout == "sharded" and parts else parts
+
 
 class NumngPackageRegistry(PackageRegistry):
-    def __init__(self, registry_dir: str) -> None:
+    def __init__(self, registry_dir: str, public_key: Optional[str] = None) -> None:
         self._registry_dir: str = registry_dir
+        self._public_key: Optional[str] = public_key  # if set all package files have to be signed
+        # the layout can be declared in numng-registry.json (`{"layout": "sharded"}`), otherwise both get tried
+        self._layouts: Tuple[str, ...] = REGISTRY_LAYOUTS
+        if (manifest_path := self._file(REGISTRY_MANIFEST_FILE)) is not None:
+            try:
+                with open(manifest_path, "r") as fp:
+                    layout: Any = json.load(fp).get("layout", "flat")
+            except (json.JSONDecodeError, AttributeError):
+                assert False, f"The repository {registry_dir} contains a invalid {REGISTRY_MANIFEST_FILE}"
+            assert layout in REGISTRY_LAYOUTS, f"The repository {registry_dir} uses the unknown layout {layout} (available: {', '.join(REGISTRY_LAYOUTS)})"
+            self._layouts = (layout,)
 
-    def get_by_name(self, name: str, version: Optional[str] = None, **_) -> Optional[Package]:
-        filepath = path.join(self._registry_dir, *[filesystem_safe(i) for i in (name+".json").split("/") if i and i != ".."])
-        if not path.isfile(path.join(filepath)):
+    def _file(self, file_path: str) -> Optional[str]:
+        # local path of a file within the registry (None if it doesn't exist)
+        return local_path if path.isfile(local_path := path.join(self._registry_dir, file_path)) else None
+
+    def _read_version_dict(self, name: str) -> Optional[Dict[str, Any]]:
+        found: Optional[Tuple[str, str]] = next((
+            (file_path, local_path) for layout in self._layouts for extension in PACKAGE_FILE_EXTENSIONS
+            if (local_path := self._file(file_path := "/".join(registry_file_parts(name, layout)) + extension)) is not None
+        ), None)
+        if found is None:
+            return None
+        file_path, local_path = found
+        if self._public_key is not None:
+            assert (signature_path := self._file(file_path + ".sig")) is not None, f"The repository {self._registry_dir} has a trusted_key, but {file_path} is not signed"
+            assert verify_ssh_signature(local_path, signature_path, self._public_key), f"Invalid signature for {file_path} in repository {self._registry_dir}"
+        try:
+            return json.loads(read_manifest_text(local_path))
+        except (json.JSONDecodeError, AssertionError):
+            assert False, f"The repository {self._registry_dir} contains a invalid package file at {file_path}"
+
+    def get_by_name(self, name: str, version: Optional[str] = None, version_scheme: Optional[str] = None, **_) -> Optional[Package]:
+        if (version_dict := self._read_version_dict(name)) is None:
             logger.debug(f"numng_registry: no package-name match found for {name}/{version}")
             return None
-        with open(filepath, "r") as fp:
-            try:
-                version_dict = json.load(fp)
-            except json.JSONDecodeError:
-                assert False, f"The repository {self._registry_dir} contains a invalid json file at {filepath}"
-        found_package = SemVer(version or "latest").latest_matching_dict_entry(version_dict)
-        if found_package is None:
+        # the version_scheme can also be set for all versions in `_`
+        version_scheme = version_scheme or (version_dict.get("_") or {}).get("version_scheme") or "semver"
+        found_item = VersionReq(version or "latest", version_scheme).latest_matching_dict_item(version_dict)
+        if found_item is None:
             logger.debug(f"numng_registry: no version match found for {name}/{version}")
             return None
+        found_version, found_package = found_item
         while isinstance(found_package, str):
             assert found_package in version_dict, f'Numng-Repo defined a invalid version alias from {name}/{version or "latest"} to {name}/{found_package}'
-            found_package = version_dict[found_package]
+            found_version, found_package = found_package, version_dict[found_package]
         found_package["name"] = name
         result: Package = load_package_from_json(found_package, allow_no_name=False)
+        result.resolved_version = found_version
         if "_" in version_dict:
             result.include_data(load_package_from_json(version_dict["_"], allow_no_name=True))
         return result
 
+    def package_names(self) -> List[str]:
+        names: List[str] = []
+        for root, dirs, files in walk(self._registry_dir):
+            dirs[:] = [i for i in dirs if i != ".git"]
+            for file_name in files:
+                if not file_name.endswith(PACKAGE_FILE_EXTENSIONS) or file_name in (REGISTRY_MANIFEST_FILE, SEARCH_INDEX_FILE):
+                    continue
+                name: str = path.splitext(path.relpath(path.join(root, file_name), self._registry_dir))[0].replace(path.sep, "/")
+                shard, _, sharded_name = name.partition("/")
+                if "sharded" in self._layouts and sharded_name and registry_file_parts(sharded_name, "sharded")[0] == shard:
+                    name = sharded_name
+                names.append(name)
+        return sorted(set(names))
+
+    def list_versions(self, name: str) -> List[str]:
+        if (version_dict := self._read_version_dict(name)) is None:
+            return []
+        version_class = VERSION_SCHEMES.get((version_dict.get("_") or {}).get("version_scheme") or "semver", SemVer)
+        return sorted((k for k, v in version_dict.items() if k != "_" and not isinstance(v, str)), key=lambda i: version_class(i).sort_key())
+
+    def search_index(self) -> List[Dict[str, Any]]:
+        if (index_path := self._file(SEARCH_INDEX_FILE)) is None:
+            return super().search_index()
+        try:
+            with open(index_path, "r") as fp:
+                index: Any = json.load(fp)
+        except json.JSONDecodeError:
+            index = None
+        assert isinstance(index, list) and all(isinstance(i, dict) and isinstance(i.get("name"), str) for i in index), f"The repository {self._registry_dir} contains a invalid {SEARCH_INDEX_FILE}"
+        return index
+
+
+class RawFileNumngPackageRegistry(NumngPackageRegistry):
+    # numng registry on a known forge (see RAW_FILE_URLS), which has not been cloned: only the required files get downloaded
+    # the files are requested by commit -> they never change and stay cached (no revalidation needed)
+    def __init__(self, url: str, commit: str, path_offset: Optional[str] = None, public_key: Optional[str] = None, fallback: Optional[Callable[[], str]] = None) -> None:
+        self._url: str = url
+        self._commit: str = commit
+        self._path_offset: Optional[str] = path_offset
+        self._fallback: Optional[Callable[[], str]] = fallback  # clones the registry at the same commit (-> its directory)
+        self._fallback_lock: Lock = Lock()
+        self._cloned: bool = False
+        super().__init__(url, public_key=public_key)
+
+    def _file(self, file_path: str) -> Optional[str]:
+        if self._cloned:
+            return super()._file(file_path)
+        raw_url: Optional[str] = raw_file_url(self._url, self._commit, "/".join(i for i in (self._path_offset, file_path) if i))
+        assert raw_url is not None, f"{self._url} is not on a known forge"
+        try:
+            return fetch_raw_file(raw_url)
+        except OSError as exc:  # rate limits, outages, proxies, .. (missing files are not a error)
+            if self._fallback is None:
+                raise
+            with self._fallback_lock:
+                if not self._cloned:
+                    logger.warning(f"Failed to download {raw_url} ({exc}) -> cloning {self._url} instead")
+                    self._registry_dir = self._fallback()
+                    self._cloned = True
+            return super()._file(file_path)
+
+    def package_names(self) -> List[str]:
+        if self._cloned:
+            return super().package_names()
+        if self._file(SEARCH_INDEX_FILE) is None:
+            raise NotImplementedError()  # would require a directory listing
+        return sorted(i["name"] for i in self.search_index())
+
+
+class MemoryPackageRegistry(NumngPackageRegistry):
+    # numng registry without files (for tests and tools embedding numng)
+    # packages: name -> the content a registry file would have ({VERSION: PACKAGE, "latest": "VERSION", "_": SHARED_DATA})
+    def __init__(self, packages: Optional[Dict[str, Dict[str, Any]]] = None) -> None:
+        super().__init__("<memory>")
+        self.packages: Dict[str, Dict[str, Any]] = packages if packages is not None else {}
+
+    def add(self, name: str, version: str, package: Union[Dict[str, Any], Package, "PackageBuilder"]) -> "MemoryPackageRegistry":
+        self.packages.setdefault(name, {})[version] = _package_json(package)
+        return self
+
+    def _read_version_dict(self, name: str) -> Optional[Dict[str, Any]]:
+        return deepcopy(self.packages.get(name))  # get_by_name modifies it
+
+    def package_names(self) -> List[str]:
+        return sorted(self.packages)
+
 
 class NupmPackageRegistry(PackageRegistry):
     def __init__(self, registry_dir: str) -> None:
@@ -171,14 +680,289 @@ class NupmPackageRegistry(PackageRegistry):
             # git already checks hashes -> no need to use the hashes here
             self._packages: Dict[str, str] = {i["name"]: i["path"] for i in load_nuon(fp.read())}
 
-    def get_by_name(self, name: str, version: Optional[str] = None, **_) -> Optional[Package]:
+    def _read_package_details(self, name: str) -> Optional[Any]:
         if (package_details_path := self._packages.get(name)) is None:
             return None
         package_details_path = path.abspath(path.join(self._registry_dir, package_details_path))
         assert package_details_path.startswith(self._registry_dir), f"Package registry unsafe (attempted to access {package_details_path})"
         with open(package_details_path, "r") as fp:
-            raw_file: str = fp.read()
-        return load_nupm_package_from_registry_nuon(load_nuon(raw_file), name=name, version=version)
+            return load_nuon(fp.read())
+
+    def get_by_name(self, name: str, version: Optional[str] = None, **_) -> Optional[Package]:
+        if (package_details := self._read_package_details(name)) is None:
+            return None
+        return load_nupm_package_from_registry_nuon(package_details, name=name, version=version)
+
+    def package_names(self) -> List[str]:
+        return sorted(self._packages)
+
+    def list_versions(self, name: str) -> List[str]:
+        return sorted(
+            (i["version"] for i in self._read_package_details(name) or [] if isinstance(i, dict) and i.get("name") == name and isinstance(i.get("version"), str)),
+            key=lambda i: SemVer(i).sort_key(),
+        )
+
+
+class CompositePackageRegistry(PackageRegistry):
+    # ordered registries: the first one with a matching version wins
+    # cacheable as a whole if all of them are (the id and revision combine theirs)
+    def __init__(self, registries: Optional[List[PackageRegistry]] = None) -> None:
+        self.registries: List[PackageRegistry] = registries if registries is not None else []
+
+    @property  # type: ignore
+    def cache_id(self) -> Optional[str]:
+        ids: List[Optional[str]] = [i.cache_id for i in self.registries]
+        return None if len(ids) == 0 or None in ids else json.dumps(ids)
+
+    @property  # type: ignore
+    def cache_revision(self) -> Optional[str]:
+        return json.dumps([i.cache_revision for i in self.registries])
+
+    def __len__(self) -> int:
+        return len(self.registries)
+
+    def append(self, registry: PackageRegistry) -> None:
+        self.registries.append(registry)
+
+    def only(self, registry_type: type) -> "CompositePackageRegistry":
+        return CompositePackageRegistry([i for i in self.registries if isinstance(i, registry_type)])
+
+    def get_by_name(self, name: str, version: Optional[str] = None, **kwargs) -> Optional[Package]:
+        for registry in self.registries:
+            if (result := registry.get_by_name(name, version=version, **kwargs)) is not None:
+                return result
+        return None
+
+    def package_names(self) -> List[str]:
+        names: List[str] = []
+        for registry in self.registries:
+            try:
+                names.extend(registry.package_names())
+            except NotImplementedError:
+                pass
+        return sorted(set(names))
+
+    def list_versions(self, name: str) -> List[str]:
+        # versions of the first registry, then the ones only the next has, ..
+        versions: List[str] = []
+        for registry in self.registries:
+            try:
+                versions.extend(i for i in registry.list_versions(name) if i not in versions)
+            except NotImplementedError:
+                pass
+        return versions
+
+    def search_index(self) -> List[Dict[str, Any]]:
+        # same name -> the entry of the first registry wins
+        entries: Dict[str, Dict[str, Any]] = {}
+        for registry in self.registries:
+            try:
+                for entry in registry.search_index():
+                    entries.setdefault(entry["name"], entry)
+            except NotImplementedError:
+                pass
+        return sorted(entries.values(), key=lambda i: i["name"])
+
+
+class CachingPackageRegistry(PackageRegistry):
+    # remembers the lookups of another registry for the rest of the run
+    # cache (see load_resolution_cache): also keep them across runs (only for registries with a cache_id, until their revision changes or ttl runs out)
+    def __init__(self, registry: PackageRegistry, cache: Optional[Dict[str, Dict[str, Any]]] = None, ttl: Optional[float] = None) -> None:
+        self.registry: PackageRegistry = registry
+        self._cache: Optional[Dict[str, Dict[str, Any]]] = cache
+        self._ttl: Optional[float] = ttl
+        self._lookups: Dict[str, Optional[Dict[str, Any]]] = {}  # lookup -> package json (packages get modified by the loader -> no shared objects)
+        self._versions: Dict[str, List[str]] = {}
+        self._lock: Lock = Lock()
+        self.changed: bool = False  # the cache has to be saved
+
+    @property  # type: ignore
+    def cache_id(self) -> Optional[str]:
+        return self.registry.cache_id
+
+    @property  # type: ignore
+    def cache_revision(self) -> Optional[str]:
+        return self.registry.cache_revision
+
+    def _cache_entry(self) -> Optional[Dict[str, Any]]:
+        if self._cache is None or (cache_id := self.cache_id) is None:
+            return None
+        entry: Optional[Dict[str, Any]] = self._cache.get(cache_id)
+        if entry is None or entry.get("revision") != self.cache_revision or (self._ttl is not None and time() - entry.get("time", 0) > self._ttl):
+            # the registry changed (or the entry is too old) -> none of the old lookups are valid anymore
+            entry = self._cache[cache_id] = {"revision": self.cache_revision, "time": time(), "packages": {}}
+        return entry
+
+    def get_by_name(self, name: str, version: Optional[str] = None, version_scheme: Optional[str] = None, **_) -> Optional[Package]:
+        lookup: str = json.dumps([name, version, version_scheme])
+        with self._lock:
+            if lookup not in self._lookups and (entry := self._cache_entry()) is not None and lookup in entry["packages"]:
+                logger.debug(f"using the cached registry lookup for {name}/{version or 'latest'}")
+                self._lookups[lookup] = entry["packages"][lookup]
+            if lookup in self._lookups:
+                return None if self._lookups[lookup] is None else load_package_from_json(self._lookups[lookup])
+        result: Optional[Package] = self.registry.get_by_name(name, version=version, version_scheme=version_scheme)
+        with self._lock:
+            self._lookups[lookup] = None if result is None else package_to_json(result)
+            if (entry := self._cache_entry()) is not None:
+                entry["packages"][lookup] = self._lookups[lookup]
+                self.changed = True
+        return result
+
+    def package_names(self) -> List[str]:
+        return self.registry.package_names()
+
+    def search_index(self) -> List[Dict[str, Any]]:
+        return self.registry.search_index()
+
+    def list_versions(self, name: str) -> List[str]:
+        if name not in self._versions:
+            self._versions[name] = self.registry.list_versions(name)
+        return list(self._versions[name])
+
+
+def load_stored_registries(registry_packages: List[Package]) -> CompositePackageRegistry:
+    # only the registries, which are already in the store (no downloads)
+    registries: CompositePackageRegistry = CompositePackageRegistry()
+    raw_file_commits: Dict[str, str] = load_json_state(data_path(RAW_FILE_CACHE_DIR_NAME, "commits.json"), {})
+    for registry in registry_packages:
+        if registry.source_type not in ("git", None) or registry.source_uri is None:
+            continue
+        registry_dir: str = get_git_ref_path(registry.source_uri, registry.source_git_ref)
+        registry_dir = path.join(registry_dir, registry.source_path_offset) if registry.source_path_offset else registry_dir
+        if registry.package_format == "nupm" and path.isfile(path.join(registry_dir, "registry", "registry.nuon")):
+            registries.append(NupmPackageRegistry(path.join(registry_dir, "registry")))
+        elif registry.package_format == "numng" and path.isdir(registry_dir):
+            registries.append(NumngPackageRegistry(registry_dir))
+        elif registry.package_format == "numng" and (commit := raw_file_commits.get(f"{registry.source_uri} {registry.source_git_ref or 'main'}")) is not None:
+            registries.append(RawFileNumngPackageRegistry(registry.source_uri, commit, registry.source_path_offset))
+    return registries
+
+
+def list_registry_package_names(package_file: str) -> List[str]:
+    # for completions
+    package: Package = load_package_from_json(read_package_file(package_file)[0], allow_no_name=True)
+    names: List[str] = [i.name for i in package.depends or [] if i.name]
+    return sorted(set(names + load_stored_registries(package.registries or []).package_names()))
+
+
+def search_packages(index: List[Dict[str, Any]], query: str) -> List[Dict[str, Any]]:
+    # every word has to be in the name, description, or keywords (case insensitive), name matches first
+    words: List[str] = query.lower().split()
+
+    def text(entry: Dict[str, Any]) -> str:
+        return " ".join([entry["name"], str(entry.get("description") or ""), *(str(i) for i in entry.get("keywords") or [])]).lower()
+
+    return sorted(
+        (i for i in index if all(word in text(i) for word in words)),
+        key=lambda i: (not all(word in i["name"].lower() for word in words), i["name"]),
+    )
+
+
+def generate_nu_completions(parser: Any) -> str:
+    # nushell `extern` definitions for every (sub-)command of the argparse parser
+    import argparse
+    lines: List[str] = [
+        "# generated by `numng completions nu`",
+        "",
+        'def "nu-complete numng packages" [] {',
+        "    ^numng completions packages | lines",
+        "}",
+    ]
+
+    def add_extern(command_name: str, command_parser: argparse.ArgumentParser) -> None:
+        params: List[str] = []
+        for action in command_parser._actions:
+            if isinstance(action, argparse._HelpAction):
+                continue
+            if isinstance(action, argparse._SubParsersAction):
+                for sub_name, sub_parser in action.choices.items():
+                    add_extern(f"{command_name} {sub_name}", sub_parser)
+                continue
+            completer: str = ""
+            if action.choices:
+                completer_name: str = f"nu-complete {command_name} {action.dest}"
+                lines.extend(["", f"def {json.dumps(completer_name)} [] {{", f"    {json.dumps([str(i) for i in action.choices])}", "}"])
+                completer = f"@{json.dumps(completer_name)}"
+            elif action.dest in ("package", "packages"):
+                completer = '@"nu-complete numng packages"'
+            value_type: str = "int" if action.type is int else ("number" if action.type is float else "string")
+            help_comment: str = f"  # {' '.join((action.help or '').split())}" if action.help else ""
+            if not action.option_strings:
+                if action.nargs in (argparse.REMAINDER, "*", "+"):
+                    params.append(f"    ...{action.dest}: {value_type}{completer}{help_comment}")
+                else:
+                    params.append(f"    {action.dest}{'?' if action.nargs == '?' else ''}: {value_type}{completer}{help_comment}")
+                continue
+            long_flag: Optional[str] = next((i for i in action.option_strings if i.startswith("--")), None)
+            short_flag: Optional[str] = next((i for i in action.option_strings if len(i) == 2), None)
+            flag: str = long_flag or short_flag or action.option_strings[0]
+            if long_flag is not None and short_flag is not None:
+                flag += f"({short_flag})"
+            is_switch: bool = isinstance(action, (argparse._StoreTrueAction, argparse._StoreFalseAction, argparse._StoreConstAction))
+            params.append(f"    {flag}{'' if is_switch else f': {value_type}{completer}'}{help_comment}")
+        lines.extend(["", f"export extern {json.dumps(command_name)} [", *params, "]"])
+
+    add_extern("numng", parser)
+    return "\n".join(lines) + "\n"
+
+
+def verify_ssh_signature(file_path: str, signature_path: str, public_key: str) -> bool:
+    # ssh-keygen only accepts keys via a allowed_signers file
+    with TemporaryDirectory() as tmpdir:
+        allowed_signers: str = path.join(tmpdir, "allowed_signers")
+        with open(allowed_signers, "w") as fp:
+            fp.write(f"numng {public_key}\n")
+        with open(file_path, "rb") as fp:
+            verify_proc = run_command(
+                ["ssh-keygen", "-Y", "verify", "-f", allowed_signers, "-I", "numng", "-n", "numng", "-s", signature_path],
+                stdin=fp, stdout=subprocess.DEVNULL, stderr=subprocess.DEVNULL,
+            )
+    return verify_proc.returncode == 0
+
+
+MERGE_STRATEGIES: Tuple[str, ...] = ("fill", "deep")
+
+
+def merge_strategy(extra_data: Optional[Dict[str, Any]], key: str) -> str:
+    # `merge`: a strategy for all keys or a record (key -> strategy, `_` is the fallback)
+    merge: Any = (extra_data or {}).get("merge", "fill")
+    if isinstance(merge, dict):
+        merge = merge.get(key, merge.get("_", "fill"))
+    assert merge in MERGE_STRATEGIES, f"Unknown merge strategy {merge} (available: {', '.join(MERGE_STRATEGIES)})"
+    return merge
+
+
+# json key -> Package attribute (everything else is extra_data)
+PACKAGE_JSON_ATTRIBUTES: Dict[str, str] = {
+    "depends": "depends",
+    "source_type": "source_type",
+    "source_uri": "source_uri",
+    "git_ref": "source_git_ref",
+    "path_offset": "source_path_offset",
+    "package_format": "package_format",
+    "resolved_version": "resolved_version",
+}
+
+
+def without_fields(package: Package, keys: List[str]) -> Package:
+    # copy of package without the given (json) keys (`ignore_registry` list form)
+    result: Package = Package(**{i.name: getattr(package, i.name) for i in fields(package)})
+    for key in keys:
+        if key in PACKAGE_JSON_ATTRIBUTES:
+            setattr(result, PACKAGE_JSON_ATTRIBUTES[key], None)
+    if result.extra_data is not None:
+        result.extra_data = {key: value for key, value in result.extra_data.items() if key not in keys}
+    return result
+
+
+def deep_merge(base: Any, override: Any) -> Any:
+    # records get merged recursively, lists combined (without duplicates), and for everything else override wins
+    if isinstance(base, dict) and isinstance(override, dict):
+        return {**base, **{key: deep_merge(base[key], value) if key in base else value for key, value in override.items()}}
+    if isinstance(base, list) and isinstance(override, list):
+        return [*base, *(i for i in override if i not in base)]
+    return override
 
 
 def _listify(i: Any) -> List[Any]:
@@ -187,10 +971,24 @@ def _listify(i: Any) -> List[Any]:
     return i if isinstance(i, list) else [i]
 
 
+def is_dependency_map(i: Any) -> bool:
+    # `"depends": {"webserver.nu": "^1.2", "jc.nu": "latest"}` (a single package always has a name)
+    return isinstance(i, dict) and len(i) != 0 and "name" not in i and all(isinstance(v, str) for v in i.values())
+
+
+def _dependency_items(i: Any) -> List[Tuple[Union[str, int, None], Any]]:
+    # depends (list, single package or name, or dependency map) -> [(json pointer part (None for a single package), package json)]
+    if is_dependency_map(i):
+        return [(name, {"name": name, "version": version}) for name, version in i.items()]
+    if isinstance(i, list):
+        return list(enumerate(i))
+    return [] if i is None else [(None, i)]
+
+
 def load_nupm_package_from_registry_nuon(json_data: Any, name: Optional[str] = None, version: Optional[str] = None) -> Optional[Package]:
     assert isinstance(json_data, list), "Invalid package-file in nupm registry (not a list)"
-    wanted_semver: SemVer = SemVer(version)
-    biggest_available: Optional[Tuple[SemVer, Any]] = wanted_semver.latest_matching_dict_entry({i.get("version"): i for i in json_data if name in (None, i.get("name"))})
+    wanted_version: VersionReq = VersionReq(version)
+    biggest_available: Optional[Tuple[Any, Any]] = wanted_version.latest_matching_dict_item({i.get("version"): i for i in json_data if name in (None, i.get("name"))})
     if biggest_available is None:
         logger.debug(f"load_nupm_package_from_registry_nuon: no match found for {name}/{version}")
         return None
@@ -202,10 +1000,34 @@ def load_nupm_package_from_registry_nuon(json_data: Any, name: Optional[str] = N
         source_git_ref=(package_nuon.get("info") or {}).get("revision"),
         source_path_offset=package_nuon.get("path"),
         package_format="nupm",
-        # version=package_nuon.get("version"),
+        resolved_version=package_nuon.get("version"),
     )
 
 
+class AdvisoryDatabase:
+    def __init__(self, advisory_dir: str) -> None:
+        self._advisory_dir: str = advisory_dir
+
+    def get_advisories(self, name: str) -> List[Dict[str, Any]]:
+        filepath = path.join(self._advisory_dir, *[filesystem_safe(i) for i in (name+".json").split("/") if i and i != ".."])
+        if not path.isfile(filepath):
+            return []
+        with open(filepath, "r") as fp:
+            try:
+                advisories = json.load(fp)
+            except json.JSONDecodeError:
+                assert False, f"The advisory database {self._advisory_dir} contains a invalid json file at {filepath}"
+        assert isinstance(advisories, list), f"The advisory database {self._advisory_dir} contains a invalid advisory file at {filepath} (not a list)"
+        return advisories
+
+
+def advisory_affects_version(advisory: Dict[str, Any], version: Optional[str], version_scheme: str = "semver") -> bool:
+    # no "versions" means all versions are affected
+    # unknown versions (git heads, etc) are assumed to be affected
+    affected_versions: List[str] = _listify(advisory.get("versions"))
+    return len(affected_versions) == 0 or any(VersionReq(i, version_scheme).matches(VERSION_SCHEMES[version_scheme](version)) for i in affected_versions)
+
+
 @dataclass(kw_only=True)
 class LoaderScriptSnippet:
     name: str
@@ -237,133 +1059,1183 @@ def sort_loader_script_snippets(snippets: List[LoaderScriptSnippet]) -> List[str
     return result
 
 
-class Loader:
-    def __init__(
-        self,
-        numng_file_path: str,
-        generate_script: Optional[str] = None,
-        generate_overlay: Optional[str] = None,
-        nupm_home: Optional[str] = None,
-        delete_existing_nupm_home: bool = False,
-        pull_updates: bool = False,
-        handle_nu_plugins: bool = False,
-        allow_build_commands: Optional[bool] = None,
-    ) -> None:
-        self._nupm_home: Optional[str] = nupm_home
-        self._loader_script_snippets_env: List[LoaderScriptSnippet] = []
-        self._loader_script_snippets_use: List[LoaderScriptSnippet] = []
-        self._loader_script_snippets_script: List[LoaderScriptSnippet] = []
-        self._registries: List[PackageRegistry] = []
-        self._load_q: SimpleQueue[Tuple[Package, str]] = SimpleQueue()
-        self._loaded: List[str] = []  # Basepath
-        self._pull_updates: bool = pull_updates
-        self._nu_plugin_paths: List[str] = []
-        self._allow_build_commands: bool = allow_build_commands or False
-        self._nupm_home_symlink_todo: List[Tuple[str, str]] = []
+class PackageId(str):
+    # a package base path, which remembers the collection it came from
+    # -> using it with another collection fails instead of silently matching a package with the same path
+    def __new__(cls, base_path: str, collection_generation: int) -> "PackageId":
+        package_id: PackageId = super().__new__(cls, base_path)
+        package_id.collection_generation = collection_generation
+        return package_id
 
-        if nupm_home is not None:
-            assert len(path.normpath(nupm_home).strip(path.sep).split(path.sep)) > 2, f"Due to security reasons (danger of damaging /home/user or something) the NUPM_HOME cant be this close to the file-root ({nupm_home})"
-            if path.exists(nupm_home):
-                assert delete_existing_nupm_home, f"NUPM_HOME at {nupm_home} already exists and delete existing is off"
+    collection_generation: int
 
-        logger.debug(f"loading initial base package from {numng_file_path}")
-        with open(numng_file_path, "r") as fp:
-            package: Package = load_package_from_json(json.load(fp), allow_no_name=True)
-        for registry in (package.registries or []):
-            self._load_registry(registry, self._download_package(registry))
-        base_path: str = path.abspath(path.join(numng_file_path, path.pardir))
-        self._load_q.put((package, base_path))
 
-        self._allow_build_commands = ((package.extra_data.get("allow_build_commands") or False) if package.extra_data is not None else False) if allow_build_commands is None else allow_build_commands
+_package_collection_generations: Iterator[int] = count()
 
-        logger.debug("entering load_q loop")
-        while not self._load_q.empty():
-            package, base_path = self._load_q.get()
-            if base_path in self._loaded:
-                continue
-            self._loaded.append(base_path)
-            self._load_package(package, base_path)
 
-        if generate_script is not None:
-            logger.debug(f"generating script at {generate_script}")
-            load_script: str = "\n".join([
-                "export-env {",
-                "$env.ENV_CONVERSIONS = ($env | get -i ENV_CONVERSIONS | default {} | upsert 'PATH' {|_| {'from_string': {|s| $s | split row (char esep)}, 'to_string': {|v| $v | str join (char esep)}}})",
-                *([
-                    f"$env.NUPM_HOME = {json.dumps(self._nupm_home)}",
-                    "$env.NU_LIB_DIRS = ($env | get -i NU_LIB_DIRS | default []"
-                    f" | append {json.dumps(path.join(self._nupm_home, 'modules'))}"
-                    f" | append {json.dumps(path.join(self._nupm_home, 'overlays'))})",
-                    f"$env.PATH = ($env.PATH | append {json.dumps(path.join(self._nupm_home, 'bin'))})",
-                ] if self._nupm_home is not None else []),
-                *sort_loader_script_snippets(self._loader_script_snippets_env),
-                "}",
-                *sort_loader_script_snippets([
-                    *self._loader_script_snippets_use,
-                    *self._loader_script_snippets_script,
-                ]),
-            ])
-            with open(generate_script, "w") as fp:
-                fp.write(load_script)
-        if generate_overlay is not None:
-            logger.debug(f"generating overlay at {generate_overlay}")
-            overlay_script: str = "\n".join([
-                "export-env {",
-                *([f"$env.NUPM_HOME = {json.dumps(nupm_home)}"] if self._nupm_home is not None else []),
-                *sort_loader_script_snippets(self._loader_script_snippets_env),
-                "}",
-                *sort_loader_script_snippets(self._loader_script_snippets_use),
-            ])
-            with open(generate_overlay, "w") as fp:
-                fp.write(overlay_script)
+class PackageCollection:
+    # view of the packages a Loader resolved (for tools embedding numng)
+    # package ids are their base paths (unique within a build; duplicates get skipped by the loader)
+    # plain strings are accepted as ids as well, PackageIds from other collections are not
+    def __init__(self, packages: List[Tuple[Package, str]], dependency_ids: Dict[str, List[str]]) -> None:
+        self._packages: List[Tuple[Package, str]] = packages
+        self._dependency_ids: Dict[str, List[str]] = dependency_ids  # package_id -> package_ids of its dependencies
+        self._generation: int = next(_package_collection_generations)
 
-        if nupm_home is not None:
-            logger.debug(f"init nupm_home at {nupm_home}")
-            if path.exists(nupm_home):
-                rmtree(nupm_home)
-            makedirs(path.join(nupm_home, "modules"))
-            mkdir(path.join(nupm_home, "bin"))
-            mkdir(path.join(nupm_home, "overlays"))
+    def _id(self, base_path: str) -> PackageId:
+        return PackageId(base_path, self._generation)
 
-        for s1, s2 in self._nupm_home_symlink_todo:
-            symlink(src=s1, dst=s2)
+    def _check_id(self, package_id: str) -> str:
+        assert not isinstance(package_id, PackageId) or package_id.collection_generation == self._generation, f"The package id {package_id} is from a different package collection"
+        return str(package_id)
 
-        if handle_nu_plugins:
-            logger.debug(f"updating plugins")
-            self._generate_nu_plugins()
+    def __iter__(self) -> Iterator[Tuple[PackageId, Package]]:
+        # (package_id, package) in load order
+        return ((self._id(base_path), package) for package, base_path in self._packages)
 
-    def _registry_get_by_name(self, name: str, **kwargs) -> Optional[Package]:
-        for registry in self._registries:
-            if (result := registry.get_by_name(name, **kwargs)):
-                return result
-        return None
+    def __len__(self) -> int:
+        return len(self._packages)
 
-    def _load_registry(self, package: Package, base_path: str) -> None:
-        logger.debug(f"loading registry from {base_path}")
-        if package.package_format == "nupm":
-            assert path.exists(path.join(base_path, "registry", "registry.nuon")), "Failed to load nupm registry (registry/registry.nuon not found)"
-            self._registries.append(NupmPackageRegistry(path.join(base_path, "registry")))
-            return
-        if package.package_format == "numng":
-            self._registries.append(NumngPackageRegistry(base_path))
-            return
-        raise AssertionError("Failed to load registry (unknown or unsupported package_format)")
+    def ids(self) -> List[PackageId]:
+        return [self._id(base_path) for _, base_path in self._packages]
 
-    def _register_nupm_module(self, module_name: str, module_source_path: str) -> None:
-        if self._nupm_home is None:
-            return
-        dst: str = path.abspath(path.join(self._nupm_home, "modules", filesystem_safe(module_name)))
-        assert dst.startswith(path.join(self._nupm_home, "modules"))
-        self._nupm_home_symlink_todo.append((module_source_path, dst,))
+    def get_package(self, package_id: str) -> Optional[Package]:
+        package_id = self._check_id(package_id)
+        return next((package for package, base_path in self._packages if base_path == package_id), None)
 
-    def _register_nupm_binary(self, binary_name: str, binary_source_path: str) -> None:
+    def dependencies(self, package_id: str) -> List[PackageId]:
+        return [self._id(i) for i in self._dependency_ids.get(self._check_id(package_id), [])]
+
+    def dependents(self, package_id: str) -> List[PackageId]:
+        package_id = self._check_id(package_id)
+        return [self._id(i) for i, dependency_ids in self._dependency_ids.items() if package_id in dependency_ids]
+
+    def replace_package(self, package_id: str, package: Package, new_package_id: Optional[str] = None) -> None:
+        # new_package_id: the replacement is somewhere else (every dependency on the old id gets moved to it)
+        package_id = self._check_id(package_id)
+        index: Optional[int] = next((n for n, (_, base_path) in enumerate(self._packages) if base_path == package_id), None)
+        assert index is not None, f"Unable to replace unknown package {package_id}"
+        new_package_id = package_id if new_package_id is None else self._check_id(new_package_id)
+        assert new_package_id == package_id or self.get_package(new_package_id) is None, f"Unable to replace {package_id} with {new_package_id} (already in the collection)"
+        self._packages[index] = (package, new_package_id)
+        if new_package_id != package_id:
+            if package_id in self._dependency_ids:
+                self._dependency_ids[new_package_id] = self._dependency_ids.pop(package_id)
+            for dependency_ids in self._dependency_ids.values():
+                dependency_ids[:] = [new_package_id if i == package_id else i for i in dependency_ids]
+
+    def remove_package(self, package_id: str) -> None:
+        # removing a package other packages depend on would leave dangling dependencies -> replace it instead
+        package_id = self._check_id(package_id)
+        assert self.get_package(package_id) is not None, f"Unable to remove unknown package {package_id}"
+        assert not (dependents := self.dependents(package_id)), f"Unable to remove {package_id} (required by {', '.join(dependents)})"
+        self._packages[:] = [i for i in self._packages if i[1] != package_id]
+        self._dependency_ids.pop(package_id, None)
+
+    def root_package(self) -> Optional[Package]:
+        # the base package always gets loaded first
+        return self._packages[0][0] if len(self._packages) != 0 else None
+
+    def find_by_name(self, name: str) -> List[PackageId]:
+        return [self._id(base_path) for package, base_path in self._packages if package.name == name]
+
+    def find_matching(self, name: str, requirement: Union[str, VersionReq]) -> List[PackageId]:
+        # packages without a known version (git heads, local packages, ..) never match
+        result: List[PackageId] = []
+        for package_id in self.find_by_name(name):
+            package: Package = self.get_package(package_id)  # type: ignore
+            if package.resolved_version is None:
+                continue
+            version_scheme: str = (package.extra_data or {}).get("version_scheme") or "semver"
+            version_req: VersionReq = VersionReq(requirement, version_scheme) if isinstance(requirement, str) else requirement
+            if version_req.matches(VERSION_SCHEMES[version_scheme](package.resolved_version)):
+                result.append(package_id)
+        return result
+
+
+@dataclass(kw_only=True)
+class BuildReportPackage:
+    name: str
+    path: str
+    version: Optional[str] = None
+    commit: Optional[str] = None
+    build_duration: Optional[float] = None  # None -> nothing was built
+    fetch_duration: Optional[float] = None  # None -> nothing was downloaded (base package)
+    has_build_command: bool = False
+
+
+@dataclass(kw_only=True)
+class BuildReport:
+    packages: List[BuildReportPackage] = field(default_factory=list)
+    skipped_packages: List[str] = field(default_factory=list)  # already loaded from the same path
+    skipped_builds: List[str] = field(default_factory=list)  # unchanged since the last build (incremental)
+    generated_files: List[str] = field(default_factory=list)
+    warnings: List[str] = field(default_factory=list)
+    diagnostics: List["Diagnostic"] = field(default_factory=list)  # warnings, deprecations, and notes (with where they came from)
+    advisories: List[Dict[str, Any]] = field(default_factory=list)  # only filled by audits
+    messages: Dict[str, str] = field(default_factory=dict)  # package name -> post_install_message (only new or updated packages)
+    timings: Dict[str, float] = field(default_factory=dict)  # phase (parse, resolve, build, ..) -> seconds
+    sources: Dict[str, Dict[str, Any]] = field(default_factory=dict)  # "git URL REF" / "archive URL" -> where it came from
+    duration: float = 0.0
+
+
+ERROR_CODES: Dict[str, str] = {
+    # stable (scripts and editors match on them) -> never renumber or reuse a code
+    "NUMNG-E000": "other error",
+    "NUMNG-E001": "invalid package definition",
+    "NUMNG-E002": "the audit found advisories",
+    "NUMNG-E003": "the lockfile does not match (locked build)",
+    "NUMNG-E004": "not in the store (offline)",
+    "NUMNG-E005": "the build was cancelled",
+    "NUMNG-E006": "build_commands failed (--keep-going)",
+}
+
+
+class NumngError(AssertionError):
+    # errors with a stable code (plain assertions are NUMNG-E000)
+    code: str = "NUMNG-E000"
+
+    def to_json(self) -> Dict[str, Any]:
+        return {"error": str(self), "code": self.code}
+
+
+class AuditError(NumngError):
+    code = "NUMNG-E002"
+
+    def __init__(self, message: str, advisories: List[Dict[str, Any]]) -> None:
+        super().__init__(message)
+        self.advisories: List[Dict[str, Any]] = advisories
+
+    def to_json(self) -> Dict[str, Any]:
+        return {**super().to_json(), "advisories": self.advisories}
+
+
+class LockfileError(NumngError):
+    code = "NUMNG-E003"
+
+
+class OfflineError(NumngError):
+    code = "NUMNG-E004"
+
+
+class BuildCancelled(NumngError):
+    code = "NUMNG-E005"
+
+
+class BuildFailures(NumngError):
+    code = "NUMNG-E006"
+
+    def __init__(self, failures: Dict[str, str]) -> None:
+        super().__init__(f"{len(failures)} packages failed to build: " + "; ".join(f"{name} ({message})" for name, message in failures.items()))
+        self.failures: Dict[str, str] = failures  # package name (with its path if the name is ambiguous) -> error
+
+    def to_json(self) -> Dict[str, Any]:
+        return {**super().to_json(), "failures": self.failures}
+
+
+def error_to_json(error: BaseException) -> Dict[str, Any]:
+    return error.to_json() if isinstance(error, NumngError) else {"error": error.args[0] if error.args else str(error), "code": "NUMNG-E000"}
+
+
+class BuildEventKind(Enum):
+    FETCH_STARTED = "fetch_started"
+    FETCH_FINISHED = "fetch_finished"
+    BUILD_STARTED = "build_started"
+    BUILD_FINISHED = "build_finished"
+    PACKAGE_LOADED = "package_loaded"
+    LINK_CREATED = "link_created"
+    WARNING = "warning"
+
+
+@dataclass(kw_only=True)
+class BuildEvent:
+    kind: BuildEventKind
+    package_name: Optional[str] = None
+    message: Optional[str] = None  # path, warning text, etc
+
+
+class ProgressDisplay(logging.Filter):
+    # status line for interactive terminals. also a log filter, which clears the status line before log messages
+    def __init__(self) -> None:
+        super().__init__()
+        self._fetches_started: int = 0
+        self._fetches_finished: int = 0
+        self._builds_finished: int = 0
+        self._packages_loaded: int = 0
+
+    def __call__(self, event: BuildEvent) -> None:
+        status: str = ""
+        if event.kind == BuildEventKind.FETCH_STARTED:
+            self._fetches_started += 1
+            status = f"fetching {event.package_name}"
+        elif event.kind == BuildEventKind.FETCH_FINISHED:
+            self._fetches_finished += 1
+        elif event.kind == BuildEventKind.BUILD_STARTED:
+            status = f"building {event.package_name}"
+        elif event.kind == BuildEventKind.BUILD_FINISHED:
+            self._builds_finished += 1
+        elif event.kind == BuildEventKind.PACKAGE_LOADED:
+            self._packages_loaded += 1
+        elif event.kind == BuildEventKind.LINK_CREATED:
+            status = "linking"
+        stdout.write(
+            f"\r\033[K[{self._packages_loaded} loaded | {self._fetches_finished}/{self._fetches_started} fetched"
+            f" | {self._builds_finished} built] {status}"
+        )
+        stdout.flush()
+
+    def filter(self, record: logging.LogRecord) -> bool:
+        self.finish()
+        return True
+
+    def finish(self) -> None:
+        stdout.write("\r\033[K")
+        stdout.flush()
+
+
+@dataclass(kw_only=True)
+class BuildTask:
+    package: Package
+    base_path: str
+    depends: List[str]  # base_paths of the dependencies
+    command: str  # build_command or path of the build script (part of the build stamp)
+    run: Callable[["BuildTask"], None]
+    env: Dict[str, str] = field(default_factory=dict)  # build_env of the package (part of the build stamp)
+
+
+@dataclass(kw_only=True)
+class Config:
+    # user-wide defaults from CONFIG_FILE_NAME (CLI flags and the base package take priority)
+    connection_policy: str = "download"
+    allow_build_commands: Optional[bool] = None
+    registries: List[Dict[str, Any]] = field(default_factory=list)  # used if the base package has none
+    jobs: int = 4
+    build_jobs: int = 1
+    nu_binary: str = "nu"
+    nupm_home_link_mode: Optional[str] = None
+    nupm_home_layout: Optional[str] = None
+    latest_git_ref: Optional[str] = None
+    update_notification: bool = False  # check for updates (once per day) when the load script gets loaded
+    git_config: Dict[str, str] = field(default_factory=dict)  # extra `git -c KEY=VALUE` settings (proxies, CA bundles, ..)
+    credentials: Dict[str, Dict[str, Any]] = field(default_factory=dict)  # host -> how to get a token for it
+    mirrors: Dict[str, str] = field(default_factory=dict)  # source_uri prefix -> replacement (applied before fetching)
+    raw_registries: bool = False  # read uncloned registries on github, gitlab, or codeberg file by file over http (see BuildOptions.raw_registries)
+
+
+def load_config(config_file: Optional[str] = None) -> Config:
+    config_file = config_file or config_path(CONFIG_FILE_NAME)
+    if not path.isfile(config_file):
+        return Config()
+    with open(config_file, "r") as fp:
+        config_json = json.load(fp)
+    assert isinstance(config_json, dict), f"Invalid config {config_file} (not a dict)"
+    assert not (unknown_keys := [i for i in config_json if i not in Config.__dataclass_fields__]), f"Invalid config {config_file} (unknown keys: {', '.join(unknown_keys)})"
+    config: Config = Config(**config_json)
+    # bool is a int -> checked by type instead of isinstance (`"jobs": true` is a mistake, not 1 job)
+    for key, types in (
+        ("connection_policy", (str,)), ("allow_build_commands", (bool, type(None))), ("jobs", (int,)), ("build_jobs", (int,)), ("nu_binary", (str,)),
+        ("nupm_home_link_mode", (str, type(None))), ("nupm_home_layout", (str, type(None))), ("latest_git_ref", (str, type(None))),
+        ("update_notification", (bool,)), ("raw_registries", (bool,)),
+    ):
+        assert type(value := getattr(config, key)) in types, f"Invalid config {config_file} ({key} is a {type(value).__name__} instead of a {' or '.join('null' if i is type(None) else i.__name__ for i in types)})"
+    assert config.jobs > 0 and config.build_jobs > 0, f"Invalid config {config_file} (jobs and build_jobs have to be positive)"
+    config.connection_policy = parse_connection_policy(config.connection_policy)
+    assert isinstance(config.registries, list), f"Invalid config {config_file} (registries is not a list)"
+    assert isinstance(config.git_config, dict) and all(isinstance(i, str) for i in config.git_config.values()), f"Invalid config {config_file} (git_config is not a dict of strings)"
+    assert isinstance(config.mirrors, dict) and all(isinstance(i, str) for i in config.mirrors.values()), f"Invalid config {config_file} (mirrors is not a dict of strings)"
+    assert isinstance(config.credentials, dict), f"Invalid config {config_file} (credentials is not a dict)"
+    for host, credential in config.credentials.items():
+        assert isinstance(credential, dict), f"Invalid config {config_file} (credentials.{host} is not a dict)"
+        assert not (unknown_keys := [i for i in credential if i not in ("username", "token_env", "token_command")]), f"Invalid config {config_file} (unknown keys in credentials.{host}: {', '.join(unknown_keys)})"
+        assert ("token_env" in credential) != ("token_command" in credential), f"Invalid config {config_file} (credentials.{host} needs either token_env or token_command)"
+        assert "token_command" not in credential or (isinstance(credential["token_command"], list) and len(credential["token_command"]) > 0), f"Invalid config {config_file} (credentials.{host}.token_command is not a list)"
+    return config
+
+
+def apply_environment_variables(config: Config) -> Config:
+    # NUMNG_* environment variables override the config file (and get overridden by CLI flags)
+    if (value := environ.get("NUMNG_CONNECTION_POLICY")) is not None:
+        config.connection_policy = parse_connection_policy(value)
+    if (value := environ.get("NUMNG_OFFLINE")) is not None:
+        if parse_env_bool("NUMNG_OFFLINE", value):
+            config.connection_policy = "offline"
+        elif config.connection_policy == "offline":
+            config.connection_policy = "download"
+    if (value := environ.get("NUMNG_ALLOW_BUILD")) is not None:
+        config.allow_build_commands = parse_env_bool("NUMNG_ALLOW_BUILD", value)
+    for env_name, key in (("NUMNG_JOBS", "jobs"), ("NUMNG_BUILD_JOBS", "build_jobs")):
+        if (value := environ.get(env_name)) is not None:
+            assert value.isdigit() and int(value) > 0, f"Invalid {env_name} ({value} is not a positive number)"
+            setattr(config, key, int(value))
+    if environ.get("NUMNG_NU_BINARY"):
+        config.nu_binary = environ["NUMNG_NU_BINARY"]
+    return config
+
+
+def parse_connection_policy(value: str) -> str:
+    assert (value := value.strip().lower()) in CONNECTION_POLICIES, f"Invalid connection policy {value} (valid: {', '.join(CONNECTION_POLICIES)})"
+    return value
+
+
+def parse_env_bool(name: str, value: str) -> bool:
+    if value.strip().lower() in ("1", "true", "yes", "on"):
+        return True
+    if value.strip().lower() in ("0", "false", "no", "off", ""):
+        return False
+    raise AssertionError(f"Invalid {name} ({value} is neither true nor false)")
+
+
+@dataclass(kw_only=True)
+class BuildOptions:
+    generate_script: Optional[str] = None
+    generate_overlay: Optional[str] = None
+    nupm_home: Optional[str] = None
+    delete_existing_nupm_home: bool = False
+    pull_updates: bool = False
+    offline: bool = False  # fail instead of downloading missing packages
+    handle_nu_plugins: bool = False
+    allow_build_commands: Optional[bool] = None  # None -> ask the user
+    dry_run: bool = False  # only download and resolve (no builds, links, etc)
+    audit: bool = False
+    lockfile: Optional[str] = None
+    locked: bool = False
+    jobs: int = 4  # concurrent downloads
+    build_jobs: int = 1  # concurrent build_commands (most build tools already use multiple cores)
+    incremental: bool = True  # skip builds of packages, which are unchanged since the last build into nupm_home
+    observer: Optional[Callable[[BuildEvent], None]] = None  # gets called for progress updates
+    config: Optional[Config] = None  # fallbacks for settings the base package doesn't specify
+    vendor: bool = True  # prefer the copies in VENDOR_DIR_NAME (`numng vendor`) over the store
+    strict: bool = False  # unknown keys in numng.json files are errors instead of warnings
+    registries: List[PackageRegistry] = field(default_factory=list)  # get asked before the registries of the base package (MemoryPackageRegistry, ..)
+    command_runner: Optional["CommandRunner"] = None  # None -> subprocess.run
+    cancellation: Optional["CancellationToken"] = None  # cancel it (from another thread) to stop the build
+    keep_going: bool = False  # a failing build_command doesn't stop the other builds (all failures get reported at the end)
+    target: Optional[str] = None  # target triple for cross-compiling (exported to build_commands as CARGO_BUILD_TARGET)
+    dev: bool = False  # also load the dev_depends of the base package (`numng test`)
+    raw_registries: Optional[bool] = None  # numng registries on github, gitlab, or codeberg, which are not in the store yet, get read file by file (instead of cloning them). None -> Config.raw_registries
+
+
+class Loader:
+    def __init__(self, numng_file_path: str, options: Optional[BuildOptions] = None, **legacy_options) -> None:
+        if options is None:
+            if legacy_options:
+                warnings.warn("Passing build options as keyword arguments to Loader is deprecated (use BuildOptions)", DeprecationWarning, stacklevel=2)
+            options = BuildOptions(**legacy_options)
+        else:
+            assert not legacy_options, "Loader got both BuildOptions and keyword arguments"
+        self._nupm_home: Optional[str] = options.nupm_home
+        self._loader_script_snippets_env: List[LoaderScriptSnippet] = []
+        self._loader_script_snippets_use: List[LoaderScriptSnippet] = []
+        self._loader_script_snippets_script: List[LoaderScriptSnippet] = []
+        self._registries: CompositePackageRegistry = CompositePackageRegistry()  # in priority order
+        self._advisory_dbs: List[AdvisoryDatabase] = []
+        self._trusted_keys: Dict[str, str] = {}
+        self._build_sandbox: Union[bool, Dict[str, bool]] = False
+        self._nupm_home_link_mode: str = "symlink"
+        self._nupm_home_bin_dir: str = "bin"  # "scripts" for the nupm layout
+        self._latest_git_ref: Optional[str] = "branch"  # registries and advisory dbs (downloaded before the base package config is read) track their branch
+        self._nu_version_check: str = "warn"
+        self._installed_nu_version: Optional[str] = None
+        self._load_q: SimpleQueue[Tuple[Package, str]] = SimpleQueue()
+        self._loaded: List[str] = []  # Basepath
+        self._loaded_packages: List[Tuple[Package, str]] = []
+        self._dependency_ids: Dict[str, List[str]] = {}  # Basepath -> Basepaths of its dependencies
+        self.packages: PackageCollection = PackageCollection(self._loaded_packages, self._dependency_ids)
+        self._pull_updates: bool = options.pull_updates
+        self._offline: bool = options.offline
+        self._jobs: int = options.jobs
+        self._build_jobs: int = options.build_jobs
+        self._build_tasks: List[BuildTask] = []
+        self._build_paths: List[str] = []  # Basepath of every package with a build (even if skipped due to dry_run)
+        self._executable_todo: List[str] = []
+        self._nu_plugin_paths: List[str] = []
+        self._allow_build_commands: Optional[bool] = options.allow_build_commands
+        self._nupm_home_symlink_todo: List[Tuple[str, str]] = []
+        self._dry_run: bool = options.dry_run
+        self._locked: bool = options.locked
+        self._strict: bool = options.strict
+        self._observer: Optional[Callable[[BuildEvent], None]] = options.observer
+        self._numng_file_path: str = path.abspath(numng_file_path)
+        self._lock_data: Dict[str, Any] = {"git": {}, "archive": {}}
+        self._resolved_commits: Dict[str, str] = {}  # Basepath -> git commit
+        self._archive_hashes: Dict[str, str] = {}  # Basepath -> sha256 of the archive
+        self._store_paths: List[str] = []  # every downloaded path (packages, registries, linkins, ..)
+        self._git_fetch_cache: Dict[str, Tuple[str, str]] = {}  # "normalized_url ref" -> (ref_path, commit)
+        self._git_tag_cache: Dict[str, List[str]] = {}  # normalized_url -> tags
+        self._build_durations: Dict[str, float] = {}  # Basepath -> seconds
+        self._fetch_durations: Dict[str, float] = {}  # Basepath -> seconds
+        self._previous_state: Optional[Dict[str, Any]] = None
+        self._resumed_stamps: Dict[str, str] = {}  # builds, which finished in the last (failed or interrupted) run
+        self._incremental: bool = options.incremental
+        self._artifact_cache: bool = True
+        self._artifact_toolchain: Optional[str] = None  # nu and cargo version (part of the artifact key)
+        self._hooks: Dict[str, List[str]] = {kind: [] for kind in HOOK_KINDS}  # kind -> nu snippets (from the base package)
+        self._post_install_messages: Dict[str, Tuple[str, str]] = {}  # package name -> (message, hash of message and version)
+        self._build_progress: Dict[str, str] = {}
+        self._build_progress_lock: Lock = Lock()
+        self._vendored: Dict[str, Dict[str, Any]] = {}  # same keys as report.sources
+        self._build_stamps: Dict[str, str] = {}  # Basepath -> hash of the built content + build command
+        self._resolution_cache: Dict[str, Dict[str, Any]] = load_resolution_cache()  # cache_id -> {"revision": .., "time": .., "packages": {lookup: package_json}}
+        self._registry: CachingPackageRegistry = CachingPackageRegistry(self._registries, self._resolution_cache, ttl=RESOLUTION_CACHE_TTL)
+        self.report: BuildReport = BuildReport()
+        start_time: float = time()
+        self._phase_start_time: float = start_time
+
+        if options.nupm_home is not None:
+            assert len(path.normpath(options.nupm_home).strip(path.sep).split(path.sep)) > 2, f"Due to security reasons (danger of damaging /home/user or something) the NUPM_HOME cant be this close to the file-root ({options.nupm_home})"
+            if path.exists(options.nupm_home):
+                assert options.delete_existing_nupm_home, f"NUPM_HOME at {options.nupm_home} already exists and delete existing is off"
+                if options.incremental:
+                    self._previous_state = load_environment_state(options.nupm_home)
+            if options.incremental:
+                self._resumed_stamps = load_build_progress(options.nupm_home)
+        assert not (options.offline and options.pull_updates), "Pulling updates is not possible in offline mode"
+        config: Config = options.config or Config()
+        self._cancellation: CancellationToken = options.cancellation or CancellationToken()
+        self._keep_going: bool = options.keep_going
+        self._target: Optional[str] = options.target
+        self._dev: bool = options.dev
+        # only used by this Loader (and the functions it calls) -> other Loaders (concurrent or later ones) keep their own settings
+        self._context: RunContext = RunContext(
+            config=config,
+            runner=options.command_runner or CommandRunner(),
+            cancellation=self._cancellation,
+            # the data and config directory of the caller (project-local environment, temporary_store, ..)
+            base_directory=current_run_context().base_directory,
+            config_directory=current_run_context().config_directory,
+        )
+        with use_run_context(self._context):
+            self._load(numng_file_path, options, config, start_time)
+
+    def _load(self, numng_file_path: str, options: BuildOptions, config: Config, start_time: float) -> None:
+        if options.locked:
+            assert not options.pull_updates, "Pulling updates is not possible in a locked build"
+            if options.lockfile is None or not path.isfile(options.lockfile):
+                raise LockfileError(f"Unable to do a locked build without a lockfile ({options.lockfile})")
+            with open(options.lockfile, "r") as fp:
+                self._lock_data = json.load(fp)
+            assert isinstance(self._lock_data, dict), f"Invalid lockfile {options.lockfile} (not a dict)"
+
+        # pulling updates fetches from upstream (the next `numng vendor` copies the new versions)
+        if options.vendor and not options.pull_updates and path.isfile(vendor_manifest := path.join(path.dirname(self._numng_file_path), VENDOR_DIR_NAME, "numng-vendor.json")):
+            logger.debug(f"using the vendored sources from {vendor_manifest}")
+            with open(vendor_manifest, "r") as fp:
+                self._vendored = json.load(fp)
+            for source in self._vendored.values():
+                source["path"] = path.join(path.dirname(vendor_manifest), source["path"])
+
+        logger.debug(f"loading initial base package from {numng_file_path}")
+        package: Package = load_package_from_json(read_package_file(numng_file_path)[0], allow_no_name=True)  # diagnostics get reported by _load_numng
+        self._trusted_keys = (package.extra_data or {}).get("trusted_keys") or {}
+        assert isinstance(self._trusted_keys, dict), "Invalid base package (trusted_keys is not a dict)"
+        for registry in options.registries:
+            self._registries.append(registry)
+        for registry in (package.registries or [load_package_from_json(i, allow_no_name=True) for i in config.registries]):
+            if (config.raw_registries if options.raw_registries is None else options.raw_registries) and self._load_raw_file_registry(registry):
+                continue
+            self._load_registry(registry, self._download_package(registry))
+        for advisory_db in _listify((package.extra_data or {}).get("advisory_db")):
+            advisory_db_pkg: Package = load_package_from_json(advisory_db, allow_no_name=True)
+            self._advisory_dbs.append(AdvisoryDatabase(self._download_package(advisory_db_pkg)))
+        base_path: str = path.abspath(path.join(numng_file_path, path.pardir))
+        self._load_q.put((package, base_path))
+        self._end_phase("parse")
+
+        self._allow_build_commands = (package.extra_data.get("allow_build_commands") if package.extra_data is not None else None) if options.allow_build_commands is None else options.allow_build_commands
+        if self._allow_build_commands is None:
+            self._allow_build_commands = config.allow_build_commands
+        self._build_sandbox = (package.extra_data or {}).get("build_sandbox") or False
+        assert isinstance(self._build_sandbox, (bool, dict)), "Invalid base package (build_sandbox is neither a boolean nor a dict)"
+        self._nupm_home_link_mode = (package.extra_data or {}).get("nupm_home_link_mode") or config.nupm_home_link_mode or "symlink"
+        assert self._nupm_home_link_mode in ("symlink", "copy"), f"Invalid base package (unknown nupm_home_link_mode {self._nupm_home_link_mode})"
+        nupm_home_layout: str = (package.extra_data or {}).get("nupm_home_layout") or config.nupm_home_layout or "numng"
+        assert nupm_home_layout in ("numng", "nupm"), f"Invalid base package (unknown nupm_home_layout {nupm_home_layout})"
+        # nupm puts everything executable into scripts/ (and only adds that to PATH)
+        self._nupm_home_bin_dir = "scripts" if nupm_home_layout == "nupm" else "bin"
+        # None -> only `"version": "latest"` resolves to the newest tag (packages without version keep tracking the branch)
+        self._latest_git_ref = (package.extra_data or {}).get("latest_git_ref") or config.latest_git_ref
+        assert self._latest_git_ref in ("tag", "branch", None), f"Invalid base package (unknown latest_git_ref {self._latest_git_ref})"
+        self._nu_version_check = (package.extra_data or {}).get("nu_version_check") or "warn"
+        assert self._nu_version_check in ("warn", "error", "ignore"), f"Invalid base package (unknown nu_version_check {self._nu_version_check})"
+        self._artifact_cache = (package.extra_data or {}).get("artifact_cache", True)
+        assert isinstance(self._artifact_cache, bool), "Invalid base package (artifact_cache is not a boolean)"
+        self._hooks = {kind: _listify((package.extra_data or {}).get(kind)) for kind in HOOK_KINDS}
+        for kind, snippets in self._hooks.items():
+            assert all(isinstance(i, str) for i in snippets), f"Invalid base package ({kind} is neither a string nor a list of strings)"
+        if any(self._hooks.values()) and not self._dry_run:
+            # asked before anything gets built (and only once for all hooks)
+            self._assert_build_allowed(package, "\n".join(f"{kind}: {i}" for kind, snippets in self._hooks.items() for i in snippets))
+
+        logger.debug("entering load_q loop")
+        while not self._load_q.empty():
+            package, base_path = self._load_q.get()
+            if base_path in self._loaded:
+                self.report.skipped_packages.append(package.name)
+                continue
+            self._loaded.append(base_path)
+            self._loaded_packages.append((package, base_path))
+            self._load_package(package, base_path)
+            self._emit(BuildEventKind.PACKAGE_LOADED, package.name, base_path)
+
+        self._end_phase("resolve")
+        # everything is downloaded at this point -> build
+        self._run_builds()
+        self._end_phase("build")
+        for package, base_path in self._loaded_packages:
+            self.report.packages.append(BuildReportPackage(
+                name=package.name,
+                path=base_path,
+                version=package.resolved_version,
+                commit=self._resolved_commits.get(base_path),
+                build_duration=self._build_durations.get(base_path),
+                fetch_duration=self._fetch_durations.get(base_path),
+                has_build_command=base_path in self._build_paths,
+            ))
+
+        if options.audit:
+            self._audit()
+            self._end_phase("audit")
+
+        if options.generate_script is not None:
+            logger.debug(f"generating script at {options.generate_script}")
+            load_script: str = "\n".join([
+                "export-env {",
+                "$env.ENV_CONVERSIONS = ($env | get -i ENV_CONVERSIONS | default {} | upsert 'PATH' {|_| {'from_string': {|s| $s | split row (char esep)}, 'to_string': {|v| $v | str join (char esep)}}})",
+                *([
+                    f"$env.NUPM_HOME = {json.dumps(self._nupm_home)}",
+                    "$env.NU_LIB_DIRS = ($env | get -i NU_LIB_DIRS | default []"
+                    f" | append {json.dumps(path.join(self._nupm_home, 'modules'))}"
+                    f" | append {json.dumps(path.join(self._nupm_home, 'overlays'))})",
+                    f"$env.PATH = ($env.PATH | append {json.dumps(path.join(self._nupm_home, self._nupm_home_bin_dir))})",
+                    f"$env.NU_PLUGIN_DIRS = ($env | get -i NU_PLUGIN_DIRS | default [] | append {json.dumps(path.join(self._nupm_home, 'plugins'))})",
+                ] if self._nupm_home is not None else []),
+                *sort_loader_script_snippets(self._loader_script_snippets_env),
+                "}",
+                *sort_loader_script_snippets([
+                    *self._loader_script_snippets_use,
+                    *self._loader_script_snippets_script,
+                ]),
+                *([update_notification_snippet(self._numng_file_path)] if config.update_notification and options.lockfile is not None else []),
+            ])
+            with open(options.generate_script, "w") as fp:
+                fp.write(load_script)
+            self.report.generated_files.append(options.generate_script)
+        if options.generate_overlay is not None:
+            logger.debug(f"generating overlay at {options.generate_overlay}")
+            overlay_script: str = "\n".join([
+                "export-env {",
+                *([
+                    f"$env.NUPM_HOME = {json.dumps(options.nupm_home)}",
+                    f"$env.NU_PLUGIN_DIRS = ($env | get -i NU_PLUGIN_DIRS | default [] | append {json.dumps(path.join(self._nupm_home, 'plugins'))})",
+                ] if self._nupm_home is not None else []),
+                *sort_loader_script_snippets(self._loader_script_snippets_env),
+                "}",
+                *sort_loader_script_snippets(self._loader_script_snippets_use),
+            ])
+            with open(options.generate_overlay, "w") as fp:
+                fp.write(overlay_script)
+            self.report.generated_files.append(options.generate_overlay)
+        self._end_phase("generate")
+
+        if options.nupm_home is not None and not self._dry_run:
+            # the new nupm_home gets created next to the old one and swapped in at the end -> a failure keeps the old one intact
+            nupm_home_abs: str = path.abspath(options.nupm_home)
+            tmp_nupm_home: Optional[str] = f"{nupm_home_abs}.tmp-{token_hex(4)}"
+            logger.debug(f"init nupm_home at {tmp_nupm_home}")
+            makedirs(path.join(tmp_nupm_home, "modules"))
+            mkdir(path.join(tmp_nupm_home, self._nupm_home_bin_dir))
+            mkdir(path.join(tmp_nupm_home, "overlays"))
+            mkdir(path.join(tmp_nupm_home, "plugins"))
+        else:
+            tmp_nupm_home = None
+
+        try:
+            if not self._dry_run:
+                for executable in self._executable_todo:
+                    chmod(executable, os_stat(executable).st_mode | stat.S_IEXEC)
+                for s1, s2 in self._nupm_home_symlink_todo:
+                    if tmp_nupm_home is not None and s2.startswith(nupm_home_abs + path.sep):
+                        s2 = tmp_nupm_home + s2[len(nupm_home_abs):]
+                        if self._nupm_home_link_mode == "copy":
+                            copy_path_cow(s1, s2)
+                            self._emit(BuildEventKind.LINK_CREATED, message=f"{s2} <- {s1}")
+                            continue
+                    create_symlink(s1, s2)
+                    self._emit(BuildEventKind.LINK_CREATED, message=f"{s2} -> {s1}")
+            if tmp_nupm_home is not None:
+                self._write_state(tmp_nupm_home)
+                if path.exists(nupm_home_abs):
+                    self._run_hooks("pre_remove")
+                logger.debug(f"moving nupm_home to {nupm_home_abs}")
+                replace_directory(tmp_nupm_home, nupm_home_abs)
+        except BaseException:
+            # replace_directory restores the old nupm_home if it fails -> only the new one has to be removed
+            if tmp_nupm_home is not None and path.lexists(tmp_nupm_home):
+                rmtree(tmp_nupm_home)
+            raise
+        if tmp_nupm_home is not None:
+            self.report.generated_files.append(nupm_home_abs)
+            save_build_progress(nupm_home_abs, None)
+
+        if options.handle_nu_plugins and not self._dry_run and self._target is not None:
+            logger.warning(f"Not registering plugins with nu, since they got built for {self._target}")
+        elif options.handle_nu_plugins and not self._dry_run:
+            logger.debug(f"updating plugins")
+            self._generate_nu_plugins()
+        self._end_phase("link")
+
+        if options.lockfile is not None and not (self._locked or self._dry_run):
+            logger.debug(f"writing lockfile {options.lockfile}")
+            save_json_state(options.lockfile, self._lock_data, indent=4)
+            self.report.generated_files.append(options.lockfile)
+        if not self._dry_run:
+            record_environment(self._numng_file_path, options.nupm_home, options.lockfile, self._store_paths)
+            if self._registry.changed:
+                save_resolution_cache(self._resolution_cache)
+            self._run_hooks("post_install")
+            self._show_post_install_messages()
+
+        self._end_phase("finalize")
+        self.report.duration = time() - start_time
+
+    def _show_post_install_messages(self) -> None:
+        # each message gets printed once per package file and installed version
+        if not self._post_install_messages:
+            return
+        shown: Dict[str, str] = load_shown_messages(self._numng_file_path)
+        for name, (message, message_hash) in self._post_install_messages.items():
+            if shown.get(name) == message_hash:
+                continue
+            logger.info(f"Message from {name}: {message}")
+            self.report.messages[name] = message
+            shown[name] = message_hash
+        if self.report.messages:
+            save_shown_messages(self._numng_file_path, shown)
+
+    def _run_hooks(self, kind: str) -> None:
+        # pre_remove: the old nupm_home still exists, post_install: everything (nupm_home, script, plugins, lockfile) is done
+        env: Dict[str, str] = {**environ, "NUMNG_HOOK": kind}
+        if self._nupm_home is not None:
+            env["NUMNG_NUPM_HOME"] = path.abspath(self._nupm_home)
+        for snippet in self._hooks[kind]:
+            logger.debug(f"running {kind} hook: {snippet}")
+            hook_proc = run_command([self._context.config.nu_binary, "--no-config-file", "-c", snippet], cwd=path.dirname(self._numng_file_path), stdout=subprocess.DEVNULL, env=env, context=self._context)
+            assert hook_proc.returncode == 0, f"{kind} hook failed: {snippet}"
+
+    def _end_phase(self, name: str) -> None:
+        now: float = time()
+        self.report.timings[name] = self.report.timings.get(name, 0.0) + now - self._phase_start_time
+        self._phase_start_time = now
+
+    def _write_state(self, nupm_home: str) -> None:
+        # paths within the (temporary) nupm_home get stored as if they were already at their final location
+        state: Dict[str, Any] = {
+            "package_file": self._numng_file_path,
+            "build_time": time(),
+            "packages": [
+                {
+                    "name": package.name,
+                    "source_type": package.source_type,
+                    "source_uri": package.source_uri,
+                    "git_ref": package.source_git_ref,
+                    "path": base_path,
+                    "version": package.resolved_version,
+                    "commit": self._resolved_commits.get(base_path),
+                    "sha256": self._archive_hashes.get(base_path),
+                    "build_stamp": self._build_stamps.get(base_path),
+                }
+                for package, base_path in self._loaded_packages
+            ],
+            "links": [{"source": s1, "target": s2} for s1, s2 in self._nupm_home_symlink_todo],
+            "generated_files": self.report.generated_files,
+        }
+        with open(path.join(nupm_home, STATE_FILE_NAME), "w") as fp:
+            json.dump(state, fp, indent=4)
+
+    def _check_nu_version(self, package: Package, supported_versions: List[str]) -> None:
+        # supported_versions: semver ranges (any of them has to match)
+        supported_versions = list(dict.fromkeys(supported_versions))  # the base package's numng.json is also its extra_data
+        if len(supported_versions) == 0 or self._nu_version_check == "ignore":
+            return
+        if self._installed_nu_version is None:
+            self._installed_nu_version = get_installed_nu_version() or ""
+            if self._installed_nu_version == "":
+                self._warn("Unable to determine the installed nushell version (`nu --version` failed) -> skipping nu_version checks")
+        if self._installed_nu_version == "" or any(VersionReq(i).matches(SemVer(self._installed_nu_version)) for i in supported_versions):
+            return
+        message: str = f"{package.name} does not support the installed nushell {self._installed_nu_version} (supported: {', '.join(supported_versions)})"
+        assert self._nu_version_check != "error", message
+        self._warn(message, package.name)
+
+    def _warn(self, message: str, package_name: Optional[str] = None) -> None:
+        self._diagnose(Diagnostic(severity="warning", message=message, package=package_name))
+
+    def _diagnose(self, diagnostic: "Diagnostic") -> None:
+        # problems, which shouldn't abort the build (notes only get logged and end up in the report)
+        self.report.diagnostics.append(diagnostic)
+        if diagnostic.severity == "note":
+            logger.info(diagnostic.message)
+            return
+        message: str = diagnostic.message if diagnostic.severity == "warning" else f"deprecated: {diagnostic.message}"
+        if location := diagnostic.location():
+            message += f" (at {location})"
+        logger.warning(message)
+        self.report.warnings.append(message)
+        self._emit(BuildEventKind.WARNING, diagnostic.package, message)
+
+    def _emit(self, kind: BuildEventKind, package_name: Optional[str] = None, message: Optional[str] = None) -> None:
+        if self._observer is not None:
+            self._observer(BuildEvent(kind=kind, package_name=package_name, message=message))
+
+    def _audit(self) -> None:
+        assert len(self._advisory_dbs) != 0, "No advisory database configured (add `advisory_db` to the base package)"
+        findings: List[str] = []
+        for package, _ in self._loaded_packages:
+            for advisory_db in self._advisory_dbs:
+                for advisory in advisory_db.get_advisories(package.name):
+                    if advisory_affects_version(advisory, package.resolved_version, (package.extra_data or {}).get("version_scheme") or "semver"):
+                        self.report.advisories.append({
+                            "package": package.name,
+                            "version": package.resolved_version,
+                            "id": advisory.get("id"),
+                            "description": advisory.get("description"),
+                            "url": advisory.get("url"),
+                        })
+                        findings.append(
+                            f"{package.name} ({package.resolved_version or 'unknown version'}): {advisory.get('id') or 'NO_ID'}"
+                            f" {advisory.get('description') or ''}{(' <' + advisory['url'] + '>') if advisory.get('url') else ''}"
+                        )
+        if len(findings) != 0:
+            raise AuditError(f"Audit found {len(findings)} matching advisories:\n" + "\n".join(findings), self.report.advisories)
+        logger.info(f"Audit: no known advisories match the {len(self._loaded_packages)} loaded packages")
+
+    def _assert_build_allowed(self, package: Package, build_command: str) -> None:
+        if self._allow_build_commands is not None:
+            assert self._allow_build_commands, f'package {package.name} contains a build_command. to use this package you will have to allow those by adding `"allow_build_commands": true` to your config'
+            return
+        # trust on first use: build_commands approved with "always" are remembered until they change
+        command_hash: str = hashlib.sha256(build_command.encode(encoding="utf-8")).hexdigest()
+        trusted_builds_file: str = config_path(TRUSTED_BUILDS_FILE_NAME)
+        trusted_builds: Dict[str, str] = {}
+        if path.isfile(trusted_builds_file):
+            with open(trusted_builds_file, "r") as fp:
+                trusted_builds = json.load(fp)
+        if trusted_builds.get(package.name) == command_hash:
+            logger.debug(f"build_command of {package.name} is trusted")
+            return
+        assert stdin.isatty(), f'package {package.name} contains a new or changed build_command. to use this package you will have to approve it in a interactive session or allow build_commands by adding `"allow_build_commands": true` to your config'
+        print("\n".join([
+            f"Package {package.name} wants to run a build_command",
+            f"source: {package.source_uri or 'local'}{(' (' + package.source_git_ref + ')') if package.source_git_ref else ''}",
+            "command:",
+            build_command,
+        ]))
+        answer: str = input("Allow? (y = yes, N = no, always = yes and remember it): ").lower()
+        if answer in ("y", "yes"):
+            return
+        if answer != "always":
+            raise AssertionError(f"build_command of {package.name} was not approved")
+        trusted_builds[package.name] = command_hash
+        save_json_state(trusted_builds_file, trusted_builds, indent=4)
+
+    def _run_builds(self) -> None:
+        # dependencies are built before their dependents. independent builds can run in parallel (build_jobs)
+        # packages with the same build stamp as last time (and whose dependencies didn't change) are skipped
+        # so are the builds, which already finished in a failed or interrupted run (unless one of their dependencies got rebuilt since)
+        # everything is keyed by base_path (packages with the same name can come from different sources or refs)
+        todo: List[BuildTask] = list(self._build_tasks)
+        names: Dict[str, str] = {task.base_path: task.package.name for task in todo}
+        built: List[str] = []  # by this run
+        skipped: List[str] = []
+        changed: List[str] = [i for i in self._changed_base_paths() if not any(i == task.base_path for task in todo)]
+        previous_stamps: Dict[str, str] = {
+            i.get("path"): i.get("build_stamp")
+            for i in (self._previous_state or {}).get("packages") or []
+            if i.get("build_stamp") is not None
+        }
+        failures: Dict[str, str] = {}  # keep_going: base_path -> error
+        while len(todo) != 0:
+            ready: List[BuildTask] = [
+                task for task in todo
+                if not any(dep == i.base_path for dep in task.depends for i in todo if i is not task)
+            ]
+            assert len(ready) != 0, "Unable to sort builds (circular dependencies): " + " ".join(i.package.name for i in todo)
+            todo = [task for task in todo if not any(task is i for i in ready)]
+            for task in [i for i in ready if any(dep in failures for dep in i.depends)]:
+                failures[task.base_path] = "skipped, since " + ", ".join(names[dep] for dep in dict.fromkeys(task.depends) if dep in failures) + " failed to build"
+            ready = [i for i in ready if i.base_path not in failures]
+            for task in ready:
+                self._build_stamps[task.base_path] = self._build_stamp(task)
+            for task in [i for i in ready if previous_stamps.get(i.base_path) == self._build_stamps[i.base_path] and not any(dep in changed for dep in i.depends)]:
+                logger.debug(f"Skipping the build of {task.package.name} (unchanged since the last build)")
+                self.report.skipped_builds.append(task.package.name)
+                skipped.append(task.base_path)
+            ready = [i for i in ready if i.base_path not in skipped]
+            changed.extend(task.base_path for task in ready)
+            for task in [i for i in ready if self._resumed_stamps.get(i.base_path) == self._build_stamps[i.base_path] and not any(dep in built for dep in i.depends)]:
+                logger.info(f"Skipping the build of {task.package.name} (already built by the last unfinished run)")
+                self.report.skipped_builds.append(task.package.name)
+                skipped.append(task.base_path)
+                self._record_build_progress(task)
+            ready = [i for i in ready if i.base_path not in skipped]
+            built.extend(task.base_path for task in ready)
+            if len(ready) == 0:
+                continue
+            if self._build_jobs <= 1 or len(ready) == 1:
+                for task in ready:
+                    self._run_build_task(task, failures)
+            else:
+                with ThreadPoolExecutor(max_workers=self._build_jobs) as executor:
+                    try:
+                        list(executor.map(self._in_context(partial(self._run_build_task, failures=failures)), ready))
+                    except KeyboardInterrupt:
+                        self._cancellation.cancel()  # don't wait for the other builds to finish
+                        raise
+        if failures:
+            # the path is only added if the name alone is ambiguous
+            raise BuildFailures({
+                names[base_path] if list(names.values()).count(names[base_path]) == 1 else f"{names[base_path]} ({base_path})": message
+                for base_path, message in failures.items()
+            })
+
+    def _in_context(self, function: Callable[..., Any]) -> Callable[..., Any]:
+        # worker threads don't inherit context variables -> the run context has to be set again
+        def wrapper(*args: Any, **kwargs: Any) -> Any:
+            with use_run_context(self._context):
+                return function(*args, **kwargs)
+        return wrapper
+
+    def _run_build_task(self, task: BuildTask, failures: Dict[str, str]) -> None:
+        self._cancellation.check()
+        with log_span(package=task.package.name, phase="build"):
+            artifact_key: Optional[str] = self._artifact_key(task)
+            if artifact_key is not None and self._incremental and path.isfile(artifact := data_path(ARTIFACT_CACHE_DIR_NAME, f"{artifact_key}.tar")):
+                logger.info(f"Restoring the build output of {task.package.name} from the artifact cache")
+                with tarfile.open(artifact, "r:") as tar:
+                    extract_tar(tar, task.base_path)
+                utime(artifact)  # used -> not garbage
+                self._record_build_progress(task)
+                return
+            try:
+                task.run(task)
+            except BuildCancelled:
+                raise
+            except AssertionError as exc:
+                if not self._keep_going or not exc.args:
+                    raise
+                logger.warning(f"{exc.args[0]} (continuing with the other builds)")
+                failures[task.base_path] = exc.args[0]
+                return
+            if task.base_path not in self._resolved_commits and task.base_path not in self._archive_hashes:
+                # the build output is part of the worktree hash -> the next run has to compare against the post-build state
+                self._build_stamps[task.base_path] = self._build_stamp(task)
+            if artifact_key is not None:
+                save_build_artifact(task.base_path, artifact_key)
+            self._record_build_progress(task)
+
+    def _artifact_key(self, task: BuildTask) -> Optional[str]:
+        # only git packages have a reliable identity (local directories change, archives might get rebuilt)
+        if not self._artifact_cache or (commit := self._resolved_commits.get(task.base_path)) is None:
+            return None
+        if self._artifact_toolchain is None:
+            self._artifact_toolchain = json.dumps([get_installed_nu_version(), command_output(["cargo", "--version"], stderr=subprocess.DEVNULL) if which("cargo") else None])
+        return hashlib.sha256(f"{commit}\n{task.command}\n{self._artifact_toolchain}{self._target_suffix()}{self._env_suffix(task)}".encode(encoding="utf-8")).hexdigest()
+
+    def _record_build_progress(self, task: BuildTask) -> None:
+        # saved after every build -> a rerun after a failure or ctrl-c doesn't have to redo it
+        if self._nupm_home is None:
+            return
+        with self._build_progress_lock:
+            self._build_progress[task.base_path] = self._build_stamps[task.base_path]
+            save_build_progress(self._nupm_home, self._build_progress)
+
+    def _build_stamp(self, task: BuildTask) -> str:
+        # git packages are identified by their commit, archives by their hash, and local directories by a hash of their content
+        content_id: str = (
+            self._resolved_commits.get(task.base_path)
+            or self._archive_hashes.get(task.base_path)
+            or directory_sha256(task.base_path)
+        )
+        return hashlib.sha256(f"{content_id}\n{task.command}{self._target_suffix()}{self._env_suffix(task)}".encode(encoding="utf-8")).hexdigest()
+
+    def _target_suffix(self) -> str:
+        # empty without a target -> the stamps of native builds stay the same
+        return "" if self._target is None else f"\n{self._target}"
+
+    def _env_suffix(self, task: BuildTask) -> str:
+        return "" if not task.env else "\n" + json.dumps(task.env, sort_keys=True)
+
+    def _build_env(self, task: BuildTask) -> Dict[str, str]:
+        # lets build scripts find their dependencies, etc (`$env.NUMNG_DEPENDENCIES | from json | get some/lib`)
+        env: Dict[str, str] = {
+            **environ,
+            "NUMNG_PACKAGE_NAME": task.package.name,
+            "NUMNG_PACKAGE_DIR": task.base_path,
+            "NUMNG_DEPENDENCIES": json.dumps({
+                package.name: base_path for package, base_path in self._loaded_packages if base_path in task.depends
+            }),
+            "NUMNG_TARGET_DIR": path.join(task.base_path, "target", *([self._target] if self._target is not None else [])),
+            "NUMNG_OFFLINE": "true" if self._offline else "false",
+        }
+        if self._nupm_home is not None:
+            env["NUMNG_NUPM_HOME"] = path.abspath(self._nupm_home)
+        if self._offline:
+            env["CARGO_NET_OFFLINE"] = "true"
+        if self._target is not None:
+            # cargo picks up CARGO_BUILD_TARGET itself (same as `cargo build --target ...`), other build tools can use NUMNG_TARGET
+            env["CARGO_BUILD_TARGET"] = self._target
+            env["NUMNG_TARGET"] = self._target
+        env.update(task.env)
+        return env
+
+    def _target_path(self, rel_path: str) -> str:
+        # cargo puts cross-compiled binaries into target/<triple>/release instead of target/release
+        parts: List[str] = rel_path.split("/")
+        if self._target is None or len(parts) < 3 or parts[0] != "target" or parts[1] not in ("release", "debug"):
+            return rel_path
+        return "/".join(["target", self._target, *parts[1:]])
+
+    def _changed_base_paths(self) -> List[str]:
+        # packages without a commit or archive hash (local directories) can't be compared -> always count as changed
+        if self._previous_state is None:
+            return [base_path for _, base_path in self._loaded_packages]
+        installed: List[Tuple[str, Optional[str], Optional[str]]] = [
+            (i.get("path"), i.get("commit"), i.get("sha256"))
+            for i in self._previous_state.get("packages") or []
+            if i.get("commit") is not None or i.get("sha256") is not None
+        ]
+        return [
+            base_path for _, base_path in self._loaded_packages
+            if (base_path, self._resolved_commits.get(base_path), self._archive_hashes.get(base_path)) not in installed
+        ]
+
+    def _run_build_command(self, task: BuildTask) -> None:
+        package, base_path, build_command = task.package, task.base_path, task.command
+        logger.debug(f"Building {package.name}: {build_command}")
+        build_start_time: float = time()
+        self._emit(BuildEventKind.BUILD_STARTED, package.name, build_command)
+        build_proc = run_command(
+            self._sandbox_build(package, [self._context.config.nu_binary, "--no-config-file", "-c", build_command], [base_path]),
+            cwd=base_path, stdout=subprocess.DEVNULL, env=self._build_env(task),
+        )
+        assert build_proc.returncode == 0, f"build_command for {package.name} failed"
+        self._build_durations[base_path] = time() - build_start_time
+        self._emit(BuildEventKind.BUILD_FINISHED, package.name)
+
+    def _run_nupm_custom_build(self, task: BuildTask) -> None:
+        package, base_path, build_script_path = task.package, task.base_path, task.command
+        # im seriosly questioning my sanity here, but as far as i can see nupm runs the build script in a empty temporary directory and deletes the tmpdir
+        # afterwards without using the tmpdir or giving the buildscript paths, etc
+        with TemporaryDirectory() as tmpdir:
+            logger.debug(f"Building {package.name} (nupm-custom)")
+            build_start_time: float = time()
+            self._emit(BuildEventKind.BUILD_STARTED, package.name, build_script_path)
+            build_proc = run_command(self._sandbox_build(package, [self._context.config.nu_binary, "--no-config", build_script_path], [tmpdir]), cwd=tmpdir, stdout=subprocess.DEVNULL, env=self._build_env(task), context=self._context)
+            assert build_proc.returncode == 0, f"nupm-custom build for {package.name} failed"
+            self._build_durations[base_path] = time() - build_start_time
+            self._emit(BuildEventKind.BUILD_FINISHED, package.name)
+
+    def _sandbox_build(self, package: Package, command: List[str], writable_dirs: List[str]) -> List[str]:
+        if isinstance(self._build_sandbox, dict):
+            use_sandbox: bool = self._build_sandbox.get(package.name, self._build_sandbox.get("_", False))
+        else:
+            use_sandbox = self._build_sandbox
+        if not use_sandbox:
+            return command
+        assert platform == "linux", f"Sandboxed builds are only supported on linux (needed for {package.name})"
+        assert which("bwrap") is not None, f"Sandboxed builds require bubblewrap (`bwrap`) to be installed (needed for {package.name})"
+        logger.debug(f"sandboxing the build of {package.name} (writable: {', '.join(writable_dirs)})")
+        return [
+            "bwrap", "--ro-bind", "/", "/", "--dev", "/dev", "--proc", "/proc", "--tmpfs", "/tmp",
+            *(i for writable_dir in writable_dirs for i in ("--bind", writable_dir, writable_dir)),
+            "--unshare-net", "--die-with-parent", "--",
+            *command,
+        ]
+
+    def _registry_get_by_name(self, name: str, version: Optional[str] = None, version_scheme: Optional[str] = None) -> Optional[Package]:
+        return self._registry.get_by_name(name, version=version, version_scheme=version_scheme)
+
+    def _did_you_mean(self, name: str) -> str:
+        # similar package names of the registries (search index or file names -> no package file gets opened)
+        if not self._registries:
+            return ""
+        suggestions: List[str] = difflib.get_close_matches(name, [i["name"] for i in self._registry.search_index()], n=3)
+        return f" (did you mean {' or '.join(suggestions)}?)" if suggestions else ""
+
+    def _registry_public_key(self, package: Package) -> Optional[str]:
+        if (key_name := (package.extra_data or {}).get("trusted_key")) is None:
+            return None
+        assert key_name in self._trusted_keys, f"Registry {package.source_uri} uses the trusted_key {key_name}, which is not in trusted_keys"
+        return self._trusted_keys[key_name]
+
+    def _load_raw_file_registry(self, package: Package) -> bool:
+        # reading a few files of a new registry is faster than cloning it (falls back to git for everything else)
+        # locked builds clone it (`numng bundle export`, etc need it in the store)
+        ref: str = package.source_git_ref or "main"
+        if (
+            package.package_format != "numng" or package.source_type not in ("git", None) or package.source_uri is None
+            or self._offline or self._locked or self._vendored or apply_mirrors(package.source_uri, self._context) != package.source_uri
+            or raw_file_url(package.source_uri, ref, "") is None
+            or path.exists(get_git_ref_path(package.source_uri, ref))
+        ):
+            return False
+        # like a clone: only moves to a newer commit when pulling updates
+        commits_file: str = data_path(RAW_FILE_CACHE_DIR_NAME, "commits.json")  # (also used by load_stored_registries)
+        commits: Dict[str, str] = load_json_state(commits_file, {})
+        commit: Optional[str] = commits.get(f"{package.source_uri} {ref}") if not self._pull_updates else None
+        if commit is None:
+            commit = ref if re.fullmatch(r"[0-9a-f]{40}", ref) else get_remote_git_commit(package.source_uri, ref)
+            if commit is None:
+                return False
+            commits[f"{package.source_uri} {ref}"] = commit
+            save_json_state(commits_file, commits, indent=4)
+        self._lock_data.setdefault("git", {}).setdefault(package.source_uri, {})[ref] = commit
+        logger.debug(f"loading registry {package.source_uri} {ref} ({commit}) without cloning it")
+        public_key: Optional[str] = self._registry_public_key(package)
+        locked_commit: str = commit
+
+        def clone() -> str:
+            # the same commit the raw files would have come from (lockfile and resolution cache stay valid)
+            assert package.source_uri is not None
+            ref_path, _ = self._fetch_git_ref(package.source_uri, ref)
+            git_checkout_commit(package.source_uri, ref_path, locked_commit)
+            self._lock_data["git"][package.source_uri][ref] = locked_commit
+            self._store_paths.append(ref_path)
+            return path.join(ref_path, package.source_path_offset) if package.source_path_offset else ref_path
+        registry: PackageRegistry = RawFileNumngPackageRegistry(package.source_uri, commit, package.source_path_offset, public_key=public_key, fallback=clone)
+        # same cache as a clone of the registry
+        registry.cache_id = json.dumps([package.package_format, package.source_uri, package.source_path_offset, public_key])
+        registry.cache_revision = commit
+        registry.name = package.name if package.name != "NO_NAME_PACKAGE" else None
+        registry.source = package.source_uri
+        self._registries.append(registry)
+        return True
+
+    def _load_registry(self, package: Package, base_path: str) -> None:
+        logger.debug(f"loading registry from {base_path}")
+        public_key: Optional[str] = self._registry_public_key(package)
+        registry: PackageRegistry
+        if package.package_format == "nupm":
+            assert public_key is None, "Signed nupm registries are not supported"
+            assert path.exists(path.join(base_path, "registry", "registry.nuon")), "Failed to load nupm registry (registry/registry.nuon not found)"
+            registry = NupmPackageRegistry(path.join(base_path, "registry"))
+        elif package.package_format == "numng":
+            registry = NumngPackageRegistry(base_path, public_key=public_key)
+        else:
+            raise AssertionError("Failed to load registry (unknown or unsupported package_format)")
+        if (revision := self._resolved_commits.get(base_path) or self._archive_hashes.get(base_path)) is not None:
+            # the trusted_key is part of the id, since it decides which package files are valid
+            registry.cache_id = json.dumps([package.package_format, package.source_uri, package.source_path_offset, public_key])
+            registry.cache_revision = revision
+        registry.name = package.name if package.name != "NO_NAME_PACKAGE" else None
+        registry.source = package.source_uri
+        self._registries.append(registry)
+
+    def _fetch_git_ref(self, url: str, ref: str) -> Tuple[str, str]:
+        # multiple packages can share a source -> only fetch (and update) each repo/ref once per run
+        cache_key: str = f"{normalize_git_url(url)} {ref}"
+        with get_named_lock(f"git_fetch_cache {cache_key}"):
+            if cache_key not in self._git_fetch_cache and (vendored := self._vendored.get(f"git {cache_key}")) is not None:
+                logger.debug(f"using the vendored copy of {url} {ref}")
+                self._git_fetch_cache[cache_key] = (vendored["path"], self._lock_vendored_git_ref(url, ref, vendored["commit"]))
+            elif cache_key not in self._git_fetch_cache:
+                ref_path: str = get_git_ref_path(url, ref, download=True, update=self._pull_updates, offline=self._offline)
+                self._git_fetch_cache[cache_key] = (ref_path, self._lock_git_ref(url, ref, ref_path))
+                self.report.sources[f"git {cache_key}"] = {"type": "git", "url": url, "ref": ref, "commit": self._git_fetch_cache[cache_key][1], "path": ref_path}
+            else:
+                logger.debug(f"already fetched {url} {ref} in this run")
+            return self._git_fetch_cache[cache_key]
+
+    def _find_git_tag(self, url: str, version: str, version_scheme: str = "semver") -> Optional[str]:
+        # packages without git_ref (and registry entry) get the newest tag matching their version
+        normalized_url: str = normalize_git_url(url)
+        with get_named_lock(f"git_tag_cache {normalized_url}"):
+            if normalized_url not in self._git_tag_cache:
+                vendored_refs: List[str] = [key.split(" ", 2)[2] for key in self._vendored if key.startswith(f"git {normalized_url} ")]
+                if self._locked:
+                    self._git_tag_cache[normalized_url] = list(self._lock_data.get("git", {}).get(url, {}).keys())
+                elif vendored_refs:
+                    self._git_tag_cache[normalized_url] = vendored_refs
+                else:
+                    self._git_tag_cache[normalized_url] = list_git_tags(url, offline=self._offline)
+        tag: Optional[str] = latest_matching_git_tag(self._git_tag_cache[normalized_url], version, version_scheme)
+        logger.debug(f"using tag {tag} of {url} for version {version}" if tag else f"no tag of {url} matches version {version}")
+        return tag
+
+    def _lock_git_ref(self, url: str, ref: str, ref_path: str) -> str:
+        commit: str = get_git_commit(ref_path)
+        locked_commits: Dict[str, str] = self._lock_data.setdefault("git", {}).setdefault(url, {})
+        if not self._locked:
+            locked_commits[ref] = commit
+            return commit
+        if ref not in locked_commits:
+            raise LockfileError(f"{url} {ref} is not in the lockfile (build without --locked to update the lockfile)")
+        if commit != locked_commits[ref] and self._dry_run:
+            logger.debug(f"dry run: not checking out the locked commit {locked_commits[ref]} of {url} {ref}")
+        elif commit != locked_commits[ref]:
+            self._diagnose(Diagnostic(severity="note", message=f"{url} {ref} is at {commit} instead of the locked {locked_commits[ref]} -> checking out the locked commit"))
+            git_checkout_commit(url, ref_path, locked_commits[ref])
+        return locked_commits[ref]
+
+    def _lock_vendored_git_ref(self, url: str, ref: str, commit: str) -> str:
+        locked_commits: Dict[str, str] = self._lock_data.setdefault("git", {}).setdefault(url, {})
+        if not self._locked:
+            locked_commits[ref] = commit
+        if locked_commits.get(ref) != commit:
+            raise LockfileError(f"The vendored copy of {url} {ref} does not match the lockfile (run `numng vendor` again)")
+        return commit
+
+    def _lock_archive(self, url: str, sha256: str) -> None:
+        locked_hashes: Dict[str, str] = self._lock_data.setdefault("archive", {})
+        if not self._locked:
+            locked_hashes[url] = sha256
+            return
+        if url not in locked_hashes:
+            raise LockfileError(f"{url} is not in the lockfile (build without --locked to update the lockfile)")
+        if locked_hashes[url] != sha256:
+            raise LockfileError(f"sha256 of {url} does not match the lockfile ({sha256} instead of {locked_hashes[url]})")
+
+    def _module_name(self, package: Package, name: str, count: int) -> str:
+        # `as` of the dependency: renames its module (or prefixes its modules, if there are multiple), so forks can coexist
+        alias: Optional[str] = (package.extra_data or {}).get("as")
+        if alias is None:
+            return name
+        return alias if count == 1 else f"{alias}-{name}"
+
+    def _aliased_module_path(self, package: Package, module_path: str, count: int) -> str:
+        # nu names modules after their file or directory -> renamed modules get used through a link in NUPM_HOME/modules
+        if (package.extra_data or {}).get("as") is None:
+            return module_path
+        assert self._nupm_home is not None, f"{package.name} uses `as`, which requires a nupm_home (the renamed modules get linked into it)"
+        stem: str = path.basename(module_path)[:-len(".nu")] if module_path.endswith(".nu") else path.basename(module_path)
+        name: str = filesystem_safe(self._module_name(package, stem, count)) + (".nu" if module_path.endswith(".nu") else "")
+        dst: str = path.join(path.abspath(self._nupm_home), "modules", name)
+        self._nupm_home_symlink_todo.append((module_path, dst,))
+        return dst
+
+    def _register_nupm_module(self, module_name: str, module_source_path: str) -> None:
+        if self._nupm_home is None:
+            return
+        dst: str = path.abspath(path.join(self._nupm_home, "modules", filesystem_safe(module_name)))
+        assert dst.startswith(path.join(self._nupm_home, "modules"))
+        self._nupm_home_symlink_todo.append((module_source_path, dst,))
+
+    def _register_nupm_binary(self, binary_name: str, binary_source_path: str) -> None:
         if self._nupm_home is None:
             return
-        dst: str = path.abspath(path.join(self._nupm_home, "bin", filesystem_safe(binary_name)))
-        assert dst.startswith(path.join(self._nupm_home, "bin"))
-        chmod(binary_source_path, os_stat(binary_source_path).st_mode | stat.S_IEXEC)
+        dst: str = path.abspath(path.join(self._nupm_home, self._nupm_home_bin_dir, filesystem_safe(binary_name)))
+        assert dst.startswith(path.join(self._nupm_home, self._nupm_home_bin_dir))
+        self._executable_todo.append(binary_source_path)  # it might not be built yet
         self._nupm_home_symlink_todo.append((binary_source_path, dst,))
 
+    def _register_nupm_plugin(self, plugin_name: str, plugin_source_path: str) -> None:
+        # collected in one directory for NU_PLUGIN_DIRS (`plugin add NAME`)
+        if self._nupm_home is None:
+            return
+        dst: str = path.abspath(path.join(self._nupm_home, "plugins", filesystem_safe(plugin_name)))
+        assert dst.startswith(path.join(self._nupm_home, "plugins"))
+        self._nupm_home_symlink_todo.append((plugin_source_path, dst,))
+
     def _register_nupm_overlay(self, overlay_name: str, overlay_source_path: str) -> None:
         if self._nupm_home is None:
             return
@@ -371,44 +2243,110 @@ class Loader:
         assert dst.startswith(path.join(self._nupm_home, "overlays"))
         self._nupm_home_symlink_todo.append((overlay_source_path, dst,))
 
+    def _queue_dependencies(self, base_path: str, dependencies: List[Package]) -> List[Tuple[Package, str]]:
+        downloaded: List[Tuple[Package, str]] = self._download_packages(dependencies)
+        for i in downloaded:
+            self._load_q.put(i)
+        dependency_ids: List[str] = self._dependency_ids.setdefault(base_path, [])
+        # the base package gets its depends from both the package and its numng.json
+        dependency_ids.extend(i for i in dict.fromkeys(dependency_base_path for _, dependency_base_path in downloaded) if i not in dependency_ids)
+        return downloaded
+
     def _download_packages(self, packages: List[Package]) -> List[Tuple[Package, str]]:
-        return [(package, self._download_package(package)) for package in packages]
+        if self._jobs <= 1 or len(packages) <= 1:
+            return [(package, self._download_package(package)) for package in packages]
+        with ThreadPoolExecutor(max_workers=self._jobs) as executor:
+            try:
+                return list(zip(packages, executor.map(self._in_context(self._download_package), packages)))
+            except KeyboardInterrupt:
+                self._cancellation.cancel()
+                raise
 
     def _download_package(self, package: Package) -> str:
-        if (
-            self._registries
-            and (not ((package.extra_data or {}).get("ignore_registry") == True))
-            # and (package.source_type is None or package.source_uri is None)
-            and (regpkg := self._registry_get_by_name(package.name, version=(package.extra_data or {}).get("version"))) is not None
-        ):
-            package.include_data(regpkg)
-        assert package.source_uri is not None, f"Failed to download {package.name} (unknown source_uri)"
-        base_path: Optional[str] = None
-        if package.source_type in ("git", None):
-            assert package.source_uri is not None, f"Failed to generate loader for {package.name} (missing uri)"
-            base_path = get_git_ref_path(package.source_uri, package.source_git_ref, download=True, update=self._pull_updates)
-            base_path = path.join(base_path, package.source_path_offset) if package.source_path_offset else base_path
-        else:
-            raise AssertionError(f"Failed to download {package.name} (unknown or unsupported source-type)")
-        return base_path
+        self._cancellation.check()
+        fetch_start_time: float = time()
+        with log_span(package=package.name):
+            if (
+                self._registries
+                and (ignore_registry := (package.extra_data or {}).get("ignore_registry")) != True
+                # and (package.source_type is None or package.source_uri is None)
+                and (regpkg := self._registry_get_by_name(package.name, version=(package.extra_data or {}).get("version"), version_scheme=(package.extra_data or {}).get("version_scheme"))) is not None
+            ):
+                package.include_data(without_fields(regpkg, ignore_registry) if isinstance(ignore_registry, list) else regpkg)
+            if package.source_uri is None and (versions := self._registry.list_versions(package.name)):
+                raise AssertionError(f"Failed to download {package.name} (no version matches {(package.extra_data or {}).get('version')}, available: {', '.join(versions)})")
+            assert package.source_uri is not None, f"Failed to download {package.name} (unknown source_uri)" + self._did_you_mean(package.name)
+            self._emit(BuildEventKind.FETCH_STARTED, package.name, package.source_uri)
+            base_path: Optional[str] = None
+            if package.source_type in ("git", None):
+                assert package.source_uri is not None, f"Failed to generate loader for {package.name} (missing uri)"
+                version: Optional[str] = (package.extra_data or {}).get("version")
+                version_scheme: str = (package.extra_data or {}).get("version_scheme") or "semver"
+                assert version_scheme in VERSION_SCHEMES, f"Invalid package {package.name} (unknown version_scheme {version_scheme})"
+                if version_scheme == "commit-date":
+                    pass  # the version is only known after the download
+                elif package.source_git_ref is not None or package.resolved_version is not None:
+                    pass  # explicit git_ref or registry entry (registries without git_ref mean the branch)
+                elif version not in (None, "latest"):
+                    package.source_git_ref = self._find_git_tag(package.source_uri, version, version_scheme)
+                    assert package.source_git_ref is not None, f"No tag of {package.source_uri} matches version {version}" + (" (build without --locked to update the lockfile)" if self._locked else "")
+                elif self._latest_git_ref == "tag" or (version == "latest" and self._latest_git_ref is None):
+                    # the newest release is more likely to work than the current branch head (branch if there are no tags)
+                    package.source_git_ref = self._find_git_tag(package.source_uri, "latest", version_scheme)
+                if package.resolved_version is None and package.source_git_ref is not None and version_scheme != "commit-date":
+                    package.resolved_version = git_tag_version(package.source_git_ref, version_scheme)
+                base_path, commit = self._fetch_git_ref(package.source_uri, package.source_git_ref or "main")
+                if version_scheme == "commit-date":
+                    package.resolved_version = get_git_commit_date(package.source_uri, commit)
+                    assert version is None or VersionReq(version, version_scheme).matches(CalVer(package.resolved_version)), (
+                        f"{package.name} {package.source_git_ref or 'main'} is from {package.resolved_version}, which does not match version {version}"
+                    )
+                base_path = path.join(base_path, package.source_path_offset) if package.source_path_offset else base_path
+                self._resolved_commits[base_path] = commit
+            elif package.source_type == "archive":
+                expected_sha256: Optional[str] = (package.extra_data or {}).get("sha256") or (
+                    self._lock_data.get("archive", {}).get(package.source_uri) if self._locked else None
+                )
+                if expected_sha256 is None:
+                    self._warn(f'{package.name} does not specify a sha256 for its archive (add `"sha256": "HASH"` to its definition)', package.name)
+                if (vendored := self._vendored.get(f"archive {package.source_uri}")) is not None:
+                    logger.debug(f"using the vendored copy of {package.source_uri}")
+                    base_path, observed_sha256 = vendored["path"], vendored["sha256"]
+                    assert expected_sha256 is None or observed_sha256 == expected_sha256.lower(), f"sha256 mismatch for the vendored copy of {package.source_uri} (run `numng vendor` again)"
+                else:
+                    with get_named_lock(package.source_uri), log_span(archive=package.source_uri):
+                        base_path, observed_sha256 = get_archive_path(package.source_uri, expected_sha256, update=self._pull_updates, offline=self._offline)
+                    self.report.sources[f"archive {package.source_uri}"] = {"type": "archive", "url": package.source_uri, "sha256": observed_sha256, "path": base_path}
+                self._lock_archive(package.source_uri, observed_sha256)
+                base_path = path.join(base_path, package.source_path_offset) if package.source_path_offset else base_path
+                self._archive_hashes[base_path] = observed_sha256
+            else:
+                raise AssertionError(f"Failed to download {package.name} (unknown or unsupported source-type)")
+            self._store_paths.append(base_path)
+            self._fetch_durations[base_path] = time() - fetch_start_time
+            self._emit(BuildEventKind.FETCH_FINISHED, package.name, base_path)
+            return base_path
 
     def _load_package(self, package: Package, base_path: str) -> None:
-        for i in self._download_packages(package.depends or []):
-            self._load_q.put(i)
-        if package.package_format == "numng" or (package.package_format == None and path.isfile(path.join(base_path, "numng.json"))):
-            fp: str = path.join(base_path, "numng.json")
-            logger.info(f"Loading numng package {package.name}")
-            self._load_numng(package, fp if path.isfile(fp) else None, base_path)
-            return
-        if package.package_format in ("nupm", None) and path.isfile(fp := path.join(base_path, "nupm.nuon")):
-            logger.info(f"Loading nupm package {package.name}")
-            self._load_nupm(package, fp, base_path)
-            return
-        if package.package_format in ("packer", "packer.nu") and path.isfile(fp := path.join(base_path, "meta.nuon")):
-            logger.info(f"Loading packer.nu package {package.name}")
-            self._load_packer_meta(package, fp, base_path)
-            return
-        logger.info(f"No specific load action for {package.name} ({package.package_format}) found.")
+        with log_span(package=package.name):
+            # includes the depends of the registry entry (applied in _download_package), which get expanded the same way once downloaded
+            # (already loaded base_paths get skipped as duplicates -> cycles within registries end)
+            self._queue_dependencies(base_path, package.depends or [])
+            if package.package_format == "numng" or (package.package_format == None and find_package_file(base_path) is not None):
+                logger.info(f"Loading numng package {package.name}")
+                self._load_numng(package, find_package_file(base_path), base_path)
+                return
+            if package.package_format in ("nupm", None) and path.isfile(fp := path.join(base_path, "nupm.nuon")):
+                logger.info(f"Loading nupm package {package.name}")
+                self._load_nupm(package, fp, base_path)
+                return
+            if package.package_format in ("packer", "packer.nu") and path.isfile(fp := path.join(base_path, "meta.nuon")):
+                if package.package_format == "packer.nu":
+                    self._diagnose(Diagnostic(severity="deprecation", message=f'{package.name}: package_format "packer.nu" (use "packer")', package=package.name))
+                logger.info(f"Loading packer.nu package {package.name}")
+                self._load_packer_meta(package, fp, base_path)
+                return
+            logger.info(f"No specific load action for {package.name} ({package.package_format}) found.")
 
     def _load_packer_meta(self, package: Package, meta_nuon_path: str, base_path: str) -> None:
         with open(meta_nuon_path, "r") as fp:
@@ -422,7 +2360,7 @@ class Loader:
             self._loader_script_snippets_use.append(LoaderScriptSnippet(
                 name=package.name,
                 depends=[i.name for i in package.depends] if package.depends else [],
-                snippet=f"export use {pm_mod_path}"
+                snippet=f"export use {self._aliased_module_path(package, pm_mod_path, len(meta_nuon['prefixed_modules']))}"
             ))
         for module in (meta_nuon.get("modules") or []):
             mod_path: str = path.join(base_path, *module.split("/"))
@@ -453,21 +2391,44 @@ class Loader:
 
     def _load_numng(self, package: Package, numng_json_path: Optional[str], base_path: str) -> None:
         if numng_json_path is not None:
-            with open(numng_json_path, "r") as fp:
-                numng_json: Dict[str, Any] = json.load(fp)
-            assert isinstance(numng_json, dict), f"Invalid numng.json in {package.name} (not a dict)"
-            for dependency in _listify(numng_json.get("depends")):
-                dep_pkg: Optional[Package] = load_package_from_json(dependency)
-                assert dep_pkg is not None, f"Package from numng.json in {package.name} not found ({dependency.get('name')})"
-                self._load_q.put((dep_pkg, self._download_package(dep_pkg)))
+            numng_json, diagnostics = read_package_file(numng_json_path)
+            for diagnostic in diagnostics:
+                assert not self._strict, f"Invalid numng.json in {package.name}: {diagnostic.message} (at {diagnostic.pointer}) ({numng_json_path})"
+                diagnostic.message = f"{package.name}: {diagnostic.message}"
+                diagnostic.package = package.name
+                self._diagnose(diagnostic)
+            dependencies: List[Any] = [i for _, i in _dependency_items(numng_json.get("depends"))]
+            if self._dev and numng_json_path == self._numng_file_path:
+                dependencies.extend(i for _, i in _dependency_items(numng_json.get("dev_depends")))
+            self._queue_dependencies(base_path, [load_package_from_json(dependency) for dependency in dependencies])
         else:
             logger.debug("_load_numng: falling back to package.extra_data (numng_json_path is None)")
-            numng_json = package.extra_data or {}
-        if "build_command" in numng_json:
-            assert self._allow_build_commands, f'package {package.name} contains a build_comamnd. to use this package you will have to allow those by adding `"allow_build_commands": true` to your config'
-            logger.debug(f"Building {package.name}: {numng_json['build_command']}")
-            build_proc = subprocess.run(["nu", "--no-config-file", "-c", numng_json['build_command']], cwd=base_path, stdout=subprocess.DEVNULL)
-            assert build_proc.returncode == 0, f"build_command for {package.name} failed"
+            numng_json: Dict[str, Any] = package.extra_data or {}
+        self._check_nu_version(package, [*_listify(numng_json.get("nu_version")), *_listify((package.extra_data or {}).get("nu_version"))])
+        if "post_install_message" in numng_json:
+            assert isinstance(message := numng_json["post_install_message"], str), f"Invalid numng.json in {package.name} (post_install_message is not a string)"
+            version_id: str = self._resolved_commits.get(base_path) or self._archive_hashes.get(base_path) or ""
+            self._post_install_messages[package.name] = (message, hashlib.sha256(f"{version_id}\n{message}".encode(encoding="utf-8")).hexdigest())
+        try:
+            build_command: Optional[str] = get_build_command(numng_json, self._context.config.nu_binary)
+        except AssertionError as exc:
+            raise AssertionError(f"Invalid numng.json in {package.name} ({exc.args[0]})")
+        if build_command is not None:
+            self._build_paths.append(base_path)
+        if build_command is not None and self._dry_run:
+            logger.debug(f"dry run: skipping build_command of {package.name}")
+        elif build_command is not None:
+            self._assert_build_allowed(package, build_command)
+            build_env: Any = numng_json.get("build_env", {})
+            assert isinstance(build_env, dict) and all(isinstance(i, str) for i in build_env.values()), f"Invalid numng.json in {package.name} (build_env is not a record of strings)"
+            self._build_tasks.append(BuildTask(
+                package=package,
+                base_path=base_path,
+                depends=list(self._dependency_ids.get(base_path, [])),
+                command=build_command,
+                run=self._run_build_command,
+                env=build_env,
+            ))
         if "linkin" in numng_json:
             assert isinstance(numng_json["linkin"], dict), f"Invalid numng.json in {package.name} (linkin not a dict)"
             for linkin_path, linkin_json in numng_json["linkin"].items():
@@ -482,24 +2443,28 @@ class Loader:
                 if repo_path is not None:
                     assert (tmp := path.abspath(path.join(linkin_base_path, repo_path))).startswith(linkin_base_path), "Security issue: linkin package-rel-path is outside of package"
                     linkin_base_path = tmp
+                if self._dry_run:
+                    continue
                 if not path.exists(linkin_pardir := path.abspath(path.join(linkin_path, path.pardir))):
                     makedirs(linkin_pardir)
                 if path.exists(linkin_path):
-                    assert path.islink(linkin_path), f"Failed linkin at {linkin_path}: path exists and is not a symlink"
+                    assert is_link(linkin_path), f"Failed linkin at {linkin_path}: path exists and is not a symlink"
                     if path.realpath(linkin_path) == linkin_base_path:
                         continue
-                    unlink(linkin_path)
+                    remove_link(linkin_path)
                 self._nupm_home_symlink_todo.append((linkin_base_path, linkin_path,))
         for plugin in _listify(numng_json.get("nu_plugins")):
-            plugin_path: str = path.abspath(path.join(base_path, plugin))
+            plugin_path: str = path.abspath(path.join(base_path, self._target_path(plugin)))
             assert plugin_path.startswith(base_path), f"Security error: {package.name} tried to register a plugin outside of its directory"
             self._nu_plugin_paths.append(plugin_path)
+            self._register_nupm_plugin(path.basename(plugin_path), plugin_path)
         if "nu_libs" in numng_json:
             assert isinstance(numng_json["nu_libs"], dict), f"Invalid numng.json in {package.name} (nu_libs is not a dict)"
             for name, rel_path in numng_json["nu_libs"].items():
                 abs_path: str = path.abspath(path.join(base_path, rel_path))
                 assert abs_path.startswith(base_path), f"Security error: {package.name} tried to register a lib outside of its directory"
-                logging.debug(f"Registered module {name} for {package.name}")
+                name = self._module_name(package, name, len(numng_json["nu_libs"]))
+                logger.debug(f"Registered module {name} for {package.name}")
                 self._register_nupm_module(module_name=name, module_source_path=abs_path)
         if (sc := numng_json.get("shell_config")) is not None:
             assert isinstance(numng_json["shell_config"], dict), f"Invalid numng.json in {package.name} (shell_config not a dict)"
@@ -510,7 +2475,8 @@ class Loader:
                 self._loader_script_snippets_script.append(LoaderScriptSnippet(name=package.name, depends=deps, snippet=f"source {json.dumps(src_file)}"))
             for use_file in _listify(sc.get("use")):
                 logger.debug(f"use file found: {use_file}")
-                assert (src_file := path.abspath(path.join(base_path, src_file))).startswith(base_path), f"Security error: {package.name} tried to register a shell_config outside of its directory"
+                assert (use_file := path.abspath(path.join(base_path, use_file))).startswith(base_path), f"Security error: {package.name} tried to register a shell_config outside of its directory"
+                use_file = self._aliased_module_path(package, use_file, len(_listify(sc.get("use"))))
                 self._loader_script_snippets_use.append(LoaderScriptSnippet(name=package.name, depends=deps, snippet=f"export use {json.dumps(use_file)}"))
             for use_file in _listify(sc.get("use_all")):
                 logger.debug(f"use_all file found: {use_file}")
@@ -536,22 +2502,22 @@ class Loader:
         del nupm_nuon_path, nupm_nuon_str
         assert isinstance(nupm_nuon, dict), f"invalid nupm.nuon in {package.name} (not a record)"
         assert "type" in nupm_nuon, f"invalid nupm.nuon in {package.name} (missing type)"
+        build_script_path: Optional[str] = None
         if nupm_nuon["type"] == "module":
             assert "name" in nupm_nuon, f"invalid nupm.nuon in {package.name} (missing name)"
             assert path.exists(mod_dir_path := path.join(base_path, nupm_nuon["name"])), f"module-nupm-package {package.name} does not contain a module dir"
-            self._register_nupm_module(nupm_nuon["name"], mod_dir_path)
+            self._register_nupm_module(self._module_name(package, nupm_nuon["name"], 1), mod_dir_path)
         elif nupm_nuon["type"] == "script":
             if path.exists(script_path := path.join(base_path, script_name := f"{package.name}.nu")):
                 self._register_nupm_binary(script_name, script_path)
+        elif nupm_nuon["type"] == "custom" and self._dry_run:
+            logger.debug(f"dry run: skipping nupm-custom build of {package.name}")
+            self._build_paths.append(base_path)
         elif nupm_nuon["type"] == "custom":
-            assert self._allow_build_commands is True, f"Cannot load nupm custom-type package {package.name} (allow_build_commands is false)"
             assert path.exists(build_script_path := path.join(base_path, "build.nu")), f"Invalid nupm custom-type package {package.name} (missing build.nu)"
-            # im seriosly questioning my sanity here, but as far as i can see nupm runs the build script in a empty temporary directory and deletes the tmpdir
-            # afterwards without using the tmpdir or giving the buildscript paths, etc
-            with TemporaryDirectory() as tmpdir:
-                logger.debug(f"Building {package.name} (nupm-custom)")
-                build_proc = subprocess.run(["nu", "--no-config", build_script_path], cwd=tmpdir, stdout=subprocess.DEVNULL)
-                assert build_proc.returncode == 0, f"nupm-custom build for {package.name} failed"
+            with open(build_script_path, "r") as fp:
+                self._assert_build_allowed(package, fp.read())
+            self._build_paths.append(base_path)
         else:
             raise AssertionError(f"Failed to load nupm-package {package.name} (unknown package type: {nupm_nuon['type']})")
         if "scripts" in nupm_nuon:
@@ -560,6 +2526,7 @@ class Loader:
                 abs_path: str = path.abspath(path.join(base_path, script_subpath))
                 assert abs_path.startswith(base_path), f"Security issue: {package.name} tried to link {abs_path} as a script"
                 self._register_nupm_binary(path.split(script_subpath)[1], abs_path)
+        dep_pkgs: List[Package] = []
         if "dependencies" in nupm_nuon:
             assert isinstance(nupm_nuon["dependencies"], list), f"Invalid nupm.nuon {package.name} (dependencies not a list)"
             nupm_deps: Optional[List[Tuple[str, str]]] = None
@@ -573,33 +2540,39 @@ class Loader:
             assert nupm_deps is not None, f"Nupm package {package.name}'s dependency list is neither a list nor a dict"
             for name, version in nupm_deps:
                 if name == "nushell":  # some packages declare version compatability this way. its not a real package
+                    self._check_nu_version(package, _listify(version))
                     continue
                 dep_pkg: Optional[Package] = self._registry_get_by_name(name=name, version=version)
                 assert dep_pkg is not None, f"Failed to load {package.name} (unknown dependency: {name}/{version})"
-                self._load_q.put((dep_pkg, self._download_package(dep_pkg)))
+                dep_pkgs.append(dep_pkg)
+            self._queue_dependencies(base_path, dep_pkgs)
+        if build_script_path is not None:
+            self._build_tasks.append(BuildTask(
+                package=package,
+                base_path=base_path,
+                depends=list(self._dependency_ids.get(base_path, [])),
+                command=build_script_path,
+                run=self._run_nupm_custom_build,
+            ))
 
     def _find_nupm_package(self, name: str, version: Optional[str]) -> Optional[Package]:
-        for registry in self._registries:
-            if isinstance(registry, NupmPackageRegistry):
-                if (p := registry.get_by_name(name=name, version=version)) is not None:
-                    return p
-        return None
+        return CachingPackageRegistry(self._registries.only(NupmPackageRegistry)).get_by_name(name, version=version)
 
     def _generate_nu_plugins(self) -> None:
-        ls_plugins_proc = subprocess.run(
-            ["nu", "--commands", "plugin list | to json"],
+        ls_plugins_proc = run_command(
+            [self._context.config.nu_binary, "--commands", "plugin list | to json"],
             stdout=subprocess.PIPE,
         )
         assert ls_plugins_proc.returncode == 0, "Failed to list currently installed plugins."
         ls_plugins = json.loads(ls_plugins_proc.stdout)
         assert isinstance(ls_plugins, list), "Nushell changed its `plugin list` output format"
         for rm_plugin in (plugin["name"] for plugin in ls_plugins if (
-            plugin["filename"].startswith(BASEDIRECTORY)  # ignore non numng plugins
+            plugin["filename"].startswith(self._context.base_directory)  # ignore non numng plugins
             and plugin["filename"] not in self._nu_plugin_paths
         )):
             logger.debug(f"remove nu plugin: {rm_plugin}")
-            rm_plugin_proc = subprocess.run(
-                ["nu", "--commands", f"plugin rm {json.dumps(rm_plugin)}"],
+            rm_plugin_proc = run_command(
+                [self._context.config.nu_binary, "--commands", f"plugin rm {json.dumps(rm_plugin)}"],
                 stdout=subprocess.DEVNULL,
             )
             assert rm_plugin_proc.returncode == 0, f"Failed to remove plugin {rm_plugin} due to a nushell error (did the commands change?)"
@@ -607,8 +2580,8 @@ class Loader:
             not any(True for i in ls_plugins if i["filepath"] == plugin_path)
         )):
             logger.debug(f"add nu plugin: {add_plugin}")
-            add_plugin_proc = subprocess.run(
-                ["nu", "--commands", f"plugin add {json.dumps(add_plugin)}"],
+            add_plugin_proc = run_command(
+                [self._context.config.nu_binary, "--commands", f"plugin add {json.dumps(add_plugin)}"],
                 stdout=subprocess.DEVNULL,
             )
             assert add_plugin_proc.returncode == 0, f"Failed to add plugin {add_plugin} due to a nushell error (did the commands change?)"
@@ -617,10 +2590,19 @@ class Loader:
 def load_package_from_json(
     json_data: Dict[str, Any],
     allow_no_name: bool = False,
+    pointer: str = "",  # where json_data is within the file (for error messages)
 ) -> Package:
     if isinstance(json_data, str):
         json_data = {"name": json_data}
-    assert allow_no_name or "name" in json_data, f"Unable to load package without name ({json.dumps(json_data)})"
+    if not isinstance(json_data, dict):
+        raise ManifestError(f"package is neither a name nor a record ({json.dumps(json_data)})", pointer)
+    if not allow_no_name and "name" not in json_data:
+        raise ManifestError(f"unable to load package without name ({json.dumps(json_data)})", pointer)
+    for key in ("name", "source_type", "source_uri", "git_ref", "path_offset", "package_format"):
+        if key in json_data and not isinstance(json_data[key], str):
+            raise ManifestError(f"{key} is not a string ({json.dumps(json_data[key])})", pointer + json_pointer(key))
+    if "depends" in json_data and not isinstance(json_data["depends"], (list, dict, str)):
+        raise ManifestError(f"depends is neither a list nor a package ({json.dumps(json_data['depends'])})", pointer + json_pointer("depends"))
     result: Package = Package(
         name=json_data.get("name") or "NO_NAME_PACKAGE",
         source_type=json_data.get("source_type", None),
@@ -628,108 +2610,2133 @@ def load_package_from_json(
         source_git_ref=json_data.get("git_ref", None),
         source_path_offset=json_data.get("path_offset", None),
         depends=([] if "depends" in json_data else None),
-        registries=[load_package_from_json(dep, allow_no_name=True) for dep in _listify(json_data.get("registry"))] or None,
+        registries=[
+            load_package_from_json(dep, allow_no_name=True, pointer=pointer + json_pointer(*(("registry", n) if isinstance(json_data["registry"], list) else ("registry",))))
+            for n, dep in enumerate(_listify(json_data.get("registry")))
+        ] or None,
         package_format=json_data.get("package_format", None),
+        resolved_version=json_data.get("resolved_version", None),
         extra_data=(tmp if (tmp := {k: v for k, v in json_data.items() if k not in (
             "name", "source_type", "source_uri", "git_ref", "path_offset", "depends", "registry",
-            "package_format",
+            "package_format", "resolved_version",
         )}) != {} else None),
     )
-    for dependency in _listify(json_data.get("depends")):
+    for n, dependency in _dependency_items(json_data.get("depends")):
         assert isinstance(result.depends, list)  # linter-fix (its impossible)
-        result.depends.append(load_package_from_json(dependency))
+        result.depends.append(load_package_from_json(dependency, pointer=pointer + json_pointer(*(("depends",) if n is None else ("depends", n)))))
     return result
 
 
-def get_git_ref_path(url: str, ref: Optional[str] = None, download: bool = False, update: bool = False) -> str:
-    ref = ref or "main"
-    assert "://" in url, f"Invalid git url (missing ://): {url}"
-    base_path = path.join(
-        BASEDIRECTORY,
-        "store", "git",
-        *(filesystem_safe(i) for i in url.split("://", 1)[1].split("/")),
-    )
-    bare_path = path.join(base_path, "__bare__")
-    ref_path = path.join(base_path, filesystem_safe(ref))
-    
-    if not download:
-        return path.join(base_path, ref)
-    logger.debug(f"git downloading {url}")
+class PackageBuilder:
+    # programmatic package construction: `Package.builder("foo").git("https://..", "v1.0").nu_lib("foo", "foo").build()`
+    # builds the same json a numng.json would contain -> the result gets parsed exactly like a manifest
+    def __init__(self, name: Optional[str] = None) -> None:
+        self._json: Dict[str, Any] = {} if name is None else {"name": name}
 
-    if not path.exists(bare_path):
-        logger.debug("clone bare")
-        makedirs(base_path, exist_ok=True)
-        clone_result = subprocess.run(
-            ["git", "clone", "--bare", "--quiet", "--depth=1", url, "__bare__"],
-            # shallow copies improve speed and if handled correct work identically
-            cwd=base_path,
-            stdout=subprocess.DEVNULL,
-        )
-        assert clone_result.returncode == 0, f"Failed to git clone {url}"
-
-    if not path.exists(ref_path):
-        logger.debug(f"fetch {url} {ref}")
-        fetch_result = subprocess.run(
-            ["git", "fetch", "--quiet", "--depth=1", "--tags", "origin", ref],
-            # without "--tags" we have to manually figure out weather its a branch, tag, commit, or whatever and specify it, since git otherwise just dumps it into FETCH_HEAD without storing it.
-            # "--tags" fetches all tags, but well at least its shallow..
-            cwd=bare_path,
-            stdout=subprocess.DEVNULL,
-        )
-        if fetch_result.returncode != 0:
-            logger.debug(f"fetch failed for {url} {ref}")
-            if all(i in "0123456789abcdef" for i in ref):
-                logger.debug("attempting to fix potential short-hash problem via unshallow")
-                fetch_result = subprocess.run(["git", "fetch", "--unshallow", "--quiet"], cwd=bare_path, stdout=subprocess.DEVNULL)
-        logger.debug("worktree add")
-        worktree_result = subprocess.run(["git", "worktree", "add", "--quiet", ref_path, ref], cwd=bare_path, stdout=subprocess.DEVNULL)
-        if worktree_result.returncode != 0:
-            # git_ref is most likely a branch <https://stackoverflow.com/questions/55909951> -> try to add as local branch <https://stackoverflow.com/questions/11266478>
-            logger.debug("attempting to fix potential git-branch problem via second fetch")
-            fetch_result = subprocess.run(
-                ["git", "fetch", "--quiet", "--depth=1", "--tags", "origin", f"{ref}:{ref}"],
-                cwd=bare_path,
-                stdout=subprocess.DEVNULL,
-            )
-            assert fetch_result.returncode == 0, f"Failed to fetch git_ref {ref} of {url} as a branch"
-            # retry worktree
-            worktree_result = subprocess.run(["git", "worktree", "add", "--quiet", ref_path, ref], cwd=bare_path, stdout=subprocess.DEVNULL)
-            assert worktree_result.returncode == 0, f"Failed to add a git worktree for {ref} of {url}"
-    elif update:
-        logger.debug("update")
-        subprocess.run(["git", "clean", "-qfdx", "-e", "/target"], cwd=ref_path, stdout=subprocess.DEVNULL)
-        # "-e /release" keeps the `cargo` cache improving (re-)build speed
-        r = subprocess.run(["git", "fetch", "--quiet", "origin", ref], cwd=ref_path, stdout=subprocess.DEVNULL)
-        assert r.returncode == 0, f"Failed to fetch update {url} {ref}"
-        r = subprocess.run(["git", "reset", "--hard", "--quiet", f"FETCH_HEAD"], cwd=ref_path, stdout=subprocess.DEVNULL)
-        assert r.returncode == 0, f"Failed to reset to update {url} {ref}"
-
-    return path.join(base_path, ref)
+    def name(self, name: str) -> "PackageBuilder":
+        return self.set("name", name)
 
+    def git(self, source_uri: str, git_ref: Optional[str] = None) -> "PackageBuilder":
+        self.set("source_type", "git").set("source_uri", source_uri)
+        return self if git_ref is None else self.set("git_ref", git_ref)
 
-def filesystem_safe(text: str) -> str:
-    return "".join((i if i in VALID_FILESYSTEM_CHARACTERS else "_" for i in text))
+    def archive(self, source_uri: str, sha256: Optional[str] = None) -> "PackageBuilder":
+        self.set("source_type", "archive").set("source_uri", source_uri)
+        return self if sha256 is None else self.set("sha256", sha256)
 
+    def path_offset(self, path_offset: str) -> "PackageBuilder":
+        return self.set("path_offset", path_offset)
 
-def download_file(url: str, local_file: str) -> None:
-    import requests
-    with requests.get(url, stream=True) as r:
-        r.raise_for_status()
-        with open(local_file, "wb") as fp:
-            for chunk in r.iter_content(chunk_size=8192):
-                fp.write(chunk)
+    def package_format(self, package_format: str) -> "PackageBuilder":
+        return self.set("package_format", package_format)
 
+    def version(self, version: str) -> "PackageBuilder":
+        return self.set("version", version)
 
-def load_nuon(text: str) -> Any:
-    # nope im not writing a nuon parser for this - not interrested in "13kb" or whatever
-    to_json_proc = subprocess.Popen(
-        ["nu", "--no-config-file", "--stdin", "--commands", "$in | from nuon | to json"],
+    def depends(self, *dependencies: Union[str, Dict[str, Any], Package, "PackageBuilder"]) -> "PackageBuilder":
+        self._json.setdefault("depends", []).extend(_package_json(i) for i in dependencies)
+        return self
+
+    def registry(self, registry: Union[Dict[str, Any], Package, "PackageBuilder"]) -> "PackageBuilder":
+        self._json.setdefault("registry", []).append(_package_json(registry))
+        return self
+
+    def nu_lib(self, name: str, lib_path: str) -> "PackageBuilder":
+        self._json.setdefault("nu_libs", {})[name] = lib_path
+        return self
+
+    def bin(self, name: str, bin_path: str) -> "PackageBuilder":
+        self._json.setdefault("bin", {})[name] = bin_path
+        return self
+
+    def shell_config(self, kind: str, file_path: str) -> "PackageBuilder":
+        assert kind in ("source", "source_env", "use", "use_all"), f"Unknown shell_config type {kind}"
+        self._json.setdefault("shell_config", {}).setdefault(kind, []).append(file_path)
+        return self
+
+    def build_command(self, build_command: str) -> "PackageBuilder":
+        return self.set("build_command", build_command)
+
+    def build_spec(self, kind: str, **options: Any) -> "PackageBuilder":
+        # the `build` field: `.build_spec("cargo", features=["sqlite"])` (`build` creates the Package)
+        return self.set("build", {"kind": kind, **options})
+
+    def set(self, key: str, value: Any) -> "PackageBuilder":
+        # everything else (`linkin`, `nu_plugins`, `ignore_registry`, ..)
+        self._json[key] = value
+        return self
+
+    def to_json(self) -> Dict[str, Any]:
+        return deepcopy(self._json)
+
+    def build(self, allow_no_name: bool = False) -> Package:
+        return load_package_from_json(self.to_json(), allow_no_name=allow_no_name)
+
+
+def _package_json(package: Union[str, Dict[str, Any], Package, PackageBuilder]) -> Union[str, Dict[str, Any]]:
+    if isinstance(package, Package):
+        return package_to_json(package)
+    if isinstance(package, PackageBuilder):
+        return package.to_json()
+    return package
+
+
+def package_to_json(package: Package) -> Dict[str, Any]:
+    # inverse of load_package_from_json (including the resolution results: git_ref, resolved_version, ..)
+    json_data: Dict[str, Any] = {**(package.extra_data or {})}
+    if package.name != "NO_NAME_PACKAGE":
+        json_data["name"] = package.name
+    for key, value in (
+        ("source_type", package.source_type),
+        ("source_uri", package.source_uri),
+        ("git_ref", package.source_git_ref),
+        ("path_offset", package.source_path_offset),
+        ("package_format", package.package_format),
+        ("resolved_version", package.resolved_version),
+    ):
+        if value is not None:
+            json_data[key] = value
+    if package.depends is not None:
+        json_data["depends"] = [package_to_json(i) for i in package.depends]
+    if package.registries is not None:
+        json_data["registry"] = [package_to_json(i) for i in package.registries]
+    return json_data
+
+
+@dataclass(kw_only=True)
+class Diagnostic:
+    severity: str  # "error", "warning", "deprecation", or "note"
+    message: str
+    pointer: str = ""  # json pointer to the problematic value (example: `/depends/2/source_uri`)
+    file: Optional[str] = None
+    line: Optional[int] = None  # line and column of the value within file (starting at 1)
+    column: Optional[int] = None
+    package: Optional[str] = None  # name of the package the problem was found in
+    code: Optional[str] = None  # ERROR_CODES key (only errors found by `numng validate`)
+
+    def location(self) -> str:
+        return format_location(self.pointer, self.file, self.line, self.column)
+
+    def __str__(self) -> str:
+        return f"{self.severity}{f'[{self.code}]' if self.code else ''}: {self.message}" + (f" (at {location})" if (location := self.location()) else "")
+
+
+class ManifestError(NumngError):
+    # a package definition, which can't be parsed (with the location of the bad value)
+    code = "NUMNG-E001"
+
+    def __init__(self, message: str, pointer: str = "", file: Optional[str] = None, line: Optional[int] = None, column: Optional[int] = None) -> None:
+        location: str = format_location(pointer, file, line, column)
+        super().__init__(f"Invalid package definition: {message}" + (f" (at {location})" if location else ""))
+        self.message: str = message
+        self.pointer: str = pointer
+        self.file: Optional[str] = file
+        self.line: Optional[int] = line
+        self.column: Optional[int] = column
+
+    def to_json(self) -> Dict[str, Any]:
+        return {**super().to_json(), "pointer": self.pointer, "file": self.file, "line": self.line, "column": self.column}
+
+
+def format_location(pointer: str, file: Optional[str], line: Optional[int] = None, column: Optional[int] = None) -> str:
+    # `/depends/2 in numng.json:12:5`
+    if file is not None and line is not None:
+        file = f"{file}:{line}" + (f":{column}" if column is not None else "")
+    return " in ".join(i for i in (pointer, file) if i)
+
+
+def json_pointer_location(text: str, pointer: str) -> Optional[Tuple[int, int]]:
+    # (line, column) of the value a json pointer points to (None -> it doesn't exist)
+    decoder: json.JSONDecoder = json.JSONDecoder()
+    whitespace: re.Pattern = re.compile(r"[ \t\n\r]*")
+    index: int = whitespace.match(text, 0).end()  # type: ignore
+    try:
+        for part in ([i.replace("~1", "/").replace("~0", "~") for i in pointer.split("/")[1:]] if pointer else []):
+            if text.startswith("{", index):
+                index = whitespace.match(text, index + 1).end()  # type: ignore
+                while True:
+                    if not text.startswith('"', index):
+                        return None
+                    key, index = json.decoder.scanstring(text, index + 1)  # type: ignore
+                    index = whitespace.match(text, whitespace.match(text, index).end() + 1).end()  # type: ignore  # skip the ":"
+                    if key == part:
+                        break
+                    index = whitespace.match(text, decoder.raw_decode(text, index)[1]).end()  # type: ignore
+                    if not text.startswith(",", index):
+                        return None  # end of the record
+                    index = whitespace.match(text, index + 1).end()  # type: ignore
+            elif text.startswith("[", index) and part.isdigit():
+                index = whitespace.match(text, index + 1).end()  # type: ignore
+                for _ in range(int(part)):
+                    if text.startswith("]", index):
+                        return None
+                    index = whitespace.match(text, decoder.raw_decode(text, index)[1]).end()  # type: ignore
+                    if not text.startswith(",", index):
+                        return None  # end of the list
+                    index = whitespace.match(text, index + 1).end()  # type: ignore
+                if text.startswith("]", index):
+                    return None
+            else:
+                return None
+    except json.JSONDecodeError:
+        return None
+    return text.count("\n", 0, index) + 1, index - text.rfind("\n", 0, index)
+
+
+def read_package_file(file_path: str, _including: Tuple[str, ...] = ()) -> Tuple[Dict[str, Any], List[Diagnostic]]:
+    # the json (with all `include`s merged into it) and the problems, which shouldn't abort a build (unknown keys, ..)
+    # problems, which have to abort it, raise a ManifestError pointing at the bad value
+    try:
+        text: str = read_manifest_text(file_path)
+    except AssertionError:
+        raise ManifestError("invalid nuon", file=file_path) from None
+    try:
+        json_data: Any = json.loads(text)
+    except json.JSONDecodeError as exc:
+        raise ManifestError(f"invalid json ({exc.msg})", file=file_path, line=exc.lineno, column=exc.colno) from None
+    if not isinstance(json_data, dict):
+        raise ManifestError("the package file does not contain a record", file=file_path, line=1, column=1)
+    try:
+        load_package_from_json(json_data, allow_no_name=True)
+    except ManifestError as exc:
+        raise ManifestError(exc.message, exc.pointer, file_path, *(manifest_location(file_path, text, exc.pointer) or (None, None))) from None
+    diagnostics: List[Diagnostic] = find_unknown_keys(json_data)
+    for diagnostic in diagnostics:
+        diagnostic.file = file_path
+        diagnostic.line, diagnostic.column = manifest_location(file_path, text, diagnostic.pointer) or (None, None)
+    including: Tuple[str, ...] = (*_including, path.abspath(file_path))
+    includes: Any = json_data.pop("include", None)
+    for n, include in enumerate(_listify(includes)):
+        pointer: str = json_pointer(*(("include", n) if isinstance(includes, list) else ("include",)))
+        location: Tuple[Optional[int], Optional[int]] = manifest_location(file_path, text, pointer) or (None, None)
+        if not isinstance(include, str):
+            raise ManifestError("include is not a path", pointer, file_path, *location)
+        include_path: str = path.abspath(path.join(path.dirname(file_path), include))
+        if include_path in including:
+            raise ManifestError("circular include (" + " -> ".join([*including[including.index(include_path):], include_path]) + ")", pointer, file_path, *location)
+        if not path.isfile(include_path):
+            raise ManifestError(f"the included file {include} does not exist", pointer, file_path, *location)
+        included, included_diagnostics = read_package_file(include_path, including)
+        diagnostics.extend(included_diagnostics)
+        json_data = merge_package_json(json_data, included)
+    return json_data, diagnostics
+
+
+def merge_package_json(json_data: Dict[str, Any], included: Dict[str, Any]) -> Dict[str, Any]:
+    # `include`: lists get appended, records merged, and single values only get used if json_data doesn't set them
+    result: Dict[str, Any] = dict(json_data)
+    for key, value in included.items():
+        if key not in result:
+            result[key] = value
+        elif key in ("depends", "dev_depends") and is_dependency_map(result[key]) and is_dependency_map(value):
+            result[key] = {**value, **result[key]}
+        elif key in ("depends", "dev_depends"):
+            result[key] = [*(i for _, i in _dependency_items(result[key])), *(i for _, i in _dependency_items(value))]
+        elif key in ("registry", "advisory_db", "nu_plugins", "nu_version", "post_install", "pre_remove"):
+            result[key] = [*_listify(result[key]), *_listify(value)]
+        elif key == "shell_config" and isinstance(result[key], dict) and isinstance(value, dict):
+            result[key] = {
+                kind: [*_listify(result[key].get(kind)), *_listify(value.get(kind))]
+                for kind in dict.fromkeys([*result[key], *value])
+            }
+        elif key in ("linkin", "trusted_keys", "nu_libs", "bin", "build_env") and isinstance(result[key], dict) and isinstance(value, dict):
+            result[key] = {**value, **result[key]}
+    return result
+
+
+PACKAGE_FILE_NAMES: Tuple[str, ...] = ("numng.json", "numng.jsonc", "numng.nuon")  # in priority order
+PACKAGE_FILE_EXTENSIONS: Tuple[str, ...] = (".json", ".jsonc", ".nuon")  # registry files
+
+
+def find_package_file(directory: str) -> Optional[str]:
+    return next((i for name in PACKAGE_FILE_NAMES if path.isfile(i := path.join(directory, name))), None)
+
+
+def read_manifest_text(file_path: str) -> str:
+    # package and registry files can also be nuon or jsonc (get converted to json, so the rest only has to deal with json)
+    with open(file_path, "r") as fp:
+        text: str = fp.read()
+    if file_path.endswith(".nuon"):
+        return json.dumps(load_nuon(text), indent=4)
+    if file_path.endswith(".jsonc"):
+        return strip_jsonc(text)
+    return text
+
+
+def strip_jsonc(text: str) -> str:
+    # `//` and `/* */` comments and trailing commas get replaced with spaces (lines and columns stay the same for error locations)
+    result: List[str] = list(text)
+    i: int = 0
+    last_comma: Optional[int] = None  # a comma, which is only followed by whitespace and comments (so far)
+    while i < len(text):
+        char: str = text[i]
+        if char == '"':
+            last_comma = None
+            i += 1
+            while i < len(text) and text[i] != '"':
+                i += 2 if text[i] == "\\" else 1
+        elif text.startswith("//", i) or text.startswith("/*", i):
+            end: int = text.find("\n", i) if text.startswith("//", i) else text.find("*/", i + 2)
+            end = len(text) if end == -1 else end + (2 if text.startswith("/*", i) else 0)
+            for n in range(i, end):
+                if text[n] != "\n":
+                    result[n] = " "
+            i = end
+            continue
+        elif char == ",":
+            last_comma = i
+        elif char in "}]" and last_comma is not None:
+            result[last_comma] = " "
+            last_comma = None
+        elif not char.isspace():
+            last_comma = None
+        i += 1
+    return "".join(result)
+
+
+def manifest_location(file_path: str, text: str, pointer: str) -> Optional[Tuple[int, int]]:
+    # lines and columns of converted nuon files wouldn't match the file (the json pointer is still shown)
+    return None if file_path.endswith(".nuon") else json_pointer_location(text, pointer)
+
+
+HOOK_KINDS: Tuple[str, ...] = ("pre_remove", "post_install")
+BUILD_KINDS: Dict[str, Tuple[str, ...]] = {  # kind -> options
+    "cargo": ("profile", "features", "all_features", "no_default_features", "package"),
+    "nu-script": ("script",),
+    "make": ("target",),
+    "none": (),
+}
+
+
+def _schema_list_or(item: Dict[str, Any]) -> Dict[str, Any]:
+    # most keys also accept a single value instead of a list (`_listify`)
+    return {"anyOf": [item, {"type": "array", "items": item}]}
+
+
+_SCHEMA_STRING: Dict[str, Any] = {"type": "string"}
+_SCHEMA_BOOLEAN: Dict[str, Any] = {"type": "boolean"}
+_SCHEMA_PACKAGE: Dict[str, Any] = {"$ref": "#/$defs/package"}
+_SCHEMA_DEPENDENCY: Dict[str, Any] = {"anyOf": [_SCHEMA_STRING, _SCHEMA_PACKAGE]}
+_SCHEMA_DEPENDENCY_MAP: Dict[str, Any] = {"type": "object", "additionalProperties": _SCHEMA_STRING, "minProperties": 1}  # name -> version
+BUILD_OPTION_SCHEMAS: Dict[str, Dict[str, Any]] = {
+    "profile": _SCHEMA_STRING,
+    "features": _schema_list_or(_SCHEMA_STRING),
+    "all_features": _SCHEMA_BOOLEAN,
+    "no_default_features": _SCHEMA_BOOLEAN,
+    "package": _SCHEMA_STRING,
+    "script": _SCHEMA_STRING,
+    "target": _SCHEMA_STRING,
+}
+# every key a numng.json can contain (`numng schema` and the unknown key check are generated from this -> add new keys here)
+PACKAGE_KEY_SCHEMAS: Dict[str, Dict[str, Any]] = {
+    "$schema": _SCHEMA_STRING,  # lets editors find the schema
+    "name": _SCHEMA_STRING,
+    "linkin": {"type": "object", "additionalProperties": _SCHEMA_PACKAGE},
+    "source_type": {"enum": ["git", "archive"]},
+    "source_uri": _SCHEMA_STRING,
+    "sha256": {"type": "string", "pattern": "^[0-9a-fA-F]{64}$"},
+    "git_ref": _SCHEMA_STRING,
+    "path_offset": _SCHEMA_STRING,
+    "depends": {"anyOf": [*_schema_list_or(_SCHEMA_DEPENDENCY)["anyOf"], _SCHEMA_DEPENDENCY_MAP]},
+    "package_format": {"enum": ["numng", "nupm", "packer", "packer.nu"]},
+    "ignore_registry": {"anyOf": [_SCHEMA_BOOLEAN, {"type": "array", "items": _SCHEMA_STRING}]},
+    "version": _SCHEMA_STRING,
+    "resolved_version": _SCHEMA_STRING,
+    "version_scheme": {"enum": list(VERSION_SCHEMES)},
+    "nu_plugins": _schema_list_or(_SCHEMA_STRING),
+    "registry": _schema_list_or(_SCHEMA_PACKAGE),
+    "advisory_db": _schema_list_or(_SCHEMA_PACKAGE),
+    "trusted_keys": {"type": "object", "additionalProperties": _SCHEMA_STRING},
+    "trusted_key": _SCHEMA_STRING,
+    "nu_libs": {"type": "object", "additionalProperties": _SCHEMA_STRING},
+    "shell_config": {
+        "type": "object",
+        "properties": {kind: _schema_list_or(_SCHEMA_STRING) for kind in ("source", "source_env", "use", "use_all")},
+        "additionalProperties": False,
+    },
+    "bin": {"type": "object", "additionalProperties": _SCHEMA_STRING},
+    "build_command": _SCHEMA_STRING,
+    "build_sandbox": {"anyOf": [_SCHEMA_BOOLEAN, {"type": "object", "additionalProperties": _SCHEMA_BOOLEAN}]},
+    "local_environment": _SCHEMA_BOOLEAN,
+    "nu_version": _schema_list_or(_SCHEMA_STRING),
+    "nu_version_check": {"enum": ["warn", "error", "ignore"]},
+    "nupm_home_link_mode": {"enum": ["symlink", "copy"]},
+    "nupm_home_layout": {"enum": ["numng", "nupm"]},
+    "latest_git_ref": {"enum": ["tag", "branch"]},
+    "allow_build_commands": _SCHEMA_BOOLEAN,
+    "artifact_cache": _SCHEMA_BOOLEAN,
+    "build": {"anyOf": [{"enum": list(BUILD_KINDS)}, *(
+        {
+            "type": "object",
+            "properties": {"kind": {"const": kind}, **{option: BUILD_OPTION_SCHEMAS[option] for option in options}},
+            "required": ["kind"],
+            "additionalProperties": False,
+        }
+        for kind, options in BUILD_KINDS.items()
+    )]},
+    "build_env": {"type": "object", "additionalProperties": _SCHEMA_STRING},
+    "post_install": _schema_list_or(_SCHEMA_STRING),
+    "pre_remove": _schema_list_or(_SCHEMA_STRING),
+    "post_install_message": _SCHEMA_STRING,
+    "dev_depends": {"anyOf": [*_schema_list_or(_SCHEMA_DEPENDENCY)["anyOf"], _SCHEMA_DEPENDENCY_MAP]},
+    "test_command": _SCHEMA_STRING,
+    "include": _schema_list_or(_SCHEMA_STRING),
+    "as": _SCHEMA_STRING,
+    "merge": {"anyOf": [{"enum": list(MERGE_STRATEGIES)}, {"type": "object", "additionalProperties": {"enum": list(MERGE_STRATEGIES)}}]},
+}
+PACKAGE_KEYS: Tuple[str, ...] = tuple(PACKAGE_KEY_SCHEMAS)
+
+
+def package_json_schema() -> Dict[str, Any]:
+    # `numng schema`: JSON Schema of a numng.json (for editors)
+    return {
+        "$schema": "https://json-schema.org/draft/2020-12/schema",
+        "title": "numng package",
+        "$ref": "#/$defs/package",
+        "$defs": {
+            "package": {"type": "object", "properties": PACKAGE_KEY_SCHEMAS, "additionalProperties": False},
+        },
+    }
+
+
+def find_unknown_keys(json_data: Any, pointer: str = "") -> List[Diagnostic]:
+    # misspelled keys (`depend`, `nu-libs`, ..) would otherwise get ignored silently
+    # (also checks depends, linkins, registries, and advisory dbs)
+    diagnostics: List[Diagnostic] = []
+    if not isinstance(json_data, dict):
+        return diagnostics
+    for key in json_data:
+        if key not in PACKAGE_KEYS:
+            suggestion: List[str] = difflib.get_close_matches(key.replace("-", "_"), PACKAGE_KEYS, n=1)
+            diagnostics.append(Diagnostic(
+                severity="warning",
+                message=f"unknown key {key}" + (f" (did you mean {suggestion[0]}?)" if suggestion else ""),
+                pointer=pointer + json_pointer(key),
+            ))
+    for key in ("depends", "dev_depends", "registry", "advisory_db"):
+        if is_dependency_map(json_data.get(key)):
+            continue
+        for n, dependency in enumerate(_listify(json_data.get(key))):
+            diagnostics.extend(find_unknown_keys(dependency, pointer + json_pointer(*((key, n) if isinstance(json_data[key], list) else (key,)))))
+    for linkin_path, linkin_json in (json_data.get("linkin") if isinstance(json_data.get("linkin"), dict) else {}).items():
+        diagnostics.extend(find_unknown_keys(linkin_json, pointer + json_pointer("linkin", linkin_path)))
+    return diagnostics
+
+
+def json_pointer(*parts: Union[str, int]) -> str:
+    return "".join("/" + str(i).replace("~", "~0").replace("/", "~1") for i in parts)
+
+
+def build_command_from_spec(spec: Any, nu_binary: str = "nu") -> Optional[str]:
+    # `build`: a kind (`"cargo"`) or a record with a kind and its options (`{"kind": "cargo", "features": ["sqlite"]}`)
+    # -> the build_command it stands for (None for `none`). nu_binary: the configured nu (Config.nu_binary)
+    if isinstance(spec, str):
+        spec = {"kind": spec}
+    assert isinstance(spec, dict), "build is neither a kind nor a record"
+    kind: Any = spec.get("kind")
+    assert kind in BUILD_KINDS, f"unknown build kind {kind} (available: {', '.join(BUILD_KINDS)})"
+    for key in spec:
+        assert key == "kind" or key in BUILD_KINDS[kind], f"unknown option {key} for build kind {kind} (available: {', '.join(BUILD_KINDS[kind]) or 'none'})"
+    for key in ("profile", "package", "script", "target"):
+        assert key not in spec or isinstance(spec[key], str), f"build option {key} is not a string"
+    for key in ("all_features", "no_default_features"):
+        assert key not in spec or isinstance(spec[key], bool), f"build option {key} is not a boolean"
+    if kind == "cargo":
+        features: List[Any] = _listify(spec.get("features"))
+        assert all(isinstance(i, str) for i in features), "build option features is not a list of strings"
+        profile: str = spec.get("profile", "release")
+        args: List[str] = ["cargo", "build"]
+        if profile == "release":
+            args.append("--release")
+        elif profile != "dev":
+            args.extend(["--profile", json.dumps(profile)])
+        if spec.get("package") is not None:
+            args.extend(["--package", json.dumps(spec["package"])])
+        if features:
+            args.extend(["--features", json.dumps(",".join(features))])
+        if spec.get("all_features"):
+            args.append("--all-features")
+        if spec.get("no_default_features"):
+            args.append("--no-default-features")
+        return " ".join(args)
+    if kind == "nu-script":
+        assert isinstance(spec.get("script"), str), "build kind nu-script requires a script"
+        assert not _path_escapes(spec["script"]), f"build script {spec['script']} is outside of the package"
+        # build_commands run in nu -> paths with spaces, etc have to be quoted and marked as external command
+        nu_command: str = nu_binary if re.fullmatch(r"[\w./-]+", nu_binary) else f"^{json.dumps(nu_binary)}"
+        return f"{nu_command} --no-config-file {json.dumps(spec['script'])}"
+    if kind == "make":
+        return "make" if spec.get("target") is None else f"make {json.dumps(spec['target'])}"
+    return None
+
+
+def get_build_command(json_data: Dict[str, Any], nu_binary: str = "nu") -> Optional[str]:
+    assert not ("build" in json_data and "build_command" in json_data), "build and build_command are both set"
+    if "build" in json_data:
+        return build_command_from_spec(json_data["build"], nu_binary)
+    return json_data.get("build_command")
+
+
+def _path_escapes(file_path: str) -> bool:
+    return path.isabs(file_path) or path.normpath(file_path).split(path.sep)[0] == ".."
+
+
+def _version_problem(text: Any, version_scheme: str = "semver") -> Optional[str]:
+    if not isinstance(text, str):
+        return "is not a string"
+    for alternative in text.split("||"):
+        for part in (i.strip() for i in re.split(r",| - ", alternative)):
+            if part in ("", "*", "x", "X"):
+                continue
+            if re.fullmatch(r"[0-9A-Za-z.*^~<>=!+-]+", part) is None:
+                return f"contains invalid characters ({part})"
+            version: SemVer = VERSION_SCHEMES.get(version_scheme, SemVer)(part)
+            if version.major is None and version.comparator is None and (version.op is None or version.op in SEMVER_COMPARISON_OPS or version.op in "^~"):
+                return f"is not a valid version requirement ({part})"
+    return None
+
+
+def validate_package_json(json_data: Any, base_path: Optional[str] = None, pointer: str = "") -> List[Diagnostic]:
+    # semantic checks beyond "is it valid json" (all problems get collected instead of failing at the first one)
+    # base_path (the downloaded package): also check, that the referenced files exist
+    diagnostics: List[Diagnostic] = []
+
+    def error(message: str, *parts: Union[str, int], severity: str = "error") -> None:
+        diagnostics.append(Diagnostic(severity=severity, message=message, pointer=pointer + json_pointer(*parts)))
+
+    if isinstance(json_data, str):
+        return diagnostics
+    if not isinstance(json_data, dict):
+        error("package is neither a name nor a record")
+        return diagnostics
+    if pointer == "":
+        diagnostics.extend(find_unknown_keys(json_data))
+    for key in ("name", "source_type", "source_uri", "git_ref", "path_offset", "package_format", "sha256", "build_command", "post_install_message", "test_command", "as"):
+        if key in json_data and not isinstance(json_data[key], str):
+            error(f"{key} is not a string", key)
+    if "ignore_registry" in json_data and not (isinstance(json_data["ignore_registry"], bool) or (isinstance(json_data["ignore_registry"], list) and all(isinstance(i, str) for i in json_data["ignore_registry"]))):
+        error("ignore_registry is neither a boolean nor a list of keys", "ignore_registry")
+    if "merge" in json_data and not all(i in MERGE_STRATEGIES for i in (json_data["merge"].values() if isinstance(json_data["merge"], dict) else [json_data["merge"]])):
+        error(f"unknown merge strategy (available: {', '.join(MERGE_STRATEGIES)})", "merge")
+    if isinstance(json_data.get("as"), str) and (json_data["as"] == "" or filesystem_safe(json_data["as"]) != json_data["as"]):
+        error(f"as {json_data['as']} is not a valid module name", "as")
+    if json_data.get("source_type") not in (None, "git", "archive"):
+        error(f"unknown source_type {json_data['source_type']} (available: git, archive)", "source_type")
+    if "source_type" in json_data and "source_uri" not in json_data:
+        error("source_type is set, but source_uri is missing", "source_uri")
+    if isinstance(json_data.get("source_uri"), str) and "://" not in json_data["source_uri"]:
+        error("source_uri is not a url (missing ://)", "source_uri")
+    if json_data.get("source_type") == "archive" and "sha256" not in json_data:
+        error("archive without sha256 (the download can't be verified)", "sha256", severity="warning")
+    if json_data.get("package_format") not in (None, "numng", "nupm", "packer", "packer.nu"):
+        error(f"unknown package_format {json_data['package_format']} (available: numng, nupm, packer)", "package_format")
+    if isinstance(json_data.get("path_offset"), str) and _path_escapes(json_data["path_offset"]):
+        error("path_offset points outside of the source", "path_offset")
+    version_scheme: Any = json_data.get("version_scheme", "semver")
+    if version_scheme not in VERSION_SCHEMES:
+        error(f"unknown version_scheme {version_scheme} (available: {', '.join(VERSION_SCHEMES)})", "version_scheme")
+        version_scheme = "semver"
+    if "version" in json_data and (problem := _version_problem(json_data["version"], version_scheme)) is not None:
+        error(f"version {problem}", "version")
+    for n, nu_version in enumerate(_listify(json_data.get("nu_version"))):
+        if (problem := _version_problem(nu_version)) is not None:
+            error(f"nu_version {problem}", *(("nu_version", n) if isinstance(json_data["nu_version"], list) else ("nu_version",)))
+
+    file_keys: List[Tuple[Tuple[Union[str, int], ...], Any]] = []  # (pointer parts, path within the package)
+    for key in ("nu_libs", "bin", "linkin", "shell_config"):
+        if key in json_data and not isinstance(json_data[key], dict):
+            error(f"{key} is not a record", key)
+    for key in ("nu_libs", "bin"):
+        for name, file_path in (json_data.get(key) if isinstance(json_data.get(key), dict) else {}).items():
+            file_keys.append(((key, name), file_path))
+    for kind, file_paths in (json_data.get("shell_config") if isinstance(json_data.get("shell_config"), dict) else {}).items():
+        if kind not in ("source", "source_env", "use", "use_all"):
+            error(f"unknown shell_config type {kind} (available: source, source_env, use, use_all)", "shell_config", kind)
+            continue
+        for n, file_path in enumerate(_listify(file_paths)):
+            file_keys.append((("shell_config", kind, n) if isinstance(file_paths, list) else ("shell_config", kind), file_path))
+    if "build" in json_data or "build_command" in json_data:
+        try:
+            get_build_command(json_data)
+        except AssertionError as exc:
+            error(exc.args[0], "build" if "build" in json_data else "build_command")
+    for n, include in enumerate(_listify(json_data.get("include"))):
+        include_pointer: Tuple[Union[str, int], ...] = ("include", n) if isinstance(json_data["include"], list) else ("include",)
+        if not isinstance(include, str):
+            error("include is not a path", *include_pointer)
+        elif base_path is not None and not path.isfile(path.join(base_path, *include.split("/"))):
+            error(f"the included file {include} does not exist", *include_pointer)
+    for kind in HOOK_KINDS:
+        if not all(isinstance(i, str) for i in _listify(json_data.get(kind))):
+            error(f"{kind} is neither a string nor a list of strings", kind)
+    if "build_env" in json_data and not (isinstance(json_data["build_env"], dict) and all(isinstance(i, str) for i in json_data["build_env"].values())):
+        error("build_env is not a record of strings", "build_env")
+    if isinstance(json_data.get("build"), dict) and isinstance(json_data["build"].get("script"), str) and not _path_escapes(json_data["build"]["script"]):
+        file_keys.append((("build", "script"), json_data["build"]["script"]))
+    for n, plugin in enumerate(_listify(json_data.get("nu_plugins"))):
+        if not isinstance(plugin, str) or _path_escapes(plugin):
+            error("nu_plugins entry is not a path within the package", "nu_plugins", n)
+    for parts, file_path in file_keys:
+        if not isinstance(file_path, str):
+            error("is not a path", *parts)
+        elif _path_escapes(file_path):
+            error(f"{file_path} is outside of the package", *parts)
+        elif base_path is not None and not path.exists(path.join(base_path, *file_path.split("/"))):
+            # build_commands can create files
+            error(f"{file_path} does not exist", *parts, severity="warning" if "build_command" in json_data or "build" in json_data else "error")
+    for linkin_path, linkin_json in (json_data.get("linkin") if isinstance(json_data.get("linkin"), dict) else {}).items():
+        if _path_escapes(linkin_path.split(":", 1)[-1]) or (":" in linkin_path and _path_escapes(linkin_path.split(":", 1)[0])):
+            error(f"linkin {linkin_path} is outside of the package", "linkin", linkin_path)
+        diagnostics.extend(validate_package_json(linkin_json, pointer=pointer + json_pointer("linkin", linkin_path)))
+
+    for key in ("depends", "dev_depends"):
+        if key in json_data and not isinstance(json_data[key], (list, dict, str)):
+            error(f"{key} is neither a list nor a package", key)
+        if is_dependency_map(json_data.get(key)):
+            for name, version in json_data[key].items():
+                if (problem := _version_problem(version)) is not None:
+                    error(f"version {problem}", key, name)
+            continue
+        for n, dependency in enumerate(_listify(json_data.get(key))):
+            if isinstance(dependency, dict) and "name" not in dependency:
+                error("dependency without name", *((key, n) if isinstance(json_data[key], list) else (key,)))
+            diagnostics.extend(validate_package_json(dependency, pointer=pointer + json_pointer(*((key, n) if isinstance(json_data[key], list) else (key,)))))
+    for n, registry in enumerate(_listify(json_data.get("registry"))):
+        registry_pointer: Tuple[Union[str, int], ...] = ("registry", n) if isinstance(json_data["registry"], list) else ("registry",)
+        if not isinstance(registry, dict) or "package_format" not in registry:
+            error("registry without package_format", *registry_pointer)
+        diagnostics.extend(validate_package_json(registry, pointer=pointer + json_pointer(*registry_pointer)))
+    return diagnostics
+
+
+def validate_file(file_path: str, registry: bool = False) -> List[Diagnostic]:
+    # `numng validate`: everything that can be checked without downloading anything
+    # registry: file_path is a numng registry file ({VERSION: PACKAGE or ALIAS, "_": SHARED_DATA}) instead of a package file
+    try:
+        text: str = read_manifest_text(file_path)
+    except AssertionError:
+        return [Diagnostic(severity="error", message="invalid nuon", file=file_path, code=ManifestError.code)]
+    diagnostics: List[Diagnostic] = []
+    try:
+        json_data: Any = json.loads(text)
+    except json.JSONDecodeError as exc:
+        return [Diagnostic(severity="error", message=f"invalid json ({exc.msg})", file=file_path, line=exc.lineno, column=exc.colno, code=ManifestError.code)]
+    if not isinstance(json_data, dict):
+        diagnostics.append(Diagnostic(severity="error", message="the file does not contain a record"))
+    elif not registry:
+        diagnostics.extend(validate_package_json(json_data, base_path=path.dirname(file_path)))
+        try:
+            load_package_from_json(json_data, allow_no_name=True)
+        except ManifestError as exc:
+            # most of these are also found by validate_package_json
+            if not any(i.severity == "error" and i.pointer == exc.pointer for i in diagnostics):
+                diagnostics.append(Diagnostic(severity="error", message=exc.message, pointer=exc.pointer))
+    else:
+        version_scheme: Any = (json_data.get("_") if isinstance(json_data.get("_"), dict) else {}).get("version_scheme", "semver")
+        for version, package in json_data.items():
+            pointer: str = json_pointer(version)
+            if isinstance(package, str):
+                if package not in json_data:
+                    diagnostics.append(Diagnostic(severity="error", message=f"version alias {version} points to the unknown version {package}", pointer=pointer))
+                continue
+            if version != "_" and version_scheme in VERSION_SCHEMES and (problem := _version_problem(version, version_scheme)) is not None:
+                diagnostics.append(Diagnostic(severity="error", message=f"version {problem}", pointer=pointer))
+            diagnostics.extend(find_unknown_keys(package, pointer))
+            diagnostics.extend(validate_package_json(package, pointer=pointer))
+            if isinstance(package, dict) and version != "_":
+                try:
+                    load_package_from_json({**package, "name": package.get("name", "registry-package")}, pointer=pointer)
+                except ManifestError as exc:
+                    if not any(i.severity == "error" and i.pointer == exc.pointer for i in diagnostics):
+                        diagnostics.append(Diagnostic(severity="error", message=exc.message, pointer=exc.pointer))
+    for diagnostic in diagnostics:
+        diagnostic.file = file_path
+        diagnostic.line, diagnostic.column = manifest_location(file_path, text, diagnostic.pointer) or (None, None)
+        if diagnostic.severity == "error":
+            diagnostic.code = ManifestError.code
+    return diagnostics
+
+
+FORMAT_FIRST_KEYS: Tuple[str, ...] = (
+    "$schema", "name", "version", "version_scheme", "source_type", "source_uri", "git_ref", "path_offset", "sha256", "package_format", "depends", "dev_depends",
+)
+
+
+def format_package_json(json_data: Any) -> Any:
+    # canonical form for `numng fmt`: known keys in a fixed order (identity first), unknown keys sorted at the end, depends sorted by name
+    if not isinstance(json_data, dict):
+        return json_data
+    key_order: List[str] = [*FORMAT_FIRST_KEYS, *(i for i in PACKAGE_KEYS if i not in FORMAT_FIRST_KEYS)]
+    result: Dict[str, Any] = {}
+    for key in sorted(json_data, key=lambda i: (0, key_order.index(i), "") if i in key_order else (1, 0, i)):
+        value: Any = json_data[key]
+        if key in ("depends", "dev_depends") and is_dependency_map(value):
+            value = dict(sorted(value.items()))
+        elif key in ("depends", "dev_depends") and isinstance(value, list):
+            value = sorted((format_package_json(i) for i in value), key=lambda i: i if isinstance(i, str) else str(i.get("name", "")) if isinstance(i, dict) else "")
+        elif key in ("depends", "dev_depends", "registry", "advisory_db"):
+            value = [format_package_json(i) for i in value] if isinstance(value, list) else format_package_json(value)
+        elif key == "linkin" and isinstance(value, dict):
+            value = {linkin_path: format_package_json(linkin_json) for linkin_path, linkin_json in value.items()}
+        result[key] = value
+    return result
+
+
+def format_package_file(file_path: str) -> Tuple[str, str]:
+    # -> (current content, formatted content)
+    with open(file_path, "r") as fp:
+        text: str = fp.read()
+    json_data, _ = read_package_file(file_path)
+    return text, json.dumps(format_package_json(json_data), indent=4, ensure_ascii=False) + "\n"
+
+
+def get_git_store_path(url: str) -> str:
+    assert "://" in url, f"Invalid git url (missing ://): {url}"
+    return data_path(
+        "store", "git",
+        *(filesystem_safe(i) for i in url.split("://", 1)[1].split("/")),
+    )
+
+
+GIT_REFS_FILE_NAME: str = "__refs__.json"  # next to __bare__: checkout directory -> git ref (filesystem_safe can't be reversed)
+
+
+def load_git_refs(base_path: str) -> Dict[str, str]:
+    if not path.isfile(refs_file := path.join(base_path, GIT_REFS_FILE_NAME)):
+        return {}
+    with open(refs_file, "r") as fp:
+        refs: Any = json.load(fp)
+    assert isinstance(refs, dict), f"Invalid {refs_file} (not a dict)"
+    return refs
+
+
+def remember_git_ref(base_path: str, ref: str) -> None:
+    refs: Dict[str, str] = load_git_refs(base_path)
+    if refs.get(filesystem_safe(ref)) != ref:
+        refs[filesystem_safe(ref)] = ref
+        save_json_state(path.join(base_path, GIT_REFS_FILE_NAME), refs, indent=4)
+
+
+def get_git_ref_path(url: str, ref: Optional[str] = None, download: bool = False, update: bool = False, offline: bool = False) -> str:
+    ref = ref or "main"
+    base_path = get_git_store_path(url)
+    bare_path = path.join(base_path, "__bare__")
+    ref_path = path.join(base_path, filesystem_safe(ref))
+    
+    if not download:
+        return ref_path
+    with get_named_lock(base_path), log_span(git=f"{url} {ref}"):  # multiple fetch workers could target the same repo
+        logger.debug(f"git downloading {url}")
+
+        if offline and not path.exists(ref_path):
+            raise OfflineError(f"{url} {ref} is not in the store (unable to download it in offline mode)")
+        if not path.exists(bare_path):
+            logger.debug("clone bare")
+            makedirs(base_path, exist_ok=True)
+            clone_result = run_command(
+                ["git", "clone", "--bare", "--quiet", "--depth=1", url, "__bare__"],
+                # shallow copies improve speed and if handled correct work identically
+                cwd=base_path,
+                stdout=subprocess.DEVNULL,
+            )
+            assert clone_result.returncode == 0, f"Failed to git clone {url}"
+
+        if not path.exists(ref_path):
+            logger.debug(f"fetch {url} {ref}")
+            fetch_result = run_command(
+                ["git", "fetch", "--quiet", "--depth=1", "--tags", "origin", ref],
+                # without "--tags" we have to manually figure out weather its a branch, tag, commit, or whatever and specify it, since git otherwise just dumps it into FETCH_HEAD without storing it.
+                # "--tags" fetches all tags, but well at least its shallow..
+                cwd=bare_path,
+                stdout=subprocess.DEVNULL,
+            )
+            if fetch_result.returncode != 0:
+                logger.debug(f"fetch failed for {url} {ref}")
+                if all(i in "0123456789abcdef" for i in ref):
+                    logger.debug("attempting to fix potential short-hash problem via unshallow")
+                    fetch_result = run_command(["git", "fetch", "--unshallow", "--quiet"], cwd=bare_path, stdout=subprocess.DEVNULL)
+            logger.debug("worktree add")
+            worktree_result = run_command(["git", "worktree", "add", "--quiet", ref_path, ref], cwd=bare_path, stdout=subprocess.DEVNULL)
+            if worktree_result.returncode != 0:
+                # git_ref is most likely a branch <https://stackoverflow.com/questions/55909951> -> try to add as local branch <https://stackoverflow.com/questions/11266478>
+                logger.debug("attempting to fix potential git-branch problem via second fetch")
+                fetch_result = run_command(
+                    ["git", "fetch", "--quiet", "--depth=1", "--tags", "origin", f"{ref}:{ref}"],
+                    cwd=bare_path,
+                    stdout=subprocess.DEVNULL,
+                )
+                assert fetch_result.returncode == 0, f"Failed to fetch git_ref {ref} of {url} as a branch"
+                # retry worktree
+                worktree_result = run_command(["git", "worktree", "add", "--quiet", ref_path, ref], cwd=bare_path, stdout=subprocess.DEVNULL)
+                assert worktree_result.returncode == 0, f"Failed to add a git worktree for {ref} of {url}"
+            remember_git_ref(base_path, ref)
+        elif update:
+            logger.debug("update")
+            r = run_command(["git", "fetch", "--quiet", "origin", ref], cwd=ref_path, stdout=subprocess.DEVNULL)
+            assert r.returncode == 0, f"Failed to fetch update {url} {ref}"
+            if (fetch_head := command_output(["git", "rev-parse", "FETCH_HEAD"], cwd=ref_path)) is not None and fetch_head.strip() == get_git_commit(ref_path):
+                # nothing changed -> keep the build output (incremental builds rely on it)
+                logger.debug(f"{url} {ref} is already up to date")
+            else:
+                run_command(["git", "clean", "-qfdx", "-e", "/target"], cwd=ref_path, stdout=subprocess.DEVNULL)
+                # "-e /release" keeps the `cargo` cache improving (re-)build speed
+                r = run_command(["git", "reset", "--hard", "--quiet", f"FETCH_HEAD"], cwd=ref_path, stdout=subprocess.DEVNULL)
+                assert r.returncode == 0, f"Failed to reset to update {url} {ref}"
+
+    return ref_path
+
+
+def list_git_tags(url: str, offline: bool = False) -> List[str]:
+    if offline:
+        # the bare repo contains every tag known at the last fetch
+        bare_path: str = path.join(get_git_store_path(url), "__bare__")
+        if not path.exists(bare_path):
+            raise OfflineError(f"{url} is not in the store (unable to list its tags in offline mode)")
+        tags: Optional[str] = command_output(["git", "tag", "--list"], cwd=bare_path)
+        assert tags is not None, f"Failed to list the tags of {url}"
+        return tags.split()
+    refs: Optional[str] = command_output(["git", "ls-remote", "--tags", "--refs", url])
+    assert refs is not None, f"Failed to list the tags of {url}"
+    return [line.split("\t", 1)[1].removeprefix("refs/tags/") for line in refs.splitlines() if "\t" in line]
+
+
+def git_tag_version(tag: str, version_scheme: str = "semver") -> Optional[str]:
+    # `1.2.3` and `v1.2.3` style tags (`2024.05.01` and `2024-05-01` for calver) (everything else is not a version)
+    if re.fullmatch(r"v?[0-9]+([.-][0-9]+)*" if version_scheme == "calver" else r"v?[0-9]+(\.[0-9]+){0,2}(-[0-9A-Za-z.-]+)?(\+[0-9A-Za-z.-]+)?", tag):
+        return tag.removeprefix("v")
+    return None
+
+
+def latest_matching_git_tag(tags: List[str], version: str, version_scheme: str = "semver") -> Optional[str]:
+    return VersionReq(version, version_scheme).latest_matching_dict_entry({
+        tag_version: tag for tag in tags if (tag_version := git_tag_version(tag, version_scheme)) is not None
+    })
+
+
+def get_git_commit_date(url: str, commit: str) -> str:
+    # asks the bare repo, since vendored copies are no git repos
+    bare_path: str = path.join(get_git_store_path(url), "__bare__")
+    date_proc = run_command(
+        ["git", "show", "--no-patch", "--format=%cd", "--date=format-local:%Y.%m.%d", commit],
+        cwd=bare_path, stdout=subprocess.PIPE, env={**environ, "TZ": "UTC"},
+    )
+    assert date_proc.returncode == 0, f"Failed to get the commit date of {commit} ({url})"
+    return date_proc.stdout.decode(encoding="utf-8").strip()
+
+
+def get_store_lock_holder() -> Optional[str]:
+    # pid of the process currently holding the store lock (None if it's not locked)
+    if not path.isfile(lock_file := data_path(STORE_LOCK_FILE_NAME)):
+        return None
+    try:
+        with StoreLock():
+            return None
+    except AssertionError:
+        with open(lock_file, "r") as fp:
+            return fp.read().strip() or "unknown"
+
+
+class StoreLock:
+    # prevents multiple numng processes from using the store (git repos, etc) at the same time
+    def __init__(self, wait: bool = False) -> None:
+        self._wait: bool = wait
+        self._fp: Optional[Any] = None
+
+    def __enter__(self) -> "StoreLock":
+        makedirs(data_path(), exist_ok=True)
+        self._fp = open(data_path(STORE_LOCK_FILE_NAME), "a+")
+        try:
+            if fcntl is not None:
+                fcntl.flock(self._fp, fcntl.LOCK_EX | (0 if self._wait else fcntl.LOCK_NB))
+            else:
+                msvcrt.locking(self._fp.fileno(), msvcrt.LK_LOCK if self._wait else msvcrt.LK_NBLCK, 1)
+        except OSError:
+            self._fp.close()
+            self._fp = None
+            raise AssertionError("Another numng process is currently using the store (add --wait to wait for it)")
+        self._fp.seek(0)
+        self._fp.truncate()
+        self._fp.write(str(getpid()))
+        self._fp.flush()
+        return self
+
+    def __exit__(self, *_) -> None:
+        if self._fp is None:
+            return
+        if fcntl is not None:
+            fcntl.flock(self._fp, fcntl.LOCK_UN)
+        else:
+            msvcrt.locking(self._fp.fileno(), msvcrt.LK_UNLCK, 1)
+        self._fp.close()
+        self._fp = None
+
+
+_named_locks: Dict[str, Lock] = {}
+_named_locks_lock: Lock = Lock()
+
+
+def get_named_lock(name: str) -> Lock:
+    with _named_locks_lock:
+        return _named_locks.setdefault(name, Lock())
+
+
+def normalize_git_url(url: str) -> str:
+    scheme, rest = url.split("://", 1) if "://" in url else ("", url)
+    host, _, repo_path = rest.partition("/")
+    repo_path = repo_path.rstrip("/")
+    if repo_path.endswith(".git"):
+        repo_path = repo_path[:-4]
+    return f"{scheme.lower()}://{host.lower()}/{repo_path}"
+
+
+def environment_status(report: BuildReport, state: Optional[Dict[str, Any]]) -> Dict[str, List[str]]:
+    # compares a (dry run) BuildReport with the state of a installed environment
+    # packages are matched by path (names can be ambiguous). one moving to a new path (other git_ref, ..) counts as updated if its name is unique
+    installed: Dict[str, Dict[str, Any]] = {i["path"]: i for i in (state or {}).get("packages", [])}
+    resolved: Dict[str, BuildReportPackage] = {i.path: i for i in report.packages}
+    installed_names: List[str] = [i["name"] for i in installed.values()]
+    resolved_names: List[str] = [i.name for i in resolved.values()]
+
+    def label(name: str, package_path: str) -> str:
+        # the path is only added if the name alone is ambiguous
+        return name if installed_names.count(name) <= 1 and resolved_names.count(name) <= 1 else f"{name} ({package_path})"
+
+    moved: Dict[str, str] = {  # resolved path -> installed path
+        package.path: installed_path
+        for package in resolved.values()
+        for installed_path, installed_package in installed.items()
+        if installed_package["name"] == package.name and installed_names.count(package.name) == 1 and resolved_names.count(package.name) == 1
+        and package.path not in installed and installed_path not in resolved
+    }
+    status: Dict[str, List[str]] = {"added": [], "removed": [], "updated": [], "rebuilt": []}
+    for package_path, package in resolved.items():
+        if (previous := installed.get(moved.get(package_path, package_path))) is None:
+            status["added"].append(label(package.name, package_path))
+        elif (previous["path"], previous["commit"]) != (package.path, package.commit):
+            status["updated"].append(label(package.name, package_path))
+        else:
+            continue
+        if package.has_build_command:
+            status["rebuilt"].append(label(package.name, package_path))
+    status["removed"] = [label(i["name"], i["path"]) for i in installed.values() if i["path"] not in resolved and i["path"] not in moved.values()]
+    return status
+
+
+def load_json_state(file_path: str, default: Any) -> Any:
+    # numngs own caches and progress files: a missing or broken file is the same as a empty one
+    if not path.isfile(file_path):
+        return default
+    try:
+        with open(file_path, "r") as fp:
+            data: Any = json.load(fp)
+    except (OSError, json.JSONDecodeError) as exc:
+        logger.debug(f"ignoring the broken state file {file_path}: {exc}")
+        return default
+    return data if isinstance(data, type(default)) else default
+
+
+def save_json_state(file_path: str, data: Any, indent: Optional[int] = None) -> None:
+    # written to a temporary file first -> a interrupted write can't leave a broken file behind
+    makedirs(path.dirname(path.abspath(file_path)), exist_ok=True)
+    tmp_file: str = f"{file_path}.tmp-{token_hex(4)}"
+    with open(tmp_file, "w") as fp:
+        json.dump(data, fp, indent=indent)
+    replace(tmp_file, file_path)
+
+
+def load_resolution_cache() -> Dict[str, Dict[str, Any]]:
+    return load_json_state(data_path(RESOLUTION_CACHE_FILE_NAME), {})
+
+
+def save_resolution_cache(cache: Dict[str, Dict[str, Any]]) -> None:
+    save_json_state(data_path(RESOLUTION_CACHE_FILE_NAME), cache)
+
+
+def build_output_files(base_path: str) -> List[str]:
+    # files a build_command created (untracked or ignored by git) relative to base_path
+    # cargos intermediate files (target/release/deps, ..) are left out (they are big and only needed for rebuilds)
+    output: Optional[str] = command_output(["git", "ls-files", "--others", "-z"], cwd=base_path)
+    return [
+        i for i in (output or "").split("\0")
+        if i and not (i.startswith("target/") and any(part in ("deps", "build", ".fingerprint", "incremental", "examples") for part in i.split("/")[1:-1]))
+    ]
+
+
+def save_build_artifact(base_path: str, artifact_key: str) -> None:
+    if len(files := build_output_files(base_path)) == 0:
+        return
+    makedirs(data_path(ARTIFACT_CACHE_DIR_NAME), exist_ok=True)
+    artifact: str = data_path(ARTIFACT_CACHE_DIR_NAME, f"{artifact_key}.tar")
+    tmp_file: str = f"{artifact}.tmp-{token_hex(4)}"
+    with tarfile.open(tmp_file, "w:") as tar:
+        for file_path in files:
+            tar.add(path.join(base_path, file_path), arcname=file_path, recursive=False)
+    replace(tmp_file, artifact)
+
+
+def load_build_progress(nupm_home: str) -> Dict[str, str]:
+    # builds, which finished during a failed or interrupted build into nupm_home (base_path -> build_stamp)
+    return load_json_state(data_path(BUILD_PROGRESS_FILE_NAME), {}).get(path.abspath(nupm_home)) or {}
+
+
+def save_build_progress(nupm_home: str, build_stamps: Optional[Dict[str, str]]) -> None:
+    # build_stamps None -> the build finished (nothing to resume)
+    progress: Dict[str, Any] = load_json_state(data_path(BUILD_PROGRESS_FILE_NAME), {})
+    if build_stamps is None and path.abspath(nupm_home) not in progress:
+        return
+    if build_stamps is None:
+        del progress[path.abspath(nupm_home)]
+    else:
+        progress[path.abspath(nupm_home)] = build_stamps
+    save_json_state(data_path(BUILD_PROGRESS_FILE_NAME), progress)
+
+
+def load_shown_messages(numng_file_path: str) -> Dict[str, str]:
+    # post_install_messages, which were already printed for the package file (package name -> hash)
+    return load_json_state(data_path(SHOWN_MESSAGES_FILE_NAME), {}).get(path.abspath(numng_file_path)) or {}
+
+
+def save_shown_messages(numng_file_path: str, messages: Dict[str, str]) -> None:
+    shown: Dict[str, Any] = load_json_state(data_path(SHOWN_MESSAGES_FILE_NAME), {})
+    shown[path.abspath(numng_file_path)] = messages
+    save_json_state(data_path(SHOWN_MESSAGES_FILE_NAME), shown)
+
+
+def load_environment_state(nupm_home: str) -> Optional[Dict[str, Any]]:
+    # what the last successful build installed into nupm_home
+    if not path.isfile(state_file := path.join(nupm_home, STATE_FILE_NAME)):
+        return None
+    with open(state_file, "r") as fp:
+        state = json.load(fp)
+    assert isinstance(state, dict), f"Invalid numng state file {state_file} (not a dict)"
+    return state
+
+
+def load_known_environments() -> Dict[str, Dict[str, Any]]:
+    # package file -> last build of it
+    if not path.isfile(environments_file := data_path(ENVIRONMENTS_FILE_NAME)):
+        return {}
+    # no fallback to {} (like load_json_state): gc would consider the whole store unused
+    try:
+        with open(environments_file, "r") as fp:
+            environments = json.load(fp)
+    except json.JSONDecodeError as exc:
+        raise AssertionError(f"Invalid environments file {environments_file} ({exc}). Fix or remove it (and rebuild all environments).")
+    assert isinstance(environments, dict), f"Invalid environments file {environments_file} (not a dict)"
+    return environments
+
+
+def record_environment(package_file: str, nupm_home: Optional[str], lockfile: Optional[str], store_paths: List[str]) -> None:
+    environments: Dict[str, Dict[str, Any]] = load_known_environments()
+    environments[package_file] = {
+        "nupm_home": path.abspath(nupm_home) if nupm_home is not None else None,
+        "lockfile": lockfile,
+        "build_time": time(),
+        "store_paths": sorted(set(store_paths)),
+    }
+    save_json_state(data_path(ENVIRONMENTS_FILE_NAME), environments, indent=4)
+
+
+def find_store_entries() -> Tuple[Dict[str, List[str]], List[str]]:
+    # (git repo -> checkouts of its refs, archives)
+    repos: Dict[str, List[str]] = {}
+    archives: List[str] = []
+    for root, dirs, _ in walk(data_path("store")):
+        if "__bare__" in dirs:
+            repos[root] = [path.join(root, i) for i in dirs if i != "__bare__"]
+            dirs.clear()
+        elif "__content__" in dirs:
+            archives.append(root)
+            dirs.clear()
+    return repos, archives
+
+
+def store_disk_usage() -> List[Dict[str, Any]]:
+    # size of every store entry, biggest first
+    repos, archives = find_store_entries()
+    store_dir: str = data_path("store")
+    entries: List[Dict[str, Any]] = [
+        *({"path": path.join(repo, "__bare__"), "type": "git_bare"} for repo in repos),
+        *({"path": i, "type": "git_checkout"} for checkouts in repos.values() for i in checkouts),
+        *({"path": i, "type": "archive"} for i in archives),
+    ]
+    for entry in entries:
+        entry["name"] = path.relpath(entry["path"], store_dir)
+        entry["size"] = directory_size(entry["path"])
+    return sorted(entries, key=lambda i: i["size"], reverse=True)
+
+
+def directory_size(dir_path: str) -> int:
+    size: int = 0
+    for root, _, files in walk(dir_path):
+        size += sum(lstat(path.join(root, i)).st_size for i in files)
+    return size
+
+
+def format_size(size: float) -> str:
+    for unit in ("B", "KiB", "MiB", "GiB"):
+        if size < 1024:
+            return f"{size:.1f}{unit}" if unit != "B" else f"{size:.0f}{unit}"
+        size /= 1024
+    return f"{size:.1f}TiB"
+
+
+def collect_garbage(dry_run: bool = False, min_age_days: float = 0.0) -> List[str]:
+    # removes git checkouts and archives from the store, which are not used by any known environment or its lockfile
+    environments: Dict[str, Dict[str, Any]] = load_known_environments()
+    assert len(environments) != 0, f"No known environments in {data_path(ENVIRONMENTS_FILE_NAME)} (build at least once before collecting garbage)"
+    for package_file in [i for i in environments if not path.isfile(i)]:
+        logger.info(f"forgetting the environment of {package_file} (the package file no longer exists)")
+        del environments[package_file]
+    used_paths: List[str] = []
+    for environment in environments.values():
+        used_paths.extend(environment.get("store_paths") or [])
+        if (lockfile := environment.get("lockfile")) is not None and path.isfile(lockfile):
+            with open(lockfile, "r") as fp:
+                lock_data: Dict[str, Any] = json.load(fp)
+            for url, refs in (lock_data.get("git") or {}).items():
+                used_paths.extend(get_git_ref_path(url, ref) for ref in refs)
+            used_paths.extend(get_archive_store_path(url) for url in lock_data.get("archive") or {})
+
+    repos, archives = find_store_entries()
+    max_mtime: float = time() - min_age_days * 86400
+    removed: List[str] = []
+    for unit in [*(i for checkouts in repos.values() for i in checkouts), *archives]:
+        if any(i == unit or i.startswith(unit + path.sep) for i in used_paths):
+            continue
+        # git updates the .git directory on fetch/reset, while the checkout directory itself rarely changes
+        if max(os_stat(i).st_mtime for i in (unit, path.join(unit, ".git")) if path.exists(i)) > max_mtime:
+            continue
+        removed.append(unit)
+    for repo, checkouts in repos.items():
+        if all(i in removed for i in checkouts):
+            removed = [i for i in removed if i not in checkouts]
+            removed.append(repo)
+    if path.isdir(artifact_cache_dir := data_path(ARTIFACT_CACHE_DIR_NAME)):
+        # artifacts aren't tied to environments -> removed once they weren't used for a while
+        max_artifact_mtime: float = time() - max(min_age_days, ARTIFACT_CACHE_MAX_AGE_DAYS) * 86400
+        removed.extend(i for i in (path.join(artifact_cache_dir, j) for j in sorted(listdir(artifact_cache_dir))) if os_stat(i).st_mtime <= max_artifact_mtime)
+
+    if not dry_run:
+        for unit in removed:
+            logger.debug(f"gc: removing {unit}")
+            if path.isdir(unit):
+                rmtree(unit)
+            else:
+                unlink(unit)
+        for repo in repos:
+            if path.isdir(bare_path := path.join(repo, "__bare__")):
+                run_command(["git", "worktree", "prune"], cwd=bare_path, stdout=subprocess.DEVNULL)
+        save_json_state(data_path(ENVIRONMENTS_FILE_NAME), environments, indent=4)
+    return removed
+
+
+def verify_store(repair: bool = False) -> List[str]:
+    # finds corrupted git repos, broken checkouts, incomplete archives, and dangling links in known environments
+    problems: List[str] = []
+    repos, archives = find_store_entries()
+    for repo, checkouts in repos.items():
+        bare_path: str = path.join(repo, "__bare__")
+        url_proc = run_command(["git", "config", "--get", "remote.origin.url"], cwd=bare_path, stdout=subprocess.PIPE, stderr=subprocess.DEVNULL)
+        url: Optional[str] = url_proc.stdout.decode(encoding="utf-8").strip() if url_proc.returncode == 0 else None
+        # the checkout directories are filesystem_safe versions of the refs (`release/1.x` -> `release_1.x`)
+        refs: Dict[str, str] = {**(locked_git_refs(url) if url is not None else {}), **load_git_refs(repo)}
+        ref_names: List[str] = [refs.get(path.basename(i), path.basename(i)) for i in checkouts]
+        if run_command(["git", "fsck", "--no-progress", "--connectivity-only"], cwd=bare_path, stdout=subprocess.DEVNULL, stderr=subprocess.DEVNULL).returncode != 0:
+            problems.append(f"corrupted git repo {bare_path}")
+            if repair and url is None:
+                problems.append(f"unable to repair {bare_path} (unknown remote url). Remove {repo} and rebuild instead.")
+            elif repair:
+                logger.info(f"re-cloning {url}")
+                if (error := recreate_store_entry(repo, lambda: [get_git_ref_path(url, ref, download=True) for ref in ref_names])) is not None:
+                    problems.append(f"unable to re-clone {url} (kept the old copy): {error}")
+            continue
+        for checkout in checkouts:
+            if run_command(["git", "rev-parse", "HEAD"], cwd=checkout, stdout=subprocess.DEVNULL, stderr=subprocess.DEVNULL).returncode == 0:
+                continue
+            problems.append(f"broken git checkout {checkout}")
+            if repair and url is None:
+                problems.append(f"unable to repair {checkout} (unknown remote url). Remove {repo} and rebuild instead.")
+            elif repair:
+                logger.info(f"re-creating the checkout {checkout}")
+                ref: str = refs.get(path.basename(checkout), path.basename(checkout))
+
+                def recreate_checkout() -> None:
+                    run_command(["git", "worktree", "prune"], cwd=bare_path, stdout=subprocess.DEVNULL)
+                    get_git_ref_path(url, ref, download=True)
+                if (error := recreate_store_entry(checkout, recreate_checkout)) is not None:
+                    problems.append(f"unable to re-create {checkout} (kept the old copy): {error}")
+    for archive in archives:
+        if len(listdir(archive)) == 2 and len(listdir(path.join(archive, "__content__"))) != 0:
+            continue
+        problems.append(f"incomplete archive {archive}")
+        if repair:
+            logger.info(f"removing {archive} (it gets downloaded again by the next build)")
+            rmtree(archive)
+    # links get repaired together with their target (the links themselves only point into the store)
+    problems.extend(
+        f"dangling link {link} -> {readlink(link)} (rebuild {package_file} if --repair doesn't fix it)"
+        for package_file, link in find_dangling_links()
+    )
+    return problems
+
+
+def recreate_store_entry(entry: str, recreate: Callable[[], Any]) -> Optional[str]:
+    # the broken entry is only moved aside until its replacement is complete -> a failed download (offline, ..) doesn't lose it
+    old_entry: str = f"{entry}.old-{token_hex(4)}"
+    rename(entry, old_entry)
+    try:
+        recreate()
+    except (AssertionError, OSError) as exc:
+        if path.lexists(entry):
+            rmtree(entry)
+        rename(old_entry, entry)
+        return str(exc)
+    rmtree(old_entry)
+    return None
+
+
+def locked_git_refs(url: str) -> Dict[str, str]:
+    # checkout directory -> git ref according to the lockfiles of known environments (for checkouts without a __refs__.json entry)
+    refs: Dict[str, str] = {}
+    for environment in load_known_environments().values():
+        if (lockfile := environment.get("lockfile")) is None or not path.isfile(lockfile):
+            continue
+        with open(lockfile, "r") as fp:
+            lock_data: Any = json.load(fp)
+        refs.update((filesystem_safe(i), i) for i in ((lock_data.get("git") or {}).get(url) or {}))
+    return refs
+
+
+def find_dangling_links() -> List[Tuple[str, str]]:
+    # (package file, link) for every broken link in the nupm_home of known environments
+    result: List[Tuple[str, str]] = []
+    for package_file, environment in load_known_environments().items():
+        if (nupm_home := environment.get("nupm_home")) is None or not path.isdir(nupm_home):
+            continue
+        for root, dirs, files in walk(nupm_home):
+            for i in [*dirs, *files]:
+                if is_link(link := path.join(root, i)) and not path.exists(link):
+                    result.append((package_file, link))
+    return result
+
+
+def run_package_tests(numng_file_path: str, overlay_file: str) -> List[Tuple[str, bool]]:
+    # test_command of the package, nupm-style tests (every command exported by tests/mod.nu is a test), or `cargo test`
+    # -> [(test name, passed)]
+    package_dir: str = path.dirname(numng_file_path)
+    test_command: Any = read_package_file(numng_file_path)[0].get("test_command")
+    assert test_command is None or isinstance(test_command, str), "Invalid numng.json (test_command is not a string)"
+    overlay: str = f"overlay use {json.dumps(overlay_file)}"
+    if test_command is None and path.isfile(path.join(package_dir, "tests", "mod.nu")):
+        tests_output: Optional[str] = command_output(
+            [nu_binary(), "--no-config-file", "-c", f'{overlay}; use tests/; scope commands | where name starts-with "tests " | get name | to json'],
+            cwd=package_dir,
+        )
+        assert tests_output is not None, "Failed to list the tests in tests/mod.nu"
+        results: List[Tuple[str, bool]] = []
+        for test in json.loads(tests_output):
+            logger.debug(f"running test {test}")
+            test_proc = run_command([nu_binary(), "--no-config-file", "-c", f"{overlay}; use tests/; {test}"], cwd=package_dir)
+            results.append((test[len("tests "):], test_proc.returncode == 0))
+        return results
+    if test_command is None and path.isfile(path.join(package_dir, "Cargo.toml")):
+        test_command = "cargo test"
+    assert test_command is not None, "The package has no tests (add a test_command, tests/mod.nu, or a Cargo.toml)"
+    logger.debug(f"running test_command: {test_command}")
+    test_proc = run_command([nu_binary(), "--no-config-file", "-c", f"{overlay}; {test_command}"], cwd=package_dir)
+    return [(test_command, test_proc.returncode == 0)]
+
+
+def run_doctor() -> List[Tuple[bool, str]]:
+    # (ok, message) for every check. messages of failed checks contain a fix
+    results: List[Tuple[bool, str]] = []
+    if which("git") is None:
+        results.append((False, "git is not installed (install it using your system package manager)"))
+    else:
+        git_version_proc = run_command(["git", "--version"], stdout=subprocess.PIPE, stderr=subprocess.DEVNULL)
+        git_version: str = (git_version_proc.stdout.decode(encoding="utf-8").split() + ["", "", "unknown"])[2]  # "git version 2.39.3 (Apple Git-145)"
+        if SemVer(git_version) >= SemVer("2.17.0"):
+            results.append((True, f"git {git_version}"))
+        else:
+            results.append((False, f"git {git_version} is too old (numng needs at least 2.17 for worktrees and shallow fetches)"))
+    if (nu_version := get_installed_nu_version()) is None:
+        results.append((False, f"{nu_binary()} --version failed (install nushell or set nu_binary in {config_path(CONFIG_FILE_NAME)})"))
+    else:
+        results.append((True, f"nushell {nu_version} ({which(nu_binary()) or nu_binary()})"))
+    try:
+        apply_environment_variables(load_config())
+        results.append((True, f"config ({config_path(CONFIG_FILE_NAME) if path.isfile(config_path(CONFIG_FILE_NAME)) else 'no config file'})"))
+    except (AssertionError, json.JSONDecodeError, TypeError) as exc:
+        results.append((False, f"invalid config: {exc} (fix {config_path(CONFIG_FILE_NAME)} or your NUMNG_* environment variables)"))
+    base_directory: str = data_path()
+    try:
+        makedirs(base_directory, exist_ok=True)
+        with TemporaryDirectory(dir=base_directory) as tmpdir:
+            results.append((True, f"data directory {base_directory} is writable"))
+            try:
+                create_symlink(tmpdir, path.join(tmpdir, "link"))
+                results.append((True, "links can be created"))
+            except OSError as exc:
+                results.append((False, f"unable to create links in {base_directory}: {exc} (on windows enable the developer mode)"))
+    except OSError as exc:
+        results.append((False, f"data directory {base_directory} is not writable: {exc} (fix its permissions or set NUMNG_DATA_DIR)"))
+    if (lock_pid := get_store_lock_holder()) is not None:
+        results.append((False, f"the store is locked by process {lock_pid} (wait for it to finish or kill it if it hangs)"))
+    else:
+        results.append((True, "the store is not locked"))
+    dangling_links: List[Tuple[str, str]] = find_dangling_links()
+    results.extend((False, f"dangling link {link} (run `numng store verify --repair` or rebuild {package_file})") for package_file, link in dangling_links)
+    if len(dangling_links) == 0:
+        results.append((True, "no dangling links in known environments"))
+    return results
+
+
+def is_local_environment(package_file: str) -> bool:
+    # `"local_environment": true` in the base package
+    if not path.isfile(package_file):
+        return False
+    try:
+        package_json: Any = read_package_file(package_file)[0]
+    except AssertionError:
+        return False  # gets reported (with its location) once the package file gets loaded
+    return isinstance(package_json, dict) and package_json.get("local_environment") is True
+
+
+def extract_tar(tar: tarfile.TarFile, target_dir: str) -> None:
+    # `filter="data"` only exists since python 3.12 (backported to 3.8.17, 3.9.17, 3.10.12, 3.11.4)
+    # -> older versions get the important parts of it checked by hand (no paths or links out of target_dir, no devices)
+    if getattr(tarfile, "data_filter", None) is not None:
+        tar.extractall(target_dir, filter="data")
+        return
+    root: str = path.realpath(target_dir)
+    for member in tar.getmembers():
+        destination: str = path.realpath(path.join(root, member.name))
+        assert member.isfile() or member.isdir() or member.issym() or member.islnk(), f"Refusing to extract {member.name} (special file)"
+        assert not path.isabs(member.name) and path.commonpath([root, destination]) == root, f"Refusing to extract {member.name} (outside of {target_dir})"
+        if member.issym() or member.islnk():
+            link_target: str = path.join(path.dirname(destination) if member.issym() else root, member.linkname)
+            assert (
+                not path.isabs(member.linkname) and path.commonpath([root, path.realpath(link_target)]) == root
+            ), f"Refusing to extract {member.name} (links outside of {target_dir})"
+    tar.extractall(target_dir)
+
+
+def replace_directory(new_dir: str, target_dir: str) -> None:
+    # rename can't replace non-empty directories -> move the old one out of the way and remove it once the new one is in place
+    if not path.lexists(target_dir):
+        rename(new_dir, target_dir)
+        return
+    old_dir: str = f"{target_dir}.old-{token_hex(4)}"
+    rename(target_dir, old_dir)
+    try:
+        rename(new_dir, target_dir)
+    except OSError:
+        rename(old_dir, target_dir)
+        raise
+    rmtree(old_dir)
+
+
+def get_git_commit(ref_path: str) -> str:
+    commit: Optional[str] = command_output(["git", "rev-parse", "HEAD"], cwd=ref_path)
+    assert commit is not None, f"Failed to get the current commit of {ref_path}"
+    return commit.strip()
+
+
+def git_checkout_commit(url: str, ref_path: str, commit: str) -> None:
+    r = run_command(["git", "reset", "--hard", "--quiet", commit], cwd=ref_path, stdout=subprocess.DEVNULL, stderr=subprocess.DEVNULL)
+    if r.returncode == 0:
+        return
+    logger.debug(f"locked commit {commit} of {url} not available locally -> fetching it")
+    r = run_command(["git", "fetch", "--quiet", "--depth=1", "origin", commit], cwd=ref_path, stdout=subprocess.DEVNULL)
+    assert r.returncode == 0, f"Failed to fetch the locked commit {commit} of {url} (did the upstream force-push?)"
+    r = run_command(["git", "reset", "--hard", "--quiet", commit], cwd=ref_path, stdout=subprocess.DEVNULL)
+    assert r.returncode == 0, f"Failed to checkout the locked commit {commit} of {url}"
+
+
+def filesystem_safe(text: str) -> str:
+    return "".join((i if i in VALID_FILESYSTEM_CHARACTERS else "_" for i in text))
+
+
+def verify_lockfile_in_store(lock_data: Dict[str, Any]) -> List[str]:
+    # is everything required for a locked offline build in the store?
+    problems: List[str] = []
+    for url, refs in (lock_data.get("git") or {}).items():
+        bare_path: str = path.join(get_git_store_path(url), "__bare__")
+        if not path.isdir(bare_path):
+            problems.append(f"{url} is not in the store")
+            continue
+        for ref, commit in refs.items():
+            if run_command(["git", "cat-file", "-e", f"{commit}^{{commit}}"], cwd=bare_path, stderr=subprocess.DEVNULL).returncode != 0:
+                problems.append(f"commit {commit} ({ref}) of {url} is not in the store")
+    for url, sha256 in (lock_data.get("archive") or {}).items():
+        archive_path: str = path.join(get_archive_store_path(url), filesystem_safe(url.rsplit("/", 1)[1]))
+        if not path.isfile(archive_path):
+            problems.append(f"{url} is not in the store")
+        elif file_sha256(archive_path) != sha256:
+            problems.append(f"sha256 mismatch for the archive of {url} (expected {sha256})")
+    return problems
+
+
+def export_bundle(package_file: str, lockfile: str, bundle_file: str) -> Dict[str, Any]:
+    # single archive with everything required to build the locked environment without network access:
+    # bundle.json, numng.json, numng.lock.json, and the store entries (bare git repos and archives) in their store layout
+    with open(lockfile, "r") as fp:
+        lock_data: Dict[str, Any] = json.load(fp)
+    problems: List[str] = verify_lockfile_in_store(lock_data)
+    assert len(problems) == 0, "Unable to export a bundle (run `numng build --locked` first):\n" + "\n".join(problems)
+
+    def exclude_worktrees(tarinfo: tarfile.TarInfo) -> Optional[tarfile.TarInfo]:
+        # worktree metadata contains absolute paths -> they get re-created on import
+        parts: List[str] = tarinfo.name.split("/")
+        return None if "__bare__" in parts and parts[parts.index("__bare__") + 1:][:1] == ["worktrees"] else tarinfo
+
+    manifest: bytes = json.dumps({"version": BUNDLE_FORMAT_VERSION, "created": time()}, indent=4).encode(encoding="utf-8")
+    with tarfile.open(bundle_file + ".part", "w:gz") as tar:
+        manifest_info = tarfile.TarInfo("bundle.json")
+        manifest_info.size = len(manifest)
+        manifest_info.mtime = int(time())
+        tar.addfile(manifest_info, io.BytesIO(manifest))
+        # as json with the includes merged into it (the included files aren't part of the bundle)
+        package_json: bytes = json.dumps(read_package_file(package_file)[0], indent=4).encode(encoding="utf-8")
+        package_info = tarfile.TarInfo("numng.json")
+        package_info.size = len(package_json)
+        package_info.mtime = int(time())
+        tar.addfile(package_info, io.BytesIO(package_json))
+        tar.add(lockfile, arcname="numng.lock.json")
+        for url in lock_data.get("git") or {}:
+            bare_path: str = path.join(get_git_store_path(url), "__bare__")
+            tar.add(bare_path, arcname=path.relpath(bare_path, data_path()).replace(path.sep, "/"), filter=exclude_worktrees)
+        for url in lock_data.get("archive") or {}:
+            archive_path: str = path.join(get_archive_store_path(url), filesystem_safe(url.rsplit("/", 1)[1]))
+            tar.add(archive_path, arcname=path.relpath(archive_path, data_path()).replace(path.sep, "/"))
+    replace(bundle_file + ".part", bundle_file)
+    return {"file": path.abspath(bundle_file), "git": len(lock_data.get("git") or {}), "archive": len(lock_data.get("archive") or {})}
+
+
+def migrate_packer_config(packer_file: str) -> Tuple[List[Dict[str, Any]], List[str]]:
+    # best effort: packer.nu configs are nu code -> only `(p SOURCE --flags)` entries are understood
+    # returns (numng dependencies, warnings)
+    with open(packer_file, "r") as fp:
+        packer_config: str = fp.read()
+    depends: List[Dict[str, Any]] = [{"name": "packer.nu", "source_uri": "https://github.com/jan9103/packer.nu"}]
+    problems: List[str] = []
+    for line_number, line in enumerate(packer_config.splitlines(), start=1):
+        for match in re.finditer(r"\(\s*p\s+([^)]*)\)", line.split("#", 1)[0]):
+            try:
+                source, *flags = shlex.split(match.group(1))
+            except ValueError:
+                problems.append(f"line {line_number}: unable to parse `{match.group(0)}`")
+                continue
+            if "://" not in source:
+                source = f"https://github.com/{source.removeprefix('github:')}"
+            package: Dict[str, Any] = {
+                "name": "/".join(source.removesuffix(".git").rstrip("/").split("/")[-2:]),
+                "source_uri": source.removesuffix(".git") if source.startswith("https://github.com/") else source,
+                "package_format": "packer",
+            }
+            disabled: bool = False
+            while flags:
+                flag: str = flags.pop(0)
+                if flag in ("-b", "--branch", "-t", "--tag") and flags:
+                    package["git_ref"] = flags.pop(0)
+                elif flag.startswith(("--branch=", "--tag=")):
+                    package["git_ref"] = flag.split("=", 1)[1]
+                elif flag in ("-d", "--disabled"):
+                    disabled = True
+                else:
+                    problems.append(f"line {line_number}: unsupported option `{flag}` of {package['name']} (ignored)")
+            if disabled:
+                problems.append(f"line {line_number}: {package['name']} is disabled (not migrated)")
+                continue
+            depends.append(package)
+    if len(depends) == 1:
+        problems.append(f"no packages found in {packer_file} (expected entries like `(p jan9103/nutils)`)")
+    return depends, problems
+
+
+def migrate_nupm_home(nupm_home: str) -> Tuple[List[Dict[str, Any]], List[str]]:
+    # nupm only keeps the installed files -> the packages have to be resolved by name using the registries
+    # returns (numng dependencies, warnings)
+    assert path.isdir(nupm_home), f"{nupm_home} does not exist"
+    assert not path.isfile(path.join(nupm_home, STATE_FILE_NAME)), f"{nupm_home} was created by numng"
+    depends: List[Dict[str, Any]] = []
+    problems: List[str] = []
+    names: List[str] = []
+    if path.isdir(modules_dir := path.join(nupm_home, "modules")):
+        names.extend(i for i in sorted(listdir(modules_dir)) if path.isdir(path.join(modules_dir, i)))
+    if path.isdir(scripts_dir := path.join(nupm_home, "scripts")):
+        names.extend(i.removesuffix(".nu") for i in sorted(listdir(scripts_dir)) if i.endswith(".nu") and i.removesuffix(".nu") not in names)
+    for name in names:
+        depends.append({"name": name})
+    for directory in ("bin", "plugins"):
+        if path.isdir(dir_path := path.join(nupm_home, directory)) and (files := sorted(listdir(dir_path))):
+            problems.append(f"{directory}/ contains {', '.join(files)} (can't tell which package installed them -> check that they are part of a migrated package)")
+    if len(names) == 0:
+        problems.append(f"no packages found in {nupm_home} (expected modules/ or scripts/)")
+    else:
+        problems.append(f"versions and sources are unknown -> the {len(names)} packages get resolved from the registries (add a `source_uri` to the ones not in a registry)")
+    return depends, problems
+
+
+def vendor_sources(sources: Dict[str, Dict[str, Any]], vendor_dir: str) -> Dict[str, Dict[str, Any]]:
+    # only the committed files (`git archive`) -> no .git directories or build output in the project tree
+    if path.exists(vendor_dir):
+        assert path.isfile(path.join(vendor_dir, "numng-vendor.json")), f"{vendor_dir} already exists and was not created by `numng vendor`"
+    store_dir: str = data_path("store")
+    tmp_vendor_dir: str = f"{vendor_dir}.tmp-{token_hex(4)}"
+    manifest: Dict[str, Dict[str, Any]] = {}
+    try:
+        for key, source in sources.items():
+            relative_path: str = path.relpath(source["path"], store_dir)
+            dst: str = path.join(tmp_vendor_dir, relative_path)
+            logger.debug(f"vendoring {key} into {dst}")
+            makedirs(dst)
+            if source["type"] == "git":
+                archive_proc = run_command(["git", "archive", "--format=tar", source["commit"]], cwd=source["path"], stdout=subprocess.PIPE)
+                assert archive_proc.returncode == 0, f"Failed to export {source['url']} {source['ref']} ({source['commit']})"
+                with tarfile.open(fileobj=io.BytesIO(archive_proc.stdout), mode="r:") as tar:
+                    extract_tar(tar, dst)
+                manifest[key] = {"path": relative_path.replace(path.sep, "/"), "commit": source["commit"]}
+            else:
+                copytree(source["path"], dst, symlinks=True, dirs_exist_ok=True)
+                manifest[key] = {"path": relative_path.replace(path.sep, "/"), "sha256": source["sha256"]}
+        with open(path.join(tmp_vendor_dir, "numng-vendor.json"), "w") as fp:
+            json.dump(manifest, fp, indent=4)
+    except BaseException:
+        if path.exists(tmp_vendor_dir):
+            rmtree(tmp_vendor_dir)
+        raise
+    replace_directory(tmp_vendor_dir, vendor_dir)
+    return manifest
+
+
+def import_bundle(bundle_file: str, target_dir: str) -> Dict[str, Any]:
+    # seeds the store from a `numng bundle export` file -> `numng build --offline --locked` works afterwards
+    with TemporaryDirectory(dir=data_path()) as tmpdir, tarfile.open(bundle_file, "r:*") as tar:
+        for member in tar.getmembers():
+            assert (
+                member.name in ("bundle.json", "numng.json", "numng.lock.json") or member.name.startswith("store/")
+            ) and ".." not in member.name.split("/") and not path.isabs(member.name), f"Invalid bundle {bundle_file} (unexpected file {member.name})"
+        extract_tar(tar, tmpdir)
+        assert path.isfile(manifest_path := path.join(tmpdir, "bundle.json")), f"Invalid bundle {bundle_file} (missing bundle.json)"
+        with open(manifest_path, "r") as fp:
+            manifest: Dict[str, Any] = json.load(fp)
+        assert manifest.get("version") == BUNDLE_FORMAT_VERSION, f"Unsupported bundle version {manifest.get('version')} (update numng)"
+        with open(path.join(tmpdir, "numng.lock.json"), "r") as fp:
+            lock_data: Dict[str, Any] = json.load(fp)
+
+        for file_name in ("numng.json", "numng.lock.json"):
+            dst: str = path.join(target_dir, file_name)
+            if path.exists(dst):
+                assert file_sha256(dst) == file_sha256(path.join(tmpdir, file_name)), f"{dst} already exists (import into a different directory)"
+                continue
+            makedirs(target_dir, exist_ok=True)
+            copy2(path.join(tmpdir, file_name), dst)
+
+        for url, refs in (lock_data.get("git") or {}).items():
+            repo: str = get_git_store_path(url)
+            bare_path: str = path.join(repo, "__bare__")
+            imported_bare_path: str = path.join(tmpdir, path.relpath(bare_path, data_path()))
+            if not path.isdir(bare_path):
+                makedirs(repo, exist_ok=True)
+                move(imported_bare_path, bare_path)
+            else:
+                # git objects are content addressed -> copying them into the existing repo can't break it
+                logger.debug(f"merging the bundled objects of {url} into the store")
+                for root, _, files in walk(path.join(imported_bare_path, "objects")):
+                    dst_dir: str = path.join(bare_path, path.relpath(root, imported_bare_path))
+                    makedirs(dst_dir, exist_ok=True)
+                    for file in files:
+                        if not path.exists(dst_file := path.join(dst_dir, file)):
+                            copy2(path.join(root, file), dst_file)
+                shallow: List[str] = []
+                for shallow_file in (path.join(bare_path, "shallow"), path.join(imported_bare_path, "shallow")):
+                    if path.isfile(shallow_file):
+                        with open(shallow_file, "r") as fp:
+                            shallow.extend(i for i in fp.read().split() if i not in shallow)
+                if shallow:
+                    with open(path.join(bare_path, "shallow"), "w") as fp:
+                        fp.write("\n".join(shallow) + "\n")
+            run_command(["git", "worktree", "prune"], cwd=bare_path, stdout=subprocess.DEVNULL)
+            for ref, commit in refs.items():
+                if path.exists(ref_path := path.join(repo, filesystem_safe(ref))):
+                    continue  # a locked build resets it to the locked commit
+                worktree_result = run_command(["git", "worktree", "add", "--quiet", "--detach", ref_path, commit], cwd=bare_path, stdout=subprocess.DEVNULL)
+                assert worktree_result.returncode == 0, f"Failed to add a git worktree for {ref} of {url}"
+                remember_git_ref(repo, ref)
+
+        for url, sha256 in (lock_data.get("archive") or {}).items():
+            archive_path: str = path.join(get_archive_store_path(url), filesystem_safe(url.rsplit("/", 1)[1]))
+            if path.isfile(archive_path) and file_sha256(archive_path) == sha256:
+                continue
+            makedirs(path.dirname(archive_path), exist_ok=True)
+            move(path.join(tmpdir, path.relpath(archive_path, data_path())), archive_path)
+            if path.exists(content_path := path.join(path.dirname(archive_path), "__content__")):
+                rmtree(content_path)  # gets re-extracted during the next build
+
+    problems: List[str] = verify_lockfile_in_store(lock_data)
+    assert len(problems) == 0, "The bundle is incomplete:\n" + "\n".join(problems)
+    return {"package_file": path.abspath(path.join(target_dir, "numng.json")), "git": len(lock_data.get("git") or {}), "archive": len(lock_data.get("archive") or {})}
+
+
+def get_archive_store_path(url: str) -> str:
+    assert "://" in url, f"Invalid archive url (missing ://): {url}"
+    return data_path(
+        "store", "archive",
+        *(filesystem_safe(i) for i in url.split("://", 1)[1].split("/")),
+    )
+
+
+def get_archive_path(url: str, sha256: Optional[str] = None, update: bool = False, offline: bool = False) -> Tuple[str, str]:
+    base_path = get_archive_store_path(url)
+    # the file-extension is required for unpack_archive to detect the format
+    archive_path = path.join(base_path, filesystem_safe(url.rsplit("/", 1)[1]))
+    content_path = path.join(base_path, "__content__")
+
+    if offline and not path.exists(archive_path):
+        raise OfflineError(f"{url} is not in the store (unable to download it in offline mode)")
+    if update or not path.exists(archive_path):
+        logger.debug(f"downloading archive {url}")
+        makedirs(base_path, exist_ok=True)
+        download_file(url, archive_path + ".part")
+        observed_sha256 = file_sha256(archive_path + ".part")
+        if sha256 is not None and observed_sha256 != sha256.lower():
+            unlink(archive_path + ".part")
+            raise AssertionError(f"sha256 mismatch for {url} (expected {sha256}, got {observed_sha256})")
+        move(archive_path + ".part", archive_path)
+        if path.exists(content_path):
+            rmtree(content_path)
+    else:
+        # the cached archive is kept to be able to re-verify it without a download
+        observed_sha256 = file_sha256(archive_path)
+        assert sha256 is None or observed_sha256 == sha256.lower(), f"sha256 mismatch for the cached archive of {url} (expected {sha256}, got {observed_sha256}). Use --pull-updates to download it again."
+
+    if not path.exists(content_path):
+        logger.debug(f"extracting archive {url}")
+        unpack_archive(archive_path, content_path)
+    return content_path, observed_sha256
+
+
+def create_symlink(src: str, dst: str) -> None:
+    if platform != "win32":
+        symlink(src=src, dst=dst)
+        return
+    # symlinks require admin rights or developer mode on windows, directory junctions don't
+    src, dst = windows_long_path(src), windows_long_path(dst)
+    if path.isdir(src):
+        import _winapi
+        _winapi.CreateJunction(src, dst)
+        return
+    try:
+        symlink(src=src, dst=dst)
+    except OSError:
+        logger.debug(f"unable to symlink {src} (missing privileges?) -> falling back to a hardlink or copy")
+        try:
+            link(src, dst)  # only works within the same volume
+        except OSError:
+            copy2(src, dst)
+
+
+def is_link(file_path: str) -> bool:
+    if path.islink(file_path):
+        return True
+    if platform != "win32":
+        return False
+    if hasattr(path, "isjunction"):  # python 3.12+
+        return path.isjunction(file_path)
+    try:
+        return lstat(file_path).st_reparse_tag == stat.IO_REPARSE_TAG_MOUNT_POINT
+    except OSError:
+        return False
+
+
+def remove_link(file_path: str) -> None:
+    if platform == "win32" and path.isdir(file_path):
+        rmdir(file_path)  # junctions and directory symlinks
+    else:
+        unlink(file_path)
+
+
+def windows_long_path(file_path: str) -> str:
+    # windows paths are limited to 260 characters unless prefixed with \\?\
+    file_path = path.abspath(file_path)
+    if len(file_path) < 260 or file_path.startswith("\\\\?\\"):
+        return file_path
+    return "\\\\?\\" + file_path
+
+
+def copy_path_cow(src: str, dst: str) -> None:
+    # reflink (copy-on-write) where the filesystem supports it (btrfs, xfs, apfs, ..) -> copy semantics at almost the cost of a symlink
+    cow_command: Optional[List[str]] = {
+        "linux": ["cp", "-R", "--reflink=auto", src, dst],  # falls back to a normal copy by itself
+        "darwin": ["cp", "-R", "-c", src, dst],  # clonefile (fails outside of apfs)
+    }.get(platform)
+    if cow_command is not None and which("cp") is not None:
+        if run_command(cow_command, stdout=subprocess.DEVNULL, stderr=subprocess.DEVNULL).returncode == 0:
+            return
+        logger.debug(f"copy-on-write copy of {src} failed -> falling back to a normal copy")
+        if path.isdir(dst) and not path.islink(dst):
+            rmtree(dst)
+        elif path.lexists(dst):
+            unlink(dst)
+    if path.isdir(src):
+        copytree(src, dst, symlinks=True)
+    else:
+        copy2(src, dst)
+
+
+def file_sha256(file_path: str) -> str:
+    file_hash = hashlib.sha256()
+    with open(file_path, "rb") as fp:
+        for chunk in iter(lambda: fp.read(8192), b""):
+            file_hash.update(chunk)
+    return file_hash.hexdigest()
+
+
+def directory_sha256(dir_path: str) -> str:
+    # hash of all file paths + contents (and symlink targets) within the directory, ignoring `.git` and local environments
+    dir_hash = hashlib.sha256()
+    for root, dirs, files in walk(dir_path):
+        dirs[:] = sorted(i for i in dirs if i not in (".git", LOCAL_ENVIRONMENT_DIR_NAME))
+        for file_name in sorted(files):
+            file_path: str = path.join(root, file_name)
+            dir_hash.update(path.relpath(file_path, dir_path).encode(encoding="utf-8") + b"\0")
+            if path.islink(file_path):
+                dir_hash.update(readlink(file_path).encode(encoding="utf-8"))
+            elif path.isfile(file_path):
+                dir_hash.update(file_sha256(file_path).encode(encoding="utf-8"))
+            dir_hash.update(b"\0")
+    return dir_hash.hexdigest()
+
+
+def update_check_file(package_file: str) -> str:
+    return data_path("update_checks", hashlib.sha256(package_file.encode(encoding="utf-8")).hexdigest()[:16] + ".json")
+
+
+def check_for_updates(lockfile: str) -> List[str]:
+    # compares the locked commits with the remote ones (without fetching anything)
+    with open(lockfile, "r") as fp:
+        lock_data: Dict[str, Any] = json.load(fp)
+    outdated: List[str] = []
+    for url, refs in (lock_data.get("git") or {}).items():
+        for ref, commit in refs.items():
+            remote_commit: Optional[str] = get_remote_git_commit(url, ref)
+            if remote_commit is not None and remote_commit != commit:  # commit hashes as ref can't be outdated
+                outdated.append(f"{url} {ref}")
+    return outdated
+
+
+def get_remote_git_commit(url: str, ref: str) -> Optional[str]:
+    # commit of a branch or tag (without fetching anything), None if unknown
+    ls_remote_proc = run_command(["git", "ls-remote", url, ref], stdout=subprocess.PIPE, stderr=subprocess.DEVNULL)
+    if ls_remote_proc.returncode != 0:
+        logger.debug(f"git ls-remote failed for {url} {ref}")
+        return None
+    remote_refs: Dict[str, str] = {
+        line.split("\t", 1)[1]: line.split("\t", 1)[0]
+        for line in ls_remote_proc.stdout.decode(encoding="utf-8").splitlines() if "\t" in line
+    }
+    # peeled tags ("^{}") point at the commit instead of the tag object
+    return next((remote_refs[i] for i in (f"refs/tags/{ref}^{{}}", f"refs/tags/{ref}", f"refs/heads/{ref}", ref) if i in remote_refs), None)
+
+
+def update_notification_snippet(package_file: str) -> str:
+    # prints the result of the last check and starts a new background check if the last one is older than a day
+    check_file: str = update_check_file(package_file)
+    numng_command: str = " ".join(json.dumps(i) for i in [executable, path.realpath(__file__), "--package-file", package_file, "check-updates", "--background"])
+    return "\n".join([
+        "do --ignore-errors {",
+        f"    let check_file = {json.dumps(check_file)}",
+        "    let last_check = (if ($check_file | path exists) { open $check_file } else { {time: 0, outdated: [], notified: true} })",
+        "    if not $last_check.notified and ($last_check.outdated | is-not-empty) {",
+        "        print $\"numng: updates available for ($last_check.outdated | length) packages \\(numng build --pull-updates\\)\"",
+        "        $last_check | upsert notified true | save -f $check_file",
+        "    }",
+        f"    if ((date now | into int) / 1_000_000_000 - $last_check.time) > 86400 {{ ^{numng_command} }}",
+        "}",
+    ])
+
+
+def format_timings(report: BuildReport) -> str:
+    lines: List[str] = ["phase       duration"]
+    lines.extend(f"{name:<10}  {duration:>7.2f}s" for name, duration in report.timings.items())
+    lines.append("")
+    lines.append("   fetch    build  package")
+    # slowest first
+    for package in sorted(report.packages, key=lambda i: (i.fetch_duration or 0.0) + (i.build_duration or 0.0), reverse=True):
+        fetch: str = "-" if package.fetch_duration is None else f"{package.fetch_duration:.2f}s"
+        build: str = "-" if package.build_duration is None else f"{package.build_duration:.2f}s"
+        lines.append(f"{fetch:>8} {build:>8}  {package.name}")
+    return "\n".join(lines)
+
+
+def to_nuon(value: Any, indent: int = 0) -> str:
+    # json would be valid nuon as well, but this is easier to read
+    padding: str = "    " * indent
+    if isinstance(value, dict) and len(value) != 0:
+        return "{\n" + "\n".join(
+            f"{padding}    {key if all(i in VALID_FILESYSTEM_CHARACTERS and i not in ' .' for i in key) and key else json.dumps(key)}: {to_nuon(item, indent + 1)}"
+            for key, item in ((str(k), v) for k, v in value.items())
+        ) + f"\n{padding}}}"
+    if isinstance(value, (list, tuple)) and len(value) != 0:
+        return "[\n" + "\n".join(f"{padding}    {to_nuon(item, indent + 1)}" for item in value) + f"\n{padding}]"
+    if isinstance(value, dict):
+        return "{}"
+    if isinstance(value, (list, tuple)):
+        return "[]"
+    return json.dumps(value)
+
+
+class StructuredErrorHandler(logging.Handler):
+    # --json / --nuon: errors also get printed to stdout as {"error": MESSAGE, "code": "NUMNG-E..."} (+ details like the location)
+    # (the exception gets passed via `logger.error(.., extra={"numng_error": exc})`)
+    def __init__(self, output_format: str) -> None:
+        super().__init__(level=logging.ERROR)
+        self._output_format: str = output_format
+
+    def emit(self, record: logging.LogRecord) -> None:
+        error: Optional[BaseException] = getattr(record, "numng_error", None)
+        print_structured({**(error_to_json(error) if error is not None else {"code": "NUMNG-E000"}), "error": record.getMessage()}, self._output_format)
+
+
+def to_json_value(value: Any) -> Any:
+    # numngs own types (versions, packages, reports, ..) -> json compatible values (used for --json, --nuon, etc)
+    if isinstance(value, (SemVer, VersionReq)):
+        return value.text
+    if isinstance(value, Package):
+        return package_to_json(value)
+    if isinstance(value, Enum):
+        return value.value
+    if is_dataclass(value) and not isinstance(value, type):
+        return {i.name: to_json_value(getattr(value, i.name)) for i in fields(value)}
+    if isinstance(value, dict):
+        return {str(k): to_json_value(v) for k, v in value.items()}
+    if isinstance(value, (list, tuple)):
+        return [to_json_value(i) for i in value]
+    return value
+
+
+def print_structured(data: Any, output_format: str) -> None:
+    data = to_json_value(data)
+    print(to_nuon(data) if output_format == "nuon" else json.dumps(data, indent=4))
+
+
+def git_blob_sha1(data: bytes) -> str:
+    # the hash git (and the github api) uses for file contents
+    return hashlib.sha1(b"blob %d\0" % len(data) + data).hexdigest()
+
+
+def self_update(check_only: bool = False) -> bool:
+    # returns whether a update is (or was) available
+    from urllib.request import urlopen, Request
+    own_file: str = path.realpath(__file__)
+    assert not own_file.startswith(path.realpath(data_path()) + path.sep), "numng is managed as a numng package -> update it using `numng --nu-config build --pull-updates`"
+    with urlopen(Request(SELF_UPDATE_URL, headers={"Accept": "application/vnd.github+json"})) as r:
+        latest: Dict[str, Any] = json.load(r)
+    assert isinstance(latest, dict) and "sha" in latest and "download_url" in latest, "Unexpected response from the github api"
+    with open(own_file, "rb") as fp:
+        if git_blob_sha1(fp.read()) == latest["sha"]:
+            return False
+    if check_only:
+        return True
+    with urlopen(latest["download_url"]) as r:
+        new_content: bytes = r.read()
+    # the api and the raw download are different servers -> a checksum mismatch means something went wrong
+    assert git_blob_sha1(new_content) == latest["sha"], f"Checksum mismatch for the downloaded numng.py (expected {latest['sha']}, got {git_blob_sha1(new_content)})"
+    with open(own_file + ".new", "wb") as fp:
+        fp.write(new_content)
+    chmod(own_file + ".new", os_stat(own_file).st_mode)
+    replace(own_file + ".new", own_file)
+    return True
+
+
+def get_authorization_header(host: str, context: Optional["RunContext"] = None) -> Optional[str]:
+    # tokens are only requested once per run (token_command could be a password manager prompt)
+    # NEVER log the result
+    context = context or current_run_context()
+    if (credential := context.config.credentials.get(host)) is None:
+        return None
+    with context.credential_lock:
+        if host not in context.credential_cache:
+            token: Optional[str] = None
+            if "token_env" in credential:
+                token = environ.get(credential["token_env"]) or None
+                if token is None:
+                    logger.warning(f"No credentials for {host} (${credential['token_env']} is not set)")
+            else:
+                logger.debug(f"requesting credentials for {host} from {credential['token_command'][0]}")
+                token_proc = run_command(credential["token_command"], stdout=subprocess.PIPE, context=context)
+                if token_proc.returncode == 0:
+                    token = token_proc.stdout.decode(encoding="utf-8").strip() or None
+                else:
+                    logger.warning(f"No credentials for {host} (token_command failed with exit code {token_proc.returncode})")
+            context.credential_cache[host] = None if token is None else "Basic " + base64.b64encode(f"{credential.get('username') or 'oauth2'}:{token}".encode(encoding="utf-8")).decode(encoding="ascii")
+        return context.credential_cache[host]
+
+
+def apply_mirrors(url: str, context: Optional["RunContext"] = None) -> str:
+    # longest prefix wins
+    mirrors: Dict[str, str] = (context or current_run_context()).config.mirrors
+    for prefix in sorted(mirrors, key=len, reverse=True):
+        if url.startswith(prefix):
+            return mirrors[prefix] + url[len(prefix):]
+    return url
+
+
+def download_file(url: str, local_file: str, context: Optional["RunContext"] = None) -> None:
+    # urlopen respects http_proxy / https_proxy / no_proxy and SSL_CERT_FILE on its own
+    from urllib.request import urlopen, Request
+    from urllib.parse import urlsplit
+    context = context or current_run_context()
+    if context.cancellation is not None:
+        context.cancellation.check()
+    if (mirrored_url := apply_mirrors(url, context)) != url:
+        logger.debug(f"downloading {url} from the mirror {mirrored_url}")
+        url = mirrored_url
+    request = Request(url)
+    if (host := urlsplit(url).hostname) is not None and (header := get_authorization_header(host, context)) is not None:
+        # unredirected -> not sent to the CDN / storage host most release downloads redirect to
+        request.add_unredirected_header("Authorization", header)
+    with urlopen(request) as r:
+        with open(local_file, "wb") as fp:
+            for chunk in iter(lambda: r.read(8192), b""):
+                fp.write(chunk)
+
+
+# forge host -> url of a single file ({repo}: owner/name, {ref}: branch, tag, or commit)
+RAW_FILE_URLS: Dict[str, str] = {
+    "github.com": "https://raw.githubusercontent.com/{repo}/{ref}/{path}",
+    "gitlab.com": "https://gitlab.com/{repo}/-/raw/{ref}/{path}",
+    "codeberg.org": "https://codeberg.org/{repo}/raw/{ref}/{path}",
+}
+
+
+def raw_file_url(url: str, ref: str, file_path: str) -> Optional[str]:
+    scheme, _, rest = normalize_git_url(url).partition("://")
+    host, _, repo = rest.partition("/")
+    if scheme not in ("http", "https") or host not in RAW_FILE_URLS or "/" not in repo:
+        return None
+    return RAW_FILE_URLS[host].format(repo=repo, ref=ref, path=file_path.lstrip("/"))
+
+
+def fetch_raw_file(url: str, context: Optional["RunContext"] = None) -> Optional[str]:
+    # local copy of a single file (None if it does not exist)
+    # only used with commits as ref -> the result never changes (missing files get remembered as well)
+    from urllib.request import urlopen, Request
+    from urllib.error import HTTPError
+    from urllib.parse import urlsplit
+    cache_path: str = data_path(RAW_FILE_CACHE_DIR_NAME, hashlib.sha256(url.encode(encoding="utf-8")).hexdigest() + path.splitext(urlsplit(url).path)[1])
+    with get_named_lock(cache_path):
+        if path.isfile(cache_path):
+            return cache_path
+        if path.isfile(cache_path + ".missing"):
+            return None
+        request = Request(url)
+        if (host := urlsplit(url).hostname) is not None and (header := get_authorization_header(host, context)) is not None:
+            request.add_unredirected_header("Authorization", header)
+        logger.debug(f"downloading {url}")
+        makedirs(data_path(RAW_FILE_CACHE_DIR_NAME), exist_ok=True)
+        try:
+            with urlopen(request) as r:
+                content: bytes = r.read()
+        except HTTPError as exc:
+            if exc.code != 404:
+                raise
+            with open(cache_path + ".missing", "w"):
+                pass
+            return None
+        with open(cache_path + ".part", "wb") as fp:
+            fp.write(content)
+        replace(cache_path + ".part", cache_path)
+        return cache_path
+
+
+def get_installed_nu_version() -> Optional[str]:
+    try:
+        version_proc = run_command([nu_binary(), "--version"], stdout=subprocess.PIPE, stderr=subprocess.DEVNULL)
+    except OSError:
+        return None
+    if version_proc.returncode != 0:
+        return None
+    return version_proc.stdout.decode(encoding="utf-8").strip() or None
+
+
+def load_nuon(text: str) -> Any:
+    # nope im not writing a nuon parser for this - not interrested in "13kb" or whatever
+    to_json_proc = run_command(
+        [nu_binary(), "--no-config-file", "--stdin", "--commands", "$in | from nuon | to json"],
         stdout=subprocess.PIPE,
-        stdin=subprocess.PIPE,
+        input=text.encode(encoding="utf-8", errors="replace"),
     )
-    json_bytes: bytes = to_json_proc.communicate(input=text.encode(encoding="utf-8", errors="replace"))[0]
-    assert to_json_proc.wait() == 0, 'Failed to convert nuon to json using subshell: ' + text.replace("\n", "\\n")
-    return json.loads(json_bytes.decode(encoding="utf-8"))
+    assert to_json_proc.returncode == 0, 'Failed to convert nuon to json using subshell: ' + text.replace("\n", "\\n")
+    return json.loads(to_json_proc.stdout.decode(encoding="utf-8"))
+
+
+class CancellationToken:
+    # stops a running build (BuildOptions.cancellation): fetches and builds, which didn't start yet, get skipped and running commands terminated
+    def __init__(self) -> None:
+        self._event: Event = Event()
+
+    def cancel(self) -> None:
+        self._event.set()
+
+    @property
+    def cancelled(self) -> bool:
+        return self._event.is_set()
+
+    def check(self) -> None:
+        if self._event.is_set():
+            raise BuildCancelled("The build was cancelled")
+
+
+class CommandRunner:
+    # starts every external process (git, nu, build_commands, ..) -> replace it (BuildOptions.command_runner) to mock, capture, or restrict them
+    def run(self, command: List[str], cwd: Optional[str] = None, cancellation: Optional[CancellationToken] = None, **kwargs) -> subprocess.CompletedProcess:
+        if cancellation is None:
+            return subprocess.run(command, cwd=cwd, **kwargs)
+        cancellation.check()
+        stdin_data: Optional[bytes] = kwargs.pop("input", None)
+        if stdin_data is not None:
+            kwargs["stdin"] = subprocess.PIPE
+        with subprocess.Popen(command, cwd=cwd, **kwargs) as process:
+            try:
+                while True:
+                    try:
+                        stdout_data, stderr_data = process.communicate(stdin_data, timeout=0.2)
+                        break
+                    except subprocess.TimeoutExpired:
+                        if cancellation.cancelled:
+                            raise BuildCancelled(f"The build was cancelled (terminated {' '.join(command[:2])})")
+            except BaseException:
+                # cancelled or ctrl-c -> don't leave the process running
+                process.terminate()
+                try:
+                    process.communicate(timeout=5)
+                except subprocess.TimeoutExpired:
+                    process.kill()
+                    process.communicate()
+                raise
+        return subprocess.CompletedProcess(command, process.returncode, stdout_data, stderr_data)
+
+
+@dataclass(kw_only=True)
+class RunContext:
+    # how external commands and downloads get run (nu binary, git config, credentials, mirrors, runner, cancellation)
+    # every Loader uses its own one -> embedding applications can run multiple Loaders with different settings
+    config: Config = field(default_factory=Config)
+    runner: CommandRunner = field(default_factory=CommandRunner)
+    cancellation: Optional[CancellationToken] = None
+    credential_cache: Dict[str, Optional[str]] = field(default_factory=dict)  # host -> authorization header
+    credential_lock: Lock = field(default_factory=Lock)
+    base_directory: str = BASEDIRECTORY  # store, caches, .. (project-local environments and temporary_store use a different one)
+    config_directory: str = CONFIGDIRECTORY  # config.json, trusted_builds.json
+
+
+# the context of the running Loader (set for the functions it calls, which don't get it passed explicitly)
+_run_context: ContextVar[Optional[RunContext]] = ContextVar("numng_run_context", default=None)
+_default_run_context: RunContext = RunContext()
+
+
+def current_run_context() -> RunContext:
+    return _run_context.get() or _default_run_context
+
+
+@contextmanager
+def use_run_context(context: RunContext):
+    token = _run_context.set(context)
+    try:
+        yield
+    finally:
+        _run_context.reset(token)
+
+
+def nu_binary() -> str:
+    return current_run_context().config.nu_binary
+
+
+def data_path(*parts: str) -> str:
+    return path.join(current_run_context().base_directory, *parts)
+
+
+def config_path(*parts: str) -> str:
+    return path.join(current_run_context().config_directory, *parts)
+
+
+_command_logs_started: Set[str] = set()  # log files written by this process (the first write replaces the previous runs log)
+_command_log_lock: Lock = Lock()
+
+
+def run_command(command: List[str], cwd: Optional[str] = None, context: Optional[RunContext] = None, **kwargs) -> subprocess.CompletedProcess:
+    # subprocess.run, but every call gets logged to COMMAND_LOG_FILE_NAME (only the last numng run is kept)
+    start_time: float = time()
+    context = context or current_run_context()
+    config: Config = context.config
+    if command[:2] in (["git", "clone"], ["git", "fetch"], ["git", "ls-remote"]) and config.credentials:
+        # passed via the environment instead of `-c` to keep them out of the process list and the command log
+        env: Dict[str, str] = dict(kwargs.pop("env", None) or environ)
+        count: int = int(env.get("GIT_CONFIG_COUNT") or "0")
+        for host in config.credentials:
+            if (header := get_authorization_header(host, context)) is None:
+                continue
+            env[f"GIT_CONFIG_KEY_{count}"] = f"http.https://{host}/.extraHeader"
+            env[f"GIT_CONFIG_VALUE_{count}"] = f"Authorization: {header}"
+            count += 1
+        env["GIT_CONFIG_COUNT"] = str(count)
+        kwargs["env"] = env
+    if command[:1] == ["git"] and (config.git_config or config.mirrors):
+        # not part of the log since they might contain credentials (http.extraHeader, ..)
+        # mirrors use gits own url rewriting -> the remote url (and with it the store path and lockfile) stays the original one
+        git_config: List[str] = [f"url.{replacement}.insteadOf={prefix}" for prefix, replacement in config.mirrors.items()]
+        git_config.extend(f"{key}={value}" for key, value in config.git_config.items())
+        result = context.runner.run(["git", *(i for option in git_config for i in ("-c", option)), *command[1:]], cwd=cwd, cancellation=context.cancellation, **kwargs)
+    else:
+        result = context.runner.run(command, cwd=cwd, cancellation=context.cancellation, **kwargs)
+    makedirs(context.base_directory, exist_ok=True)
+    log_file: str = path.join(context.base_directory, COMMAND_LOG_FILE_NAME)
+    with _command_log_lock, open(log_file, "a" if log_file in _command_logs_started else "w") as fp:
+        fp.write(json.dumps({
+            "command": command,
+            "cwd": path.abspath(cwd or getcwd()),
+            "start_time": start_time,
+            "duration": round(time() - start_time, 3),
+            "returncode": result.returncode,
+        }) + "\n")
+        _command_logs_started.add(log_file)
+    return result
+
+
+def command_output(command: List[str], cwd: Optional[str] = None, context: Optional[RunContext] = None, **kwargs) -> Optional[str]:
+    # stdout of a successful run_command (None -> it failed)
+    result: subprocess.CompletedProcess = run_command(command, cwd=cwd, context=context, stdout=subprocess.PIPE, **kwargs)
+    return result.stdout.decode(encoding="utf-8") if result.returncode == 0 else None
+
+
+def get_last_command_log() -> List[Dict[str, Any]]:
+    if not path.isfile(log_file := data_path(COMMAND_LOG_FILE_NAME)):
+        return []
+    with open(log_file, "r") as fp:
+        return [json.loads(line) for line in fp if line.strip()]
 
 
 def main() -> None:
@@ -741,56 +4748,620 @@ def main() -> None:
     parser.add_argument("-n", "--nu-config", action="store_true", help="Shortcut to target the shell-config")
     parser.add_argument("-p", "--package-file", help="The target package file")
     parser.add_argument("-v", "--verbose", action="store_true", help="More verbose logging")
+    parser.add_argument("--wait", action="store_true", help="Wait for other numng processes instead of failing")
+    parser.add_argument("--log-format", choices=["text", "json"], default="text", help="Format of the log output (json: one object per line including the package / git repo it belongs to)")
+    parser_output = parser.add_mutually_exclusive_group()
+    parser_output.add_argument("--json", dest="output_format", action="store_const", const="json", help="Machine readable output (json)")
+    parser_output.add_argument("--nuon", dest="output_format", action="store_const", const="nuon", help="Machine readable output (nuon)")
+    parser.add_argument("--local", action="store_true", help="Use a project-local environment (store and nupm_home in .numng next to the package file)")
     subparsers = parser.add_subparsers(dest="cmd", required=True)
 
     parser_build = subparsers.add_parser("build", aliases=["b"], help="Build the package")
     parser_build.add_argument("--nupm-home", help="Nupm home directory")
     parser_build.add_argument("-o", "--overlay-file", help="Generate a overlay file at path")
     parser_build.add_argument("-s", "--script-file", help="Generate a script file for `source` loading at path")
-    parser_build.add_argument("-u", "--pull-updates", action="store_true", help="Pull updates for already installed packages")
+    parser_build_connection = parser_build.add_mutually_exclusive_group()
+    parser_build_connection.add_argument("-u", "--pull-updates", action="store_true", help="Pull updates for already installed packages")
+    parser_build_connection.add_argument("--offline", action="store_true", help="Only use packages already in the store (no downloads)")
     parser_build.add_argument("-b", "--allow-build-commands", choices=["true", "false"], help="Ignore `build_command`s in packages (overrides the setting in the base-package)")
+    parser_build.add_argument("-j", "--jobs", type=int, help="How many packages to download at the same time (default: 4)")
+    parser_build.add_argument("--build-jobs", type=int, help="How many build_commands to run at the same time (default: 1)")
+    parser_build.add_argument("-l", "--locked", action="store_true", help="Use the exact commits from the lockfile instead of updating it")
+    parser_build.add_argument("--rebuild-all", action="store_true", help="Run all build_commands, even if the package didn't change since the last build")
+    parser_build.add_argument("--timings", action="store_true", help="Print how long each phase, download, and build took")
+    parser_build.add_argument("--strict", action="store_true", help="Fail on unknown keys in numng.json files instead of warning")
+    parser_build.add_argument("--target", help="Cross-compile for a target triple (example: aarch64-unknown-linux-gnu)")
+    parser_build.add_argument("-k", "--keep-going", action="store_true", help="Continue with the other builds if a build_command fails (and report all failures at the end)")
+
+    subparsers.add_parser("audit", help="Check the packages against the advisory databases (`advisory_db`) of the package")
+
+    parser_status = subparsers.add_parser("status", help="Show what a build would change in the environment (without changing anything)")
+    parser_status.add_argument("--nupm-home", help="Nupm home directory")
+    parser_status.add_argument("-l", "--locked", action="store_true", help="Compare against the commits from the lockfile")
+
+    parser_gc = subparsers.add_parser("gc", help="Remove git checkouts and archives from the store, which are not used by any known environment")
+    parser_gc.add_argument("--dry-run", action="store_true", help="Only list what would be removed")
+    parser_gc.add_argument("--older-than", type=float, default=0.0, metavar="DAYS", help="Only remove entries, which were not updated within the last DAYS days")
+
+    parser_shell = subparsers.add_parser("shell", help="Start a nu session with the (already built) environment loaded")
+    parser_shell.add_argument("--nupm-home", help="Nupm home directory")
+    parser_exec = subparsers.add_parser("exec", help="Run a command with the (already built) environment loaded")
+    parser_exec.add_argument("--nupm-home", help="Nupm home directory")
+    parser_exec.add_argument("command", nargs=argparse.REMAINDER, help="The command and its arguments (example: `numng exec -- nu_plugin_foo --help`)")
+
+    parser_fmt = subparsers.add_parser("fmt", help="Format numng.json files (stable key order, indentation, sorted depends)")
+    parser_fmt.add_argument("--check", action="store_true", help="Only list the files, which aren't formatted (exits with 1 if there are any)")
+    parser_fmt.add_argument("files", nargs="*", help="Files to format (default: the package file)")
+    parser_validate = subparsers.add_parser("validate", help="Check package files or registries for problems (without downloading anything)")
+    parser_validate.add_argument("paths", nargs="*", help="Package files or numng registry directories (default: the package file)")
+    parser_validate.add_argument("--registry", action="store_true", help="The files are numng registry files (directories always get treated as registries)")
+    parser_validate.add_argument("--strict", action="store_true", help="Also fail on warnings")
+    subparsers.add_parser("schema", help="Print a JSON Schema of the numng.json format (for editors)")
+    parser_test = subparsers.add_parser("test", help="Build the environment of the package (including its dev_depends) and run its tests")
+    parser_test.add_argument("--nupm-home", help="Nupm home directory (default: one per package in the data directory)")
+    parser_test.add_argument("-b", "--allow-build-commands", choices=["true", "false"], help="Ignore `build_command`s in packages (overrides the setting in the base-package)")
+    parser_store = subparsers.add_parser("store", help="Inspect the store (git checkouts, archives, etc)")
+    store_subparsers = parser_store.add_subparsers(dest="store_cmd", required=True)
+    store_subparsers.add_parser("du", help="Show the disk usage of the store entries")
+    parser_store_verify = store_subparsers.add_parser("verify", help="Check the store for corrupted git repos, broken checkouts, etc")
+    parser_store_verify.add_argument("--repair", action="store_true", help="Re-clone / re-download the broken entries")
+
+    parser_migrate = subparsers.add_parser("migrate", help="Create a numng.json from the config of a different package manager")
+    migrate_subparsers = parser_migrate.add_subparsers(dest="migrate_cmd", required=True)
+    parser_migrate_packer = migrate_subparsers.add_parser("packer", help="Convert a packer.nu config (best effort)")
+    parser_migrate_packer.add_argument("file", help="The packer.nu package list (example: `~/.config/nushell/packer_packages.nu`)")
+    parser_migrate_nupm = migrate_subparsers.add_parser("nupm", help="Convert the packages installed in a nupm home (best effort)")
+    parser_migrate_nupm.add_argument("nupm_home", nargs="?", default=environ.get("NUPM_HOME"), help="The nupm home (default: $env.NUPM_HOME)")
+
+    subparsers.add_parser("vendor", help=f"Copy all dependencies into `{VENDOR_DIR_NAME}/` next to the package file (builds prefer them over the store)")
+
+    parser_bundle = subparsers.add_parser("bundle", help="Move a locked environment to a machine without network access")
+    bundle_subparsers = parser_bundle.add_subparsers(dest="bundle_cmd", required=True)
+    parser_bundle_export = bundle_subparsers.add_parser("export", help="Pack the package file, lockfile, and all sources it uses into a single archive")
+    parser_bundle_export.add_argument("file", help="Where to write the bundle (example: `numng-bundle.tar.gz`)")
+    parser_bundle_import = bundle_subparsers.add_parser("import", help="Add the sources of a bundle to the store (afterwards `build --offline --locked` works)")
+    parser_bundle_import.add_argument("file", help="The bundle created by `numng bundle export`")
+    parser_bundle_import.add_argument("directory", nargs="?", default=".", help="Where to put the package file and lockfile of the bundle (default: current directory)")
+
+    parser_search = subparsers.add_parser("search", help="Search the registries (which are already in the store) by name, description, and keywords")
+    parser_search.add_argument("query", nargs="*", help="Words, which all have to match (default: list every package)")
+
+    parser_completions = subparsers.add_parser("completions", help="Print shell completions (`numng completions nu | save numng-completions.nu`)")
+    parser_completions.add_argument("shell", choices=["nu", "packages"], help="`nu` for the nushell extern definitions (`packages` lists package names for them)")
+
+    parser_self_update = subparsers.add_parser("self-update", help="Update this numng.py to the latest version from github")
+    parser_self_update.add_argument("--check", action="store_true", help="Only check if a update is available")
+
+    parser_check_updates = subparsers.add_parser("check-updates", help="Check if the git refs in the lockfile have new commits (without downloading them)")
+    parser_check_updates.add_argument("--background", action="store_true", help="Run the check in the background and store the result for the load script")
+
+    subparsers.add_parser("doctor", help="Check for common problems (missing git, broken links, etc)")
+
+    subparsers.add_parser("command-log", help="Show the external commands (git, nu, etc) executed by the last numng run")
 
     parser_build = subparsers.add_parser("init", aliases=["i"], help="Initialize a new package in the current directory (or shell-config in its directory)")
 
     args = parser.parse_args()
     if args.verbose:
         log_handler.setLevel(logging.DEBUG)
-    # assumption: nu-config in ~/.config/nushell: https://github.com/nushell/nushell/discussions/9019
-    nu_config_subdir: str = path.abspath(path.join(path.expanduser("~"), ".config", "nushell", "numng"))
+        # parallel fetches / builds interleave -> show what each line belongs to
+        log_handler.setFormatter(logging.Formatter('%(asctime)s::%(levelname)s: %(span_prefix)s%(message)s'))
+    if args.log_format == "json":
+        log_handler.setFormatter(JsonLogFormatter())
+    if args.output_format is not None:
+        # keep stdout parseable
+        log_handler.setStream(stderr)
+        logger.addHandler(StructuredErrorHandler(args.output_format))
+
+    def output(data: Any, text: Callable[[], None]) -> None:
+        if args.output_format is None:
+            text()
+        else:
+            print_structured(data, args.output_format)
+    try:
+        config: Config = apply_environment_variables(load_config())
+    except AssertionError as exc:
+        logger.error(exc.args[0], extra={"numng_error": exc})
+        return
+    # commands outside of a Loader (doctor, gc, store verify, ..) use the config as well
+    context: RunContext = RunContext(config=config, base_directory=current_run_context().base_directory, config_directory=current_run_context().config_directory)
+    _run_context.set(context)
+    nu_config_subdir: str = path.abspath(context.config_directory)
     package_file: Optional[str] = path.abspath(args.package_file) if args.package_file is not None else None
     if package_file is None and args.nu_config:
-        package_file = path.join(nu_config_subdir, "numng.json")
-    if package_file is None and path.exists("numng.json"):
-        package_file = path.abspath("numng.json")
+        package_file = find_package_file(nu_config_subdir) or path.join(nu_config_subdir, "numng.json")
+    if package_file is None and (found_package_file := find_package_file(getcwd())) is not None:
+        package_file = found_package_file
+    local_dir: Optional[str] = None
+    if package_file is not None and (args.local or is_local_environment(package_file)):
+        local_dir = path.join(path.dirname(package_file), LOCAL_ENVIRONMENT_DIR_NAME)
+        logger.debug(f"using the project-local environment {local_dir}")
+        context.base_directory = local_dir
+        if not path.exists(local_dir):
+            makedirs(local_dir)
+            with open(path.join(local_dir, ".gitignore"), "w") as fp:
+                fp.write("*\n")
 
     if args.cmd in ("build", "b"):
         if package_file is None:
             logger.warning("No package file specified. Use --package-file FILEPATH or --nu-config.")
             return
         nupm_home: Optional[str] = args.nupm_home
+        if nupm_home is None and local_dir is not None:
+            nupm_home = path.join(local_dir, "nupm_home")
         if nupm_home is None and args.nu_config:
-            nupm_home = path.join(BASEDIRECTORY, "nu_config_nupm_home")
+            nupm_home = data_path("nu_config_nupm_home")
         # if nupm_home is None and not args.no_auto_nupm_home:
         #     nupm_home = path.abspath("numng_nupm_home")
         script_file: Optional[str] = args.script_file
         if script_file is None and args.nu_config:
             script_file = path.join(nu_config_subdir, "load_script.nu")
+        progress: Optional[ProgressDisplay] = ProgressDisplay() if stdout.isatty() and args.output_format is None else None
+        if progress is not None:
+            log_handler.addFilter(progress)
+        connection_policy: str = "update" if args.pull_updates else ("offline" if args.offline else config.connection_policy)
         try:
-            Loader(
-                package_file,
-                generate_script=script_file,
-                generate_overlay=args.overlay_file,
-                nupm_home=nupm_home,
-                delete_existing_nupm_home=True,
-                pull_updates=args.pull_updates,
-                handle_nu_plugins=args.nu_config,
-                allow_build_commands=args.allow_build_commands,
+            with StoreLock(wait=args.wait):
+                loader = Loader(package_file, BuildOptions(
+                    generate_script=script_file,
+                    generate_overlay=args.overlay_file,
+                    nupm_home=nupm_home,
+                    delete_existing_nupm_home=True,
+                    pull_updates=connection_policy == "update",
+                    offline=connection_policy == "offline",
+                    handle_nu_plugins=args.nu_config,
+                    allow_build_commands={"true": True, "false": False}.get(args.allow_build_commands),
+                    lockfile=path.join(path.dirname(package_file), "numng.lock.json"),
+                    locked=args.locked,
+                    jobs=args.jobs if args.jobs is not None else config.jobs,
+                    build_jobs=args.build_jobs if args.build_jobs is not None else config.build_jobs,
+                    incremental=not args.rebuild_all,
+                    strict=args.strict,
+                    keep_going=args.keep_going,
+                    target=args.target,
+                    observer=progress,
+                    config=config,
+                ))
+            logger.info(
+                f"Loaded {len(loader.report.packages)} packages ({len(loader.report.skipped_packages)} duplicates skipped,"
+                f" {len(loader.report.skipped_builds)} unchanged builds skipped,"
+                f" {len(loader.report.warnings)} warnings) in {loader.report.duration:.1f}s"
             )
+            output(loader.report, lambda: print(format_timings(loader.report)) if args.timings else None)
+        except AssertionError as exc:
+            if exc.args:
+                logger.error(exc.args[0], extra={"numng_error": exc})
+            else:
+                raise exc
+        except KeyboardInterrupt:
+            # running commands got terminated and the nupm_home wasn't replaced
+            exc = BuildCancelled("The build was cancelled (ctrl-c)")
+            logger.error(exc.args[0], extra={"numng_error": exc})
+        finally:
+            if progress is not None:
+                progress.finish()
+        return
+
+    if args.cmd == "audit":
+        if package_file is None:
+            logger.warning("No package file specified. Use --package-file FILEPATH or --nu-config.")
+            return
+        try:
+            with StoreLock(wait=args.wait):
+                Loader(package_file, BuildOptions(dry_run=True, audit=True, offline=config.connection_policy == "offline", config=config))
+            output({"advisories": []}, lambda: None)
+        except AuditError as exc:
+            if args.output_format is not None:
+                print_structured(exc.to_json(), args.output_format)
+            else:
+                logger.error(exc.args[0], extra={"numng_error": exc})
+        except AssertionError as exc:
+            if exc.args:
+                logger.error(exc.args[0], extra={"numng_error": exc})
+            else:
+                raise exc
+        return
+
+    if args.cmd == "status":
+        if package_file is None:
+            logger.warning("No package file specified. Use --package-file FILEPATH or --nu-config.")
+            return
+        nupm_home = args.nupm_home
+        if nupm_home is None and local_dir is not None:
+            nupm_home = path.join(local_dir, "nupm_home")
+        if nupm_home is None and args.nu_config:
+            nupm_home = data_path("nu_config_nupm_home")
+        if nupm_home is None:
+            logger.warning("No nupm home specified. Use --nupm-home DIRECTORY or --nu-config.")
+            return
+        try:
+            with StoreLock(wait=args.wait):
+                loader = Loader(package_file, BuildOptions(
+                    dry_run=True,
+                    lockfile=path.join(path.dirname(package_file), "numng.lock.json"),
+                    locked=args.locked,
+                    offline=config.connection_policy == "offline",
+                    config=config,
+                ))
+        except AssertionError as exc:
+            if exc.args:
+                logger.error(exc.args[0], extra={"numng_error": exc})
+            else:
+                raise exc
+            return
+        if (state := load_environment_state(nupm_home)) is None:
+            logger.warning(f"No numng environment found at {nupm_home} (everything would be added)")
+        changes: Dict[str, List[str]] = environment_status(loader.report, state)
+
+        def print_changes() -> None:
+            for change, symbol in (("added", "+"), ("removed", "-"), ("updated", "~"), ("rebuilt", "b")):
+                for name in changes[change]:
+                    print(f"{symbol} {name} ({change})")
+            if not any(changes.values()):
+                print("Everything is up to date")
+        output(changes, print_changes)
+        return
+
+    if args.cmd == "gc":
+        try:
+            with StoreLock(wait=args.wait):
+                removed: List[str] = collect_garbage(dry_run=args.dry_run, min_age_days=args.older_than)
+        except AssertionError as exc:
+            if exc.args:
+                logger.error(exc.args[0], extra={"numng_error": exc})
+            else:
+                raise exc
+            return
+        output({"removed": removed, "dry_run": args.dry_run}, lambda: [print(f"{'would remove' if args.dry_run else 'removed'} {i}") for i in removed])
+        logger.info(f"{'Would remove' if args.dry_run else 'Removed'} {len(removed)} store entries")
+        return
+
+    if args.cmd in ("shell", "exec"):
+        if package_file is None:
+            logger.warning("No package file specified. Use --package-file FILEPATH or --nu-config.")
+            return
+        nupm_home = args.nupm_home
+        if nupm_home is None and local_dir is not None:
+            nupm_home = path.join(local_dir, "nupm_home")
+        if nupm_home is None and args.nu_config:
+            nupm_home = data_path("nu_config_nupm_home")
+        command: List[str] = getattr(args, "command", []) if args.cmd == "exec" else []
+        # only the separator (`numng exec -- git log -- file`)
+        command = command[1:] if command[:1] == ["--"] else command
+        if args.cmd == "exec" and len(command) == 0:
+            logger.warning("No command specified (example: `numng exec -- ls`)")
+            return
+        with TemporaryDirectory() as tmpdir:
+            overlay_file: str = path.join(tmpdir, "numng_overlay.nu")
+            try:
+                with StoreLock(wait=args.wait):
+                    # the environment has to be built already -> only regenerate the overlay (offline, no builds)
+                    Loader(package_file, BuildOptions(
+                        generate_overlay=overlay_file,
+                        nupm_home=nupm_home,
+                        delete_existing_nupm_home=True,
+                        dry_run=True,
+                        offline=True,
+                        config=config,
+                    ))
+            except AssertionError as exc:
+                if exc.args:
+                    logger.error(f"{exc.args[0]} (run `numng build` first)", extra={"numng_error": exc})
+                else:
+                    raise exc
+                return
+            if args.cmd == "shell":
+                shell_proc = subprocess.run([nu_binary(), "-e", f"overlay use {json.dumps(overlay_file)}"])
+            else:
+                shell_proc = subprocess.run([nu_binary(), "-c", f"overlay use {json.dumps(overlay_file)}; run-external {' '.join(json.dumps(i) for i in command)}"])
+        raise SystemExit(shell_proc.returncode)
+
+    if args.cmd == "fmt":
+        files: List[str] = args.files or ([package_file] if package_file is not None else [])
+        if not files:
+            logger.warning("No package file specified. Use --package-file FILEPATH or pass the files.")
+            return
+        unformatted: List[str] = []
+        for file_path in files:
+            if not file_path.endswith(".json"):
+                logger.warning(f"Skipping {file_path} (only json files can be formatted, formatting would remove the comments of jsonc files)")
+                continue
+            try:
+                text, formatted = format_package_file(file_path)
+            except AssertionError as exc:
+                logger.error(exc.args[0], extra={"numng_error": exc})
+                raise SystemExit(1)
+            if text == formatted:
+                continue
+            unformatted.append(file_path)
+            if not args.check:
+                logger.info(f"formatting {file_path}")
+                with open(file_path, "w") as fp:
+                    fp.write(formatted)
+        if args.check:
+            output(unformatted, lambda: [print(f"{i} is not formatted") for i in unformatted])
+            raise SystemExit(1 if unformatted else 0)
+        return
+
+    if args.cmd == "validate":
+        paths: List[str] = args.paths or ([package_file] if package_file is not None else [])
+        if not paths:
+            logger.warning("No package file specified. Use --package-file FILEPATH or pass the files.")
+            return
+        diagnostics: List[Diagnostic] = []
+        file_count: int = 0
+        for validate_path in paths:
+            if path.isdir(validate_path):
+                for root, dirs, files in walk(validate_path):
+                    dirs[:] = sorted(i for i in dirs if i != ".git")
+                    for file_name in sorted(i for i in files if i.endswith(PACKAGE_FILE_EXTENSIONS) and i not in (REGISTRY_MANIFEST_FILE, SEARCH_INDEX_FILE)):
+                        diagnostics.extend(validate_file(path.join(root, file_name), registry=True))
+                        file_count += 1
+            else:
+                diagnostics.extend(validate_file(validate_path, registry=args.registry))
+                file_count += 1
+        errors: int = sum(1 for i in diagnostics if i.severity == "error")
+
+        def print_diagnostics() -> None:
+            for diagnostic in diagnostics:
+                print(diagnostic)
+            print(f"{errors} errors and {len(diagnostics) - errors} warnings in {file_count} files")
+        output(diagnostics, print_diagnostics)
+        raise SystemExit(1 if errors or (args.strict and diagnostics) else 0)
+
+    if args.cmd == "schema":
+        print(json.dumps(package_json_schema(), indent=4))
+        return
+
+    if args.cmd == "test":
+        if package_file is None:
+            logger.warning("No package file specified. Use --package-file FILEPATH.")
+            return
+        nupm_home = args.nupm_home
+        if nupm_home is None and local_dir is not None:
+            nupm_home = path.join(local_dir, "test_nupm_home")
+        if nupm_home is None:
+            # separate from the normal environment, since it also contains the dev_depends
+            nupm_home = data_path("test_nupm_home", hashlib.sha256(package_file.encode(encoding="utf-8")).hexdigest()[:16])
+        with TemporaryDirectory() as tmpdir:
+            overlay_file = path.join(tmpdir, "numng_overlay.nu")
+            try:
+                with StoreLock(wait=args.wait):
+                    Loader(package_file, BuildOptions(
+                        generate_overlay=overlay_file,
+                        nupm_home=nupm_home,
+                        delete_existing_nupm_home=True,
+                        allow_build_commands={"true": True, "false": False}.get(args.allow_build_commands),
+                        dev=True,
+                        config=config,
+                    ))
+                results: List[Tuple[str, bool]] = run_package_tests(package_file, overlay_file)
+            except AssertionError as exc:
+                if exc.args:
+                    logger.error(exc.args[0], extra={"numng_error": exc})
+                else:
+                    raise exc
+                raise SystemExit(1)
+        failed: int = sum(1 for _, passed in results if not passed)
+
+        def print_results() -> None:
+            for name, passed in results:
+                print(f"[{'ok' if passed else '!!'}] {name}")
+            print(f"{len(results) - failed} passed, {failed} failed")
+        output([{"name": name, "passed": passed} for name, passed in results], print_results)
+        raise SystemExit(1 if failed else 0)
+
+    if args.cmd == "store" and args.store_cmd == "du":
+        usage: List[Dict[str, Any]] = store_disk_usage()
+        total: int = sum(i["size"] for i in usage)
+        if args.output_format is not None:
+            print_structured({"entries": usage, "total": total}, args.output_format)
+            return
+        for entry in usage:
+            print(f"{format_size(entry['size']):>10}  {entry['name']}")
+        print(f"{format_size(total):>10}  total ({len(usage)} entries)")
+        return
+
+    if args.cmd == "store" and args.store_cmd == "verify":
+        try:
+            with StoreLock(wait=args.wait):
+                problems: List[str] = verify_store(repair=args.repair)
+        except AssertionError as exc:
+            if exc.args:
+                logger.error(exc.args[0], extra={"numng_error": exc})
+            else:
+                raise exc
+            return
+        if args.output_format is not None:
+            print_structured({"problems": problems, "repaired": args.repair}, args.output_format)
+            return
+        for problem in problems:
+            logger.warning(problem)
+        if len(problems) == 0:
+            logger.info("No problems found in the store")
+        elif args.repair:
+            logger.info(f"Repaired {len(problems)} problems (run `numng store verify` again to check)")
+            logger.info("Re-created checkouts no longer contain build output -> rebuild with `numng build --rebuild-all`")
+        return
+
+    if args.cmd == "migrate":
+        # the nu config is the common packer.nu / nupm use-case -> write to its numng.json if requested, otherwise the current directory
+        target_file: str = package_file or path.abspath("numng.json")
+        if path.exists(target_file):
+            logger.error(f"{target_file} already exists (remove it or use a different --package-file)")
+            return
+        if args.migrate_cmd == "nupm" and args.nupm_home is None:
+            logger.error("No nupm home specified (and $env.NUPM_HOME is not set)")
+            return
+        source: str = path.expanduser(args.file if args.migrate_cmd == "packer" else args.nupm_home)
+        try:
+            migrated, problems = (migrate_packer_config if args.migrate_cmd == "packer" else migrate_nupm_home)(source)
+        except (AssertionError, OSError) as exc:
+            logger.error(f"Failed to migrate {source} ({exc})")
+            return
+        for problem in problems:
+            logger.warning(problem)
+        makedirs(path.dirname(target_file), exist_ok=True)
+        with open(target_file, "w") as fp:
+            json.dump({
+                "name": "nu-config" if args.nu_config else path.basename(path.dirname(target_file)),
+                "depends": [*([{"name": "jan9103/numng"}] if args.nu_config else []), *migrated],
+                "registry": [
+                    {"source_uri": "https://github.com/Jan9103/numng_repo", "package_format": "numng", "path_offset": "repo"},
+                    *([{"source_uri": "https://github.com/nushell/nupm", "package_format": "nupm"}] if args.migrate_cmd == "nupm" else []),
+                ],
+            }, fp, indent=4)
+        if args.output_format is not None:
+            print_structured({"package_file": target_file, "depends": migrated, "warnings": problems}, args.output_format)
+            return
+        logger.info(f"Wrote {len(migrated)} packages to {target_file}")
+        if stdin.isatty() and input(f"Run numng build command now?\n({orig_argv[0]} {orig_argv[1]} --package-file {target_file} build)\n(yes or no): ") == "yes":
+            subprocess.run([orig_argv[0], orig_argv[1], "--package-file", target_file, "build"])
+        return
+
+    if args.cmd == "vendor":
+        if package_file is None:
+            logger.warning("No package file found (create it using `numng init` or specify it using --package-file FILEPATH or --nu-config)")
+            return
+        lockfile = path.join(path.dirname(package_file), "numng.lock.json")
+        try:
+            with StoreLock(wait=args.wait):
+                loader = Loader(package_file, BuildOptions(
+                    dry_run=True,
+                    vendor=False,
+                    raw_registries=False,
+                    lockfile=lockfile,
+                    locked=path.isfile(lockfile),
+                    offline=config.connection_policy == "offline",
+                    config=config,
+                ))
+                vendored: Dict[str, Dict[str, Any]] = vendor_sources(loader.report.sources, path.join(path.dirname(package_file), VENDOR_DIR_NAME))
+        except AssertionError as exc:
+            if exc.args:
+                logger.error(exc.args[0], extra={"numng_error": exc})
+            else:
+                raise exc
+            return
+        output(vendored, lambda: logger.info(f"Vendored {len(vendored)} sources into {path.join(path.dirname(package_file), VENDOR_DIR_NAME)}"))
+        return
+
+    if args.cmd == "bundle" and args.bundle_cmd == "export":
+        if package_file is None or not path.isfile(lockfile := path.join(path.dirname(package_file), "numng.lock.json")):
+            logger.warning("No lockfile found (build the package first or specify it using --package-file FILEPATH or --nu-config)")
+            return
+        try:
+            with StoreLock(wait=args.wait):
+                bundle: Dict[str, Any] = export_bundle(package_file, lockfile, args.file)
+        except AssertionError as exc:
+            if exc.args:
+                logger.error(exc.args[0], extra={"numng_error": exc})
+            else:
+                raise exc
+            return
+        output(bundle, lambda: logger.info(f"Exported {bundle['git']} git repos and {bundle['archive']} archives to {bundle['file']}"))
+        return
+
+    if args.cmd == "bundle" and args.bundle_cmd == "import":
+        try:
+            makedirs(data_path(), exist_ok=True)
+            with StoreLock(wait=args.wait):
+                bundle = import_bundle(args.file, args.directory)
         except AssertionError as exc:
             if exc.args:
-                logger.error(exc.args[0])
+                logger.error(exc.args[0], extra={"numng_error": exc})
             else:
                 raise exc
+            return
+        output(bundle, lambda: logger.info(
+            f"Imported {bundle['git']} git repos and {bundle['archive']} archives"
+            f" (build it using `numng --package-file {bundle['package_file']} build --offline --locked`)"
+        ))
+        return
+
+    if args.cmd == "completions" and args.shell == "nu":
+        print(generate_nu_completions(parser), end="")
+        return
+
+    if args.cmd == "completions" and args.shell == "packages":
+        names: List[str] = list_registry_package_names(package_file) if package_file is not None else []
+        output(names, lambda: [print(name) for name in names])
+        return
+
+    if args.cmd == "search":
+        if package_file is None:
+            logger.warning("No package file specified. Use --package-file FILEPATH or --nu-config.")
+            return
+        try:
+            registry_packages: List[Package] = load_package_from_json(read_package_file(package_file)[0], allow_no_name=True).registries or []
+            matches: List[Dict[str, Any]] = search_packages(load_stored_registries(registry_packages).search_index(), " ".join(args.query))
+        except AssertionError as exc:
+            logger.error(exc.args[0], extra={"numng_error": exc})
+            return
+        output(matches, lambda: [print(entry["name"] + (f" - {entry['description']}" if entry.get("description") else "")) for entry in matches])
+        return
+
+    if args.cmd == "self-update":
+        try:
+            update_available: bool = self_update(check_only=args.check)
+        except AssertionError as exc:
+            logger.error(exc.args[0], extra={"numng_error": exc})
+            return
+        except OSError as exc:
+            logger.error(f"Failed to reach github: {exc}")
+            return
+        if args.output_format is not None:
+            print_structured({"update_available": update_available, "updated": update_available and not args.check}, args.output_format)
+        elif not update_available:
+            logger.info("numng is up to date")
+        elif args.check:
+            logger.info("A numng update is available (install it using `numng self-update`)")
+        else:
+            logger.info("Updated numng")
+        return
+
+    if args.cmd == "check-updates":
+        if package_file is None or not path.isfile(lockfile := path.join(path.dirname(package_file), "numng.lock.json")):
+            logger.warning("No lockfile found (build the package first or specify it using --package-file FILEPATH or --nu-config)")
+            return
+        check_file: str = update_check_file(package_file)
+        if args.background and environ.get("NUMNG_UPDATE_CHECK_CHILD") is None:
+            # store the time immediately -> other shells started in the meantime don't start another check
+            makedirs(path.dirname(check_file), exist_ok=True)
+            previous_check: Dict[str, Any] = {"outdated": [], "notified": True}
+            if path.isfile(check_file):
+                with open(check_file, "r") as fp:
+                    previous_check = json.load(fp)
+            with open(check_file, "w") as fp:
+                json.dump({**previous_check, "time": time()}, fp)
+            subprocess.Popen(
+                [executable, path.realpath(__file__), "--package-file", package_file, "check-updates", "--background"],
+                env={**environ, "NUMNG_UPDATE_CHECK_CHILD": "1"},
+                stdin=subprocess.DEVNULL, stdout=subprocess.DEVNULL, stderr=subprocess.DEVNULL,
+                start_new_session=True,
+            )
+            return
+        outdated: List[str] = check_for_updates(lockfile)
+        if args.background:
+            with open(check_file, "w") as fp:
+                json.dump({"time": time(), "outdated": outdated, "notified": False}, fp)
+            return
+        if args.output_format is not None:
+            print_structured({"outdated": outdated}, args.output_format)
+            return
+        for i in outdated:
+            print(f"update available: {i}")
+        if len(outdated) == 0:
+            logger.info("Everything is up to date")
+        return
+
+    if args.cmd == "doctor":
+        checks: List[Tuple[bool, str]] = run_doctor()
+        output([{"ok": ok, "message": message} for ok, message in checks], lambda: [print(f"[{'ok' if ok else '!!'}] {message}") for ok, message in checks])
+        return
+
+    if args.cmd == "command-log":
+        entries: List[Dict[str, Any]] = list(get_last_command_log())
+        output(entries, lambda: [print(f"[{entry['returncode']}] ({entry['duration']}s) {entry['cwd']}: {' '.join(entry['command'])}") for entry in entries])
         return
 
     if args.cmd in ("init", "i"):
@@ -805,7 +5376,7 @@ def main() -> None:
                     "registry": [{"source_uri": "https://github.com/Jan9103/numng_repo", "package_format": "numng", "path_offset": "repo"}],
                 }, fp, indent=4)
         if args.nu_config and not path.exists(ls := path.join(dir, "load_script.nu")):
-            nupm_home = path.join(BASEDIRECTORY, "nu_config_nupm_home")
+            nupm_home = data_path("nu_config_nupm_home")
             with open(ls, "w") as fp:
                 fp.write("")
         if args.nu_config:
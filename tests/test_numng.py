@@ -0,0 +1,1001 @@
+# unit tests for the parts of numng, which don't need nu or network access (the Loader tests use local git repos)
+# run: `python3 -m unittest discover tests`
+import contextvars
+import io
+import json
+import logging
+import subprocess
+import sys
+import unittest
+from os import listdir, makedirs, path
+from shutil import which
+from tempfile import TemporaryDirectory
+from threading import Thread
+from unittest import mock
+from urllib.error import URLError
+from typing import Any, Dict, List, Optional, Tuple
+
+sys.path.insert(0, path.dirname(path.dirname(path.abspath(__file__))))
+import numng  # noqa: E402
+
+numng.logger.setLevel(logging.ERROR)  # keep the test output readable
+
+
+def write_json(file_path: str, data: Any) -> str:
+    with open(file_path, "w") as fp:
+        json.dump(data, fp)
+    return file_path
+
+
+def git_repo(directory: str, package_json: Dict[str, Any]) -> str:
+    # a local git repo containing only a numng.json -> its source_uri
+    makedirs(directory)
+    write_json(path.join(directory, "numng.json"), package_json)
+    for command in (["init", "-q", "-b", "main"], ["add", "-A"], ["-c", "user.name=numng", "-c", "user.email=numng@localhost", "commit", "-qm", "init"]):
+        subprocess.run(["git", *command], cwd=directory, check=True)
+    return "file://" + directory
+
+
+class SemVerTest(unittest.TestCase):
+    def assert_matches(self, requirement: str, version: str, expected: bool = True) -> None:
+        self.assertEqual(numng.SemVer(requirement).matches(numng.SemVer(version)), expected, f"{requirement} matches {version}")
+
+    def test_parse(self) -> None:
+        version = numng.SemVer("1.2.3-alpha.2+build.5")
+        self.assertEqual((version.major, version.minor, version.patch), (1, 2, 3))
+        self.assertEqual(version.prerelease, "alpha.2")
+        self.assertEqual(version.build, "build.5")
+        self.assertEqual(str(version), "SemVer(1.2.3-alpha.2+build.5)")
+        self.assertEqual(numng.SemVer("latest").op, "latest")
+        self.assertEqual(numng.SemVer(">=1.2").op, ">=")
+
+    def test_caret_and_tilde(self) -> None:
+        self.assert_matches("^1.2", "1.9.0")
+        self.assert_matches("^1.2.3", "1.2.4")
+        self.assert_matches("^1.2", "2.0.0", False)
+        self.assert_matches("~1.2.3", "1.2.9")
+        self.assert_matches("~1.2.3", "1.3.0", False)
+        self.assert_matches("1.2", "1.2.7")
+        self.assert_matches("1.2.3", "1.2.4", False)
+
+    def test_comparison_operators(self) -> None:
+        self.assert_matches(">=1.2.3", "1.2.3")
+        self.assert_matches(">=1.2.3", "1.2.2", False)
+        self.assert_matches("<=1.2", "1.2.9")
+        self.assert_matches(">1.2", "1.2.9", False)
+        self.assert_matches(">1.2", "1.3.0")
+        self.assert_matches("<2", "1.99.0")
+        self.assert_matches("!=1.2.3", "1.2.3", False)
+        self.assert_matches("!=1.2.3", "1.2.4")
+        self.assert_matches(">=1.0.0", "latest", False)
+
+    def test_wildcards(self) -> None:
+        self.assert_matches("1.x", "1.7.3")
+        self.assert_matches("1.2.*", "1.2.9")
+        self.assert_matches("1.2.*", "1.3.0", False)
+        self.assert_matches("*", "0.1.0")
+
+    def test_prereleases(self) -> None:
+        self.assert_matches("^1.2.0", "1.3.0-beta", False)
+        self.assert_matches("1.2.3-alpha", "1.2.3-alpha")
+        self.assert_matches(">=1.2.3-alpha.1", "1.2.3-beta")
+        self.assert_matches(">=1.2.3-beta", "1.2.3-alpha.1", False)
+        self.assert_matches("1.2.3", "1.2.3-alpha", False)
+
+    def test_build_metadata_is_ignored_for_matching(self) -> None:
+        self.assert_matches("1.2.3", "1.2.3+build.5")
+        self.assert_matches("=1.2.3+other", "1.2.3+build.5")
+
+    def test_ordering(self) -> None:
+        versions: List[str] = ["latest", "1.2.3", "1.2.3-alpha", "1.2.3-alpha.1", "1.2.3-beta", "1.0", "1", "1.0.0", "githead", "0.9.9"]
+        self.assertEqual(
+            [i.text for i in sorted(numng.SemVer(i) for i in versions)],
+            ["githead", "0.9.9", "1", "1.0", "1.0.0", "1.2.3-alpha", "1.2.3-alpha.1", "1.2.3-beta", "1.2.3", "latest"],
+        )
+        self.assertTrue(numng.SemVer("1.10.0") > numng.SemVer("1.9.0"))
+        self.assertTrue(numng.SemVer("1.2.3+a") <= numng.SemVer("1.2.3+b"))
+
+    def test_ordering_against_other_types(self) -> None:
+        with self.assertRaises(TypeError):
+            numng.SemVer("1.0.0") < "1.0.1"  # type: ignore
+        with self.assertRaises(TypeError):
+            hash(numng.SemVer("1.0.0"))
+        self.assertNotEqual(numng.SemVer("1.0.0"), "1.0.0")
+
+    def test_greater_than_is_deprecated(self) -> None:
+        with self.assertWarns(DeprecationWarning):
+            self.assertTrue(numng.SemVer("1.2.3").greater_than(numng.SemVer("1")))
+
+    def test_latest_matching_dict_item(self) -> None:
+        options: Dict[Any, Any] = {"1.0.0": "a", "1.2.0": "b", "2.0.0-rc.1": "c", "2.0.0": "d", "_": "shared"}
+        self.assertEqual(numng.SemVer("^1").latest_matching_dict_item(options), ("1.2.0", "b"))
+        self.assertEqual(numng.SemVer("latest").latest_matching_dict_item({k: v for k, v in options.items() if k != "2.0.0"}), ("1.2.0", "b"))
+        self.assertIsNone(numng.SemVer("^3").latest_matching_dict_item(options))
+
+
+class CalVerTest(unittest.TestCase):
+    def test_prefix_and_comparison(self) -> None:
+        self.assertTrue(numng.CalVer("2024.05").matches(numng.CalVer("2024.05.01")))
+        self.assertFalse(numng.CalVer("2024.05").matches(numng.CalVer("2024.06.01")))
+        self.assertTrue(numng.CalVer(">=2024.05").matches(numng.CalVer("2024-06-01")))
+        self.assertFalse(numng.CalVer("<2024").matches(numng.CalVer("2024.01.01")))
+
+    def test_ordering(self) -> None:
+        self.assertEqual(
+            [i.text for i in sorted(numng.CalVer(i) for i in ("2024.10.01", "latest", "2024.9.30", "23.12"))],
+            ["23.12", "2024.9.30", "2024.10.01", "latest"],
+        )
+
+
+class VersionReqTest(unittest.TestCase):
+    def assert_matches(self, requirement: str, version: str, expected: bool = True) -> None:
+        self.assertEqual(numng.VersionReq(requirement).matches(numng.SemVer(version)), expected, f"{requirement} matches {version}")
+
+    def test_and(self) -> None:
+        self.assert_matches(">=1.2, <2.0", "1.9.9")
+        self.assert_matches(">=1.2, <2.0", "2.0.0", False)
+        self.assert_matches(">=1.2, <2.0", "1.1.0", False)
+
+    def test_hyphen_range(self) -> None:
+        self.assert_matches("1.2 - 1.5", "1.2.0")
+        self.assert_matches("1.2 - 1.5", "1.5.9")
+        self.assert_matches("1.2 - 1.5", "1.6.0", False)
+
+    def test_or(self) -> None:
+        self.assert_matches("^1 || ^3", "1.4.0")
+        self.assert_matches("^1 || ^3", "3.0.1")
+        self.assert_matches("^1 || ^3", "2.0.0", False)
+
+    def test_prerelease_in_one_part(self) -> None:
+        self.assert_matches(">=1.2.3-alpha, <1.2.3", "1.2.3-beta")
+        self.assert_matches(">=1.2.3-alpha, <1.2.3", "1.2.3", False)
+
+    def test_schemes(self) -> None:
+        self.assertTrue(numng.VersionReq(">=2024.05, <2025", "calver").matches(numng.CalVer("2024.12.24")))
+        with self.assertRaises(AssertionError):
+            numng.VersionReq("1", "unknown")
+
+    def test_latest_matching_dict_item(self) -> None:
+        options: Dict[str, str] = {"1.0.0": "a", "1.5.0": "b", "2.0.0": "c", "3.1.0": "d"}
+        self.assertEqual(numng.VersionReq(">=1, <3").latest_matching_dict_item(options), ("2.0.0", "c"))
+        self.assertEqual(numng.VersionReq("^1 || ^3").latest_matching_dict_item(options), ("3.1.0", "d"))
+        self.assertEqual(numng.VersionReq("^1").latest_matching_dict_item(options), ("1.5.0", "b"))
+
+
+class NightlyComparator(numng.VersionComparator):
+    def handles(self, version: str) -> bool:
+        return version.startswith("nightly-")
+
+    def sort_key(self, version: str) -> Tuple[Any, ...]:
+        return (int(version[len("nightly-"):]),)
+
+
+class VersionComparatorTest(unittest.TestCase):
+    def setUp(self) -> None:
+        self.comparator = NightlyComparator()
+        numng.register_version_comparator(self.comparator)
+
+    def tearDown(self) -> None:
+        numng.VERSION_COMPARATORS.remove(self.comparator)
+
+    def test_custom_ordering(self) -> None:
+        nightlies: List[str] = ["nightly-20240501", "nightly-20231224", "nightly-20240110"]
+        self.assertEqual([i.text for i in sorted(numng.SemVer(i) for i in nightlies)], ["nightly-20231224", "nightly-20240110", "nightly-20240501"])
+        self.assertTrue(numng.SemVer("nightly-20240501").matches(numng.SemVer("nightly-20240501")))
+        self.assertFalse(numng.SemVer("nightly-20240501").matches(numng.SemVer("1.0.0")))
+
+    def test_registry_picks_the_newest(self) -> None:
+        registry = numng.MemoryPackageRegistry({"foo": {
+            "nightly-20240110": {"source_uri": "https://example.com/foo", "git_ref": "a"},
+            "nightly-20240501": {"source_uri": "https://example.com/foo", "git_ref": "b"},
+        }})
+        self.assertEqual(registry.list_versions("foo"), ["nightly-20240110", "nightly-20240501"])
+        package = registry.get_by_name("foo", version="nightly-20240501")
+        assert package is not None
+        self.assertEqual(package.source_git_ref, "b")
+
+
+class JsoncTest(unittest.TestCase):
+    def test_comments_and_trailing_commas(self) -> None:
+        text: str = '{\n  // pinned\n  "a": [1, 2,], /* note */\n  "b": "// not a comment",\n}\n'
+        stripped: str = numng.strip_jsonc(text)
+        self.assertEqual(json.loads(stripped), {"a": [1, 2], "b": "// not a comment"})
+        self.assertEqual(stripped.count("\n"), text.count("\n"))  # error locations stay the same
+        self.assertEqual(len(stripped), len(text))
+
+    def test_escaped_quotes(self) -> None:
+        self.assertEqual(json.loads(numng.strip_jsonc('{"a": "\\" // x",}')), {"a": '" // x'})
+
+
+class JsonPointerTest(unittest.TestCase):
+    def test_json_pointer(self) -> None:
+        self.assertEqual(numng.json_pointer("depends", 2, "source_uri"), "/depends/2/source_uri")
+        self.assertEqual(numng.json_pointer("linkin", "a/b~c"), "/linkin/a~1b~0c")
+
+    def test_location(self) -> None:
+        text: str = '{\n    "name": "foo",\n    "depends": [\n        "bar",\n        {"name": 5}\n    ],\n    "linkin": {"a/b": "x"}\n}\n'
+        self.assertEqual(numng.json_pointer_location(text, ""), (1, 1))
+        self.assertEqual(numng.json_pointer_location(text, "/name"), (2, 13))
+        self.assertEqual(numng.json_pointer_location(text, "/depends/1"), (5, 9))
+        self.assertEqual(numng.json_pointer_location(text, "/depends/1/name"), (5, 18))
+        self.assertEqual(numng.json_pointer_location(text, "/linkin/a~1b"), (7, 23))
+        self.assertIsNone(numng.json_pointer_location(text, "/depends/2"))
+        self.assertIsNone(numng.json_pointer_location(text, "/missing"))
+
+    def test_manifest_error_location(self) -> None:
+        with TemporaryDirectory() as tmp_dir:
+            package_file: str = path.join(tmp_dir, "numng.json")
+            with open(package_file, "w") as fp:
+                fp.write('{\n    "name": "foo",\n    "depends": [\n        {"name": 5}\n    ]\n}\n')
+            with self.assertRaises(numng.ManifestError) as context:
+                numng.read_package_file(package_file)
+            self.assertEqual(context.exception.pointer, "/depends/0/name")
+            self.assertEqual((context.exception.file, context.exception.line, context.exception.column), (package_file, 4, 18))
+
+
+class IncludeTest(unittest.TestCase):
+    def test_merge_package_json(self) -> None:
+        merged: Dict[str, Any] = numng.merge_package_json(
+            {"name": "root", "depends": ["a"], "registry": {"source_uri": "r1"}, "linkin": {"x": "1"}},
+            {"name": "ignored", "depends": ["b"], "registry": {"source_uri": "r2"}, "linkin": {"x": "2", "y": "3"}, "nu_version": "0.99"},
+        )
+        self.assertEqual(merged, {
+            "name": "root",
+            "depends": ["a", "b"],
+            "registry": [{"source_uri": "r1"}, {"source_uri": "r2"}],
+            "linkin": {"x": "1", "y": "3"},
+            "nu_version": "0.99",
+        })
+
+    def test_merge_dependency_maps(self) -> None:
+        merged: Dict[str, Any] = numng.merge_package_json({"depends": {"a": "^1"}}, {"depends": {"a": "^2", "b": "^3"}})
+        self.assertEqual(merged["depends"], {"a": "^1", "b": "^3"})
+
+    def test_include_relative_to_the_including_file(self) -> None:
+        with TemporaryDirectory() as tmp_dir:
+            makedirs(path.join(tmp_dir, "deps"))
+            write_json(path.join(tmp_dir, "deps", "libs.json"), {"depends": ["b"], "include": "more.json"})
+            write_json(path.join(tmp_dir, "deps", "more.json"), {"depends": ["c"]})
+            package_file: str = write_json(path.join(tmp_dir, "numng.json"), {"name": "root", "depends": ["a"], "include": ["deps/libs.json"]})
+            json_data, _ = numng.read_package_file(package_file)
+            self.assertEqual(json_data, {"name": "root", "depends": ["a", "b", "c"]})
+
+    def test_circular_include(self) -> None:
+        with TemporaryDirectory() as tmp_dir:
+            write_json(path.join(tmp_dir, "a.json"), {"include": "b.json"})
+            write_json(path.join(tmp_dir, "b.json"), {"include": "a.json"})
+            with self.assertRaises(numng.ManifestError) as context:
+                numng.read_package_file(path.join(tmp_dir, "a.json"))
+            self.assertIn("circular include", context.exception.message)
+
+
+class BuildSpecTest(unittest.TestCase):
+    def test_package_builder(self) -> None:
+        builder = numng.Package.builder("foo").build_spec("cargo", features=["sqlite"])
+        self.assertEqual(builder.to_json(), {"name": "foo", "build": {"kind": "cargo", "features": ["sqlite"]}})
+        package: numng.Package = builder.build()
+        self.assertEqual(package.name, "foo")
+        self.assertEqual(numng.get_build_command(package.extra_data or {}), 'cargo build --release --features "sqlite"')
+
+    def test_build_command_from_spec(self) -> None:
+        self.assertEqual(numng.build_command_from_spec("cargo"), "cargo build --release")
+        self.assertEqual(numng.build_command_from_spec({"kind": "cargo", "profile": "dev", "no_default_features": True}), "cargo build --no-default-features")
+        self.assertEqual(numng.build_command_from_spec({"kind": "make", "target": "all"}), 'make "all"')
+        self.assertIsNone(numng.build_command_from_spec("none"))
+        with self.assertRaises(AssertionError):
+            numng.build_command_from_spec({"kind": "make", "features": []})
+        with self.assertRaises(AssertionError):
+            numng.build_command_from_spec({"kind": "nu-script", "script": "../outside.nu"})
+
+    def test_nu_script_uses_the_configured_nu(self) -> None:
+        spec: Dict[str, str] = {"kind": "nu-script", "script": "build.nu"}
+        self.assertEqual(numng.build_command_from_spec(spec), 'nu --no-config-file "build.nu"')
+        self.assertEqual(numng.build_command_from_spec(spec, "/opt/nu/bin/nu"), '/opt/nu/bin/nu --no-config-file "build.nu"')
+        self.assertEqual(numng.build_command_from_spec(spec, "/opt/nu 0.99/nu"), '^"/opt/nu 0.99/nu" --no-config-file "build.nu"')
+
+
+class RecordingCommandRunner(numng.CommandRunner):
+    # every command "succeeds" without running anything
+    def __init__(self) -> None:
+        self.commands: List[List[str]] = []
+        self.cancellations: List[Optional[numng.CancellationToken]] = []
+
+    def run(self, command: List[str], cwd: Optional[str] = None, cancellation: Optional[numng.CancellationToken] = None, **kwargs) -> subprocess.CompletedProcess:
+        self.commands.append(command)
+        self.cancellations.append(cancellation)
+        return subprocess.CompletedProcess(command, 0, b"", b"")
+
+
+class RunContextTest(unittest.TestCase):
+    def test_loaders_keep_their_own_settings(self) -> None:
+        with numng.temporary_store() as store:
+            package_file: str = write_json(path.join(store, "numng.json"), {"name": "foo", "post_install": "print hi"})
+            first, second = RecordingCommandRunner(), RecordingCommandRunner()
+            cancellation = numng.CancellationToken()
+            numng.Loader(package_file, numng.BuildOptions(config=numng.Config(nu_binary="/opt/nu"), command_runner=first, cancellation=cancellation, allow_build_commands=True))
+            numng.Loader(package_file, numng.BuildOptions(command_runner=second, allow_build_commands=True))
+        self.assertEqual(first.commands, [["/opt/nu", "--no-config-file", "-c", "print hi"]])
+        self.assertEqual(first.cancellations, [cancellation])
+        self.assertEqual(second.commands, [["nu", "--no-config-file", "-c", "print hi"]])
+        self.assertIsNot(second.cancellations[0], cancellation)
+        self.assertEqual(numng.current_run_context().config.nu_binary, "nu")
+        self.assertIs(type(numng.current_run_context().runner), numng.CommandRunner)
+
+
+class FailingBuildRunner(numng.CommandRunner):
+    # git runs normally, build_commands only get recorded (`fail` fails)
+    def __init__(self) -> None:
+        self.builds: List[Tuple[str, Optional[str]]] = []
+
+    def run(self, command: List[str], cwd: Optional[str] = None, cancellation: Optional[numng.CancellationToken] = None, **kwargs) -> subprocess.CompletedProcess:
+        if command[0] != "nu":
+            return super().run(command, cwd=cwd, cancellation=cancellation, **kwargs)
+        self.builds.append((command[-1], cwd))
+        return subprocess.CompletedProcess(command, 1 if command[-1] == "fail" else 0, b"", b"")
+
+
+@unittest.skipIf(which("git") is None, "requires git")
+class BuildSchedulingTest(unittest.TestCase):
+    def test_packages_with_the_same_name_are_built_separately(self) -> None:
+        with numng.temporary_store() as store:
+            broken: str = git_repo(path.join(store, "broken"), {"name": "dup", "build_command": "fail"})
+            working: str = git_repo(path.join(store, "working"), {"name": "dup", "build_command": "ok"})
+            consumer: str = git_repo(path.join(store, "consumer"), {"name": "consumer", "build_command": "ok", "depends": [{"name": "dup", "source_uri": working}]})
+            package_file: str = write_json(path.join(store, "numng.json"), {"name": "root", "depends": [
+                {"name": "dup", "source_uri": broken},
+                {"name": "consumer", "source_uri": consumer},
+            ]})
+            runner = FailingBuildRunner()
+            with self.assertRaises(numng.BuildFailures) as context:
+                numng.Loader(package_file, numng.BuildOptions(command_runner=runner, allow_build_commands=True, keep_going=True))
+        # only the broken one failed -> the consumer of the working one still got built
+        self.assertEqual(len(context.exception.failures), 1)
+        self.assertIn(path.join("broken", "main"), next(iter(context.exception.failures)))
+        self.assertEqual(sorted(command for command, _ in runner.builds if command != "--version"), ["fail", "ok", "ok"])
+        self.assertTrue(any(cwd is not None and path.join("consumer", "main") in cwd for _, cwd in runner.builds))
+
+    def test_registry_dependencies_are_resolved_recursively(self) -> None:
+        with numng.temporary_store() as store:
+            registry = numng.MemoryPackageRegistry() \
+                .add("a", "1.0.0", {"source_uri": git_repo(path.join(store, "a"), {"name": "a"}), "depends": ["b"]}) \
+                .add("b", "1.0.0", {"source_uri": git_repo(path.join(store, "b"), {"name": "b"}), "depends": ["a", "c"]}) \
+                .add("c", "1.0.0", {"source_uri": git_repo(path.join(store, "c"), {"name": "c"})})
+            package_file: str = write_json(path.join(store, "numng.json"), {"name": "root", "depends": ["a"]})
+            loader = numng.Loader(package_file, numng.BuildOptions(registries=[registry]))
+        # "c" is only reachable through the registry entry of "b", and the a <-> b cycle terminates
+        self.assertEqual(sorted(package.name for _, package in loader.packages), ["a", "b", "c", "root"])
+        dependencies = {
+            package.name: sorted(loader.packages.get_package(dependency).name for dependency in loader.packages.dependencies(package_id))
+            for package_id, package in loader.packages
+        }
+        self.assertEqual(dependencies, {"root": ["a"], "a": ["b"], "b": ["a", "c"], "c": []})
+
+
+class JsonStateTest(unittest.TestCase):
+    def test_round_trip(self) -> None:
+        with TemporaryDirectory() as tmp_dir:
+            state_file: str = path.join(tmp_dir, "sub", "state.json")
+            self.assertEqual(numng.load_json_state(state_file, {}), {})
+            numng.save_json_state(state_file, {"a": 1})
+            self.assertEqual(numng.load_json_state(state_file, {}), {"a": 1})
+            self.assertEqual(listdir(path.dirname(state_file)), ["state.json"])  # no temporary files left behind
+
+    def test_broken_file_is_ignored(self) -> None:
+        with TemporaryDirectory() as tmp_dir:
+            state_file: str = path.join(tmp_dir, "state.json")
+            with open(state_file, "w") as fp:
+                fp.write('{"a": ')
+            self.assertEqual(numng.load_json_state(state_file, {}), {})
+            write_json(state_file, ["not", "a", "dict"])
+            self.assertEqual(numng.load_json_state(state_file, {}), {})
+
+
+@unittest.skipIf(which("git") is None, "requires git")
+class LockfileTest(unittest.TestCase):
+    def test_commits_are_locked(self) -> None:
+        with numng.temporary_store() as store:
+            dependency: str = git_repo(path.join(store, "dep"), {"name": "dep"})
+            commit: str = subprocess.run(["git", "rev-parse", "HEAD"], cwd=path.join(store, "dep"), stdout=subprocess.PIPE, check=True).stdout.decode().strip()
+            makedirs(project := path.join(store, "project"))
+            package_file: str = write_json(path.join(project, "numng.json"), {"name": "root", "depends": [{"name": "dep", "source_uri": dependency}]})
+            lockfile: str = path.join(project, "numng.lock.json")
+            numng.Loader(package_file, numng.BuildOptions(lockfile=lockfile))
+            with open(lockfile, "r") as fp:
+                self.assertEqual(json.load(fp)["git"], {dependency: {"main": commit}})
+            self.assertEqual(sorted(listdir(project)), ["numng.json", "numng.lock.json"])  # written via a temporary file
+            numng.Loader(package_file, numng.BuildOptions(lockfile=lockfile, locked=True))
+            write_json(lockfile, {"git": {}, "archive": {}})
+            with self.assertRaises(numng.LockfileError):
+                numng.Loader(package_file, numng.BuildOptions(lockfile=lockfile, locked=True))
+
+
+@unittest.skipIf(which("git") is None, "requires git")
+class GarbageCollectionTest(unittest.TestCase):
+    def test_removes_unused_checkouts(self) -> None:
+        with numng.temporary_store() as store:
+            used: str = git_repo(path.join(store, "used"), {"name": "used"})
+            unused: str = git_repo(path.join(store, "unused"), {"name": "unused"})
+            package_file: str = write_json(path.join(store, "numng.json"), {"name": "root", "depends": [{"name": "used", "source_uri": used}, {"name": "unused", "source_uri": unused}]})
+            numng.Loader(package_file, numng.BuildOptions())
+            write_json(package_file, {"name": "root", "depends": [{"name": "used", "source_uri": used}]})
+            numng.Loader(package_file, numng.BuildOptions())
+            self.assertEqual(numng.collect_garbage(dry_run=True), [numng.get_git_store_path(unused)])
+            self.assertTrue(path.isdir(numng.get_git_store_path(unused)))
+            self.assertEqual(numng.collect_garbage(), [numng.get_git_store_path(unused)])
+            self.assertFalse(path.exists(numng.get_git_store_path(unused)))
+            self.assertTrue(path.isdir(numng.get_git_ref_path(used, "main")))
+
+    def test_broken_environments_file_aborts(self) -> None:
+        with numng.temporary_store() as store:
+            dependency: str = git_repo(path.join(store, "dep"), {"name": "dep"})
+            package_file: str = write_json(path.join(store, "numng.json"), {"name": "root", "depends": [{"name": "dep", "source_uri": dependency}]})
+            numng.Loader(package_file, numng.BuildOptions())
+            with open(numng.data_path(numng.ENVIRONMENTS_FILE_NAME), "w") as fp:
+                fp.write('{"')
+            with self.assertRaises(AssertionError):
+                numng.collect_garbage()
+            self.assertTrue(path.isdir(numng.get_git_ref_path(dependency, "main")))
+
+
+@unittest.skipIf(which("git") is None, "requires git")
+class StoreDiskUsageTest(unittest.TestCase):
+    def test_entries(self) -> None:
+        with numng.temporary_store() as store:
+            dependency: str = git_repo(path.join(store, "dep"), {"name": "dep"})
+            package_file: str = write_json(path.join(store, "numng.json"), {"name": "root", "depends": [{"name": "dep", "source_uri": dependency}]})
+            numng.Loader(package_file, numng.BuildOptions())
+            entries: List[Dict[str, Any]] = numng.store_disk_usage()
+            repo: str = path.relpath(numng.get_git_store_path(dependency), path.join(store, "store"))
+        self.assertEqual(sorted((i["type"], i["name"]) for i in entries), [("git_bare", path.join(repo, "__bare__")), ("git_checkout", path.join(repo, "main"))])
+        self.assertTrue(all(i["size"] > 0 for i in entries))
+        self.assertEqual(entries, sorted(entries, key=lambda i: i["size"], reverse=True))
+
+    def test_format_size(self) -> None:
+        self.assertEqual(numng.format_size(512), "512B")
+        self.assertEqual(numng.format_size(1536), "1.5KiB")
+        self.assertEqual(numng.format_size(3 * 1024 ** 3), "3.0GiB")
+
+
+@unittest.skipIf(which("git") is None, "requires git")
+class VerifyStoreTest(unittest.TestCase):
+    def build(self, store: str) -> Tuple[str, str]:
+        makedirs(source := path.join(store, "dep"))
+        subprocess.run(["git", "init", "-q", "-b", "release/1.x"], cwd=source, check=True)
+        write_json(path.join(source, "numng.json"), {"name": "dep"})
+        subprocess.run(["git", "add", "-A"], cwd=source, check=True)
+        subprocess.run(["git", "-c", "user.name=numng", "-c", "user.email=numng@localhost", "commit", "-qm", "init"], cwd=source, check=True)
+        dependency: str = "file://" + source
+        package_file: str = write_json(path.join(store, "numng.json"), {"name": "root", "depends": [{"name": "dep", "source_uri": dependency, "git_ref": "release/1.x"}]})
+        numng.Loader(package_file, numng.BuildOptions())
+        return source, numng.get_git_store_path(dependency)
+
+    def corrupt(self, repo: str) -> None:
+        pack_dir: str = path.join(repo, "__bare__", "objects", "pack")
+        for i in listdir(pack_dir):
+            numng.unlink(path.join(pack_dir, i))
+
+    def test_repair(self) -> None:
+        with numng.temporary_store() as store:
+            _, repo = self.build(store)
+            self.assertEqual(numng.verify_store(), [])
+            self.corrupt(repo)
+            self.assertEqual(numng.verify_store(repair=True), [f"corrupted git repo {path.join(repo, '__bare__')}"])
+            self.assertEqual(numng.verify_store(), [])
+            # re-created from the original ref (the directory name is the filesystem_safe version of it)
+            self.assertEqual(sorted(listdir(repo)), ["__bare__", "__refs__.json", numng.filesystem_safe("release/1.x")])
+            self.assertTrue(path.isfile(path.join(repo, numng.filesystem_safe("release/1.x"), "numng.json")))
+
+    def test_failed_repair_keeps_the_repo(self) -> None:
+        with numng.temporary_store() as store:
+            source, repo = self.build(store)
+            self.corrupt(repo)
+            numng.rmtree(source)  # unable to clone it again
+            problems: List[str] = numng.verify_store(repair=True)
+            self.assertEqual(len(problems), 2)
+            self.assertTrue(problems[1].startswith("unable to re-clone"))
+            self.assertEqual(sorted(listdir(path.dirname(repo))), [path.basename(repo)])
+            self.assertTrue(path.isfile(path.join(repo, numng.filesystem_safe("release/1.x"), "numng.json")))
+
+
+@unittest.skipIf(which("git") is None, "requires git")
+class LatestGitRefTest(unittest.TestCase):
+    def used_refs(self, store: str, dependency: Dict[str, Any], **base_package: Any) -> List[str]:
+        package_file: str = write_json(path.join(store, "numng.json"), {"name": "root", "depends": [dependency], **base_package})
+        loader = numng.Loader(package_file, numng.BuildOptions())
+        return [i["ref"] for i in loader.report.sources.values()]
+
+    def test_tags_only_for_latest(self) -> None:
+        with numng.temporary_store() as store:
+            source: str = git_repo(path.join(store, "dep"), {"name": "dep"})
+            subprocess.run(["git", "tag", "v1.0.0"], cwd=path.join(store, "dep"), check=True)
+            subprocess.run(["git", "-c", "user.name=numng", "-c", "user.email=numng@localhost", "commit", "-q", "--allow-empty", "-m", "next"], cwd=path.join(store, "dep"), check=True)
+            self.assertEqual(self.used_refs(store, {"name": "dep", "source_uri": source}), ["main"])
+            self.assertEqual(self.used_refs(store, {"name": "dep", "source_uri": source, "version": "latest"}), ["v1.0.0"])
+            self.assertEqual(self.used_refs(store, {"name": "dep", "source_uri": source}, latest_git_ref="tag"), ["v1.0.0"])
+            self.assertEqual(self.used_refs(store, {"name": "dep", "source_uri": source, "version": "latest"}, latest_git_ref="branch"), ["main"])
+
+
+class TemporaryStoreTest(unittest.TestCase):
+    def test_only_used_by_the_current_thread(self) -> None:
+        default_directory: str = numng.data_path()
+        seen: List[str] = []
+        with numng.temporary_store() as store:
+            self.assertEqual(numng.data_path("store"), path.join(store, "store"))
+            self.assertEqual(numng.config_path(numng.CONFIG_FILE_NAME), path.join(store, "config", "config.json"))
+            thread = Thread(target=lambda: seen.append(numng.data_path()))
+            thread.start()
+            thread.join()
+            package_file: str = write_json(path.join(store, "numng.json"), {"name": "foo"})
+            numng.Loader(package_file, numng.BuildOptions())
+            self.assertIn(package_file, numng.load_known_environments())
+        self.assertEqual(seen, [default_directory])
+        self.assertEqual(numng.data_path(), default_directory)
+
+
+class RawFileRegistryTest(unittest.TestCase):
+    def test_raw_file_url(self) -> None:
+        self.assertEqual(numng.raw_file_url("https://github.com/foo/bar.git", "abc", "/a/b.json"), "https://raw.githubusercontent.com/foo/bar/abc/a/b.json")
+        self.assertEqual(numng.raw_file_url("https://codeberg.org/foo/bar", "abc", "b.json"), "https://codeberg.org/foo/bar/raw/abc/b.json")
+        self.assertIsNone(numng.raw_file_url("https://example.com/foo/bar", "abc", "b.json"))
+        self.assertIsNone(numng.raw_file_url("ssh://github.com/foo/bar", "abc", "b.json"))
+
+    def test_files_are_requested_by_commit(self) -> None:
+        with TemporaryDirectory() as tmp_dir:
+            write_json(local_file := path.join(tmp_dir, "foo.json"), {"1.0.0": {"source_uri": "https://example.com/foo"}})
+            requested: List[str] = []
+
+            def fetch(url: str) -> Optional[str]:
+                requested.append(url)
+                return local_file if url.endswith("/foo.json") else None
+            with mock.patch.object(numng, "fetch_raw_file", fetch):
+                registry = numng.RawFileNumngPackageRegistry("https://github.com/owner/registry", "0" * 40, "registry")
+                package = registry.get_by_name("foo")
+        assert package is not None
+        self.assertEqual(package.source_uri, "https://example.com/foo")
+        self.assertIn(f"https://raw.githubusercontent.com/owner/registry/{'0' * 40}/registry/foo.json", requested)
+
+    def test_failed_downloads_fall_back_to_a_clone(self) -> None:
+        with TemporaryDirectory() as tmp_dir:
+            write_json(path.join(tmp_dir, "foo.json"), {"1.0.0": {"source_uri": "https://example.com/foo"}})
+            clones: List[str] = []
+
+            def clone() -> str:
+                clones.append(tmp_dir)
+                return tmp_dir
+
+            def fetch(url: str) -> Optional[str]:
+                raise URLError("rate limited")
+            with mock.patch.object(numng, "fetch_raw_file", fetch):
+                registry = numng.RawFileNumngPackageRegistry("https://github.com/owner/registry", "0" * 40, fallback=clone)
+                self.assertIsNotNone(registry.get_by_name("foo"))
+                self.assertIsNone(registry.get_by_name("bar"))
+                self.assertEqual(registry.package_names(), ["foo"])
+        self.assertEqual(len(clones), 1)
+
+    def test_opt_in(self) -> None:
+        self.assertFalse(numng.Config().raw_registries)
+        self.assertIsNone(numng.BuildOptions().raw_registries)
+
+
+class EnvironmentStatusTest(unittest.TestCase):
+    def state(self, *packages: Tuple[str, str, str]) -> Dict[str, Any]:
+        return {"packages": [{"name": name, "path": package_path, "commit": commit} for name, package_path, commit in packages]}
+
+    def report(self, *packages: Tuple[str, str, str]) -> numng.BuildReport:
+        return numng.BuildReport(packages=[numng.BuildReportPackage(name=name, path=package_path, commit=commit) for name, package_path, commit in packages])
+
+    def test_changes(self) -> None:
+        status: Dict[str, List[str]] = numng.environment_status(
+            self.report(("a", "/store/a/main", "2"), ("b", "/store/b/v2", "3"), ("new", "/store/new/main", "1")),
+            self.state(("a", "/store/a/main", "1"), ("b", "/store/b/v1", "3"), ("old", "/store/old/main", "1")),
+        )
+        self.assertEqual(status, {"added": ["new"], "removed": ["old"], "updated": ["a", "b"], "rebuilt": []})
+
+    def test_packages_with_the_same_name(self) -> None:
+        status: Dict[str, List[str]] = numng.environment_status(
+            self.report(("dup", "/store/x/main", "1"), ("dup", "/store/y/main", "2")),
+            self.state(("dup", "/store/x/main", "1"), ("dup", "/store/y/main", "1")),
+        )
+        self.assertEqual(status, {"added": [], "removed": [], "updated": ["dup (/store/y/main)"], "rebuilt": []})
+        status = numng.environment_status(self.report(("dup", "/store/x/main", "1"), ("dup", "/store/y/main", "1")), self.state(("dup", "/store/x/main", "1")))
+        self.assertEqual(status["added"], ["dup (/store/y/main)"])
+
+
+class ExecTest(unittest.TestCase):
+    def run_main(self, *arguments: str) -> List[str]:
+        with numng.temporary_store() as store:
+            package_file: str = write_json(path.join(store, "numng.json"), {"name": "foo"})
+            with mock.patch.object(sys, "argv", ["numng", "--package-file", package_file, *arguments]), \
+                    mock.patch.object(numng.subprocess, "run", return_value=subprocess.CompletedProcess([], 0)) as run, \
+                    self.assertRaises(SystemExit):
+                contextvars.copy_context().run(numng.main)  # main sets the run context of its caller
+        return run.call_args.args[0]
+
+    def test_only_the_separator_is_removed(self) -> None:
+        self.assertTrue(self.run_main("exec", "--", "git", "log", "--", "file")[-1].endswith('; run-external "git" "log" "--" "file"'))
+        self.assertTrue(self.run_main("exec", "ls", "-la")[-1].endswith('; run-external "ls" "-la"'))
+
+
+@unittest.skipIf(which("git") is None, "requires git")
+class BundleTest(unittest.TestCase):
+    def build(self, store: str) -> Tuple[str, str, str]:
+        dependency: str = git_repo(path.join(store, "dep"), {"name": "dep"})
+        makedirs(project := path.join(store, "project"))
+        package_file: str = write_json(path.join(project, "numng.json"), {"name": "root", "depends": [{"name": "dep", "source_uri": dependency}]})
+        lockfile: str = path.join(project, "numng.lock.json")
+        numng.Loader(package_file, numng.BuildOptions(lockfile=lockfile))
+        return dependency, package_file, lockfile
+
+    def test_export(self) -> None:
+        with numng.temporary_store() as store:
+            dependency, package_file, lockfile = self.build(store)
+            bundle: Dict[str, Any] = numng.export_bundle(package_file, lockfile, bundle_file := path.join(store, "bundle.tar.gz"))
+            self.assertEqual((bundle["git"], bundle["archive"]), (1, 0))
+            with numng.tarfile.open(bundle_file, "r:*") as tar:
+                names: List[str] = tar.getnames()
+            bare_path: str = path.relpath(path.join(numng.get_git_store_path(dependency), "__bare__"), store)
+            self.assertEqual(names[:3], ["bundle.json", "numng.json", "numng.lock.json"])
+            self.assertIn(bare_path, names)
+            self.assertFalse(any("/worktrees" in i for i in names))  # contain absolute paths
+            self.assertFalse(path.exists(bundle_file + ".part"))
+
+    def test_export_requires_a_complete_store(self) -> None:
+        with numng.temporary_store() as store:
+            dependency, package_file, lockfile = self.build(store)
+            numng.rmtree(numng.get_git_store_path(dependency))
+            with self.assertRaises(AssertionError):
+                numng.export_bundle(package_file, lockfile, path.join(store, "bundle.tar.gz"))
+
+    def test_import_into_a_empty_store(self) -> None:
+        with TemporaryDirectory() as tmpdir:
+            with numng.temporary_store() as store:
+                dependency, package_file, lockfile = self.build(store)
+                numng.export_bundle(package_file, lockfile, bundle_file := path.join(tmpdir, "bundle.tar.gz"))
+            with numng.temporary_store():
+                bundle: Dict[str, Any] = numng.import_bundle(bundle_file, target_dir := path.join(tmpdir, "imported"))
+                self.assertEqual(bundle["package_file"], path.join(target_dir, "numng.json"))
+                self.assertTrue(path.isfile(path.join(numng.get_git_ref_path(dependency, "main"), "numng.json")))
+                numng.Loader(bundle["package_file"], numng.BuildOptions(lockfile=path.join(target_dir, "numng.lock.json"), locked=True, offline=True))
+
+    def test_import_rejects_unexpected_files(self) -> None:
+        with numng.temporary_store() as store:
+            with numng.tarfile.open(bundle_file := path.join(store, "bundle.tar.gz"), "w:gz") as tar:
+                tar.add(write_json(path.join(store, "bundle.json"), {"version": numng.BUNDLE_FORMAT_VERSION}), arcname="../bundle.json")
+            with self.assertRaises(AssertionError):
+                numng.import_bundle(bundle_file, path.join(store, "imported"))
+
+
+class ExtractTarTest(unittest.TestCase):
+    def archive(self, tmpdir: str, *members: numng.tarfile.TarInfo) -> str:
+        with numng.tarfile.open(tar_file := path.join(tmpdir, "test.tar"), "w:") as tar:
+            for member in members:
+                tar.addfile(member)
+        return tar_file
+
+    def link(self, name: str, target: str) -> numng.tarfile.TarInfo:
+        member = numng.tarfile.TarInfo(name)
+        member.type, member.linkname = numng.tarfile.SYMTYPE, target
+        return member
+
+    def test_without_the_data_filter(self) -> None:
+        # python versions before 3.12 (and the backports) don't know `filter="data"`
+        with TemporaryDirectory() as tmpdir, mock.patch.object(numng.tarfile, "data_filter", None, create=True):
+            with numng.tarfile.open(self.archive(tmpdir, numng.tarfile.TarInfo("ok/file"), self.link("ok/link", "file"))) as tar:
+                numng.extract_tar(tar, path.join(tmpdir, "ok"))
+            self.assertEqual(sorted(listdir(path.join(tmpdir, "ok", "ok"))), ["file", "link"])
+            for member in (numng.tarfile.TarInfo("../escape"), numng.tarfile.TarInfo("/abs"), self.link("link", "../../escape"), self.link("link", "/etc/passwd")):
+                with self.subTest(member=member.name, link=member.linkname), numng.tarfile.open(self.archive(tmpdir, member)) as tar:
+                    with self.assertRaises(AssertionError):
+                        numng.extract_tar(tar, path.join(tmpdir, "bad"))
+            self.assertFalse(path.exists(path.join(tmpdir, "escape")))
+
+
+@unittest.skipIf(which("git") is None, "requires git")
+class VendorTest(unittest.TestCase):
+    def test_vendored_sources_are_used(self) -> None:
+        with numng.temporary_store() as store:
+            dependency: str = git_repo(path.join(store, "dep"), {"name": "dep"})
+            makedirs(project := path.join(store, "project"))
+            package_file: str = write_json(path.join(project, "numng.json"), {"name": "root", "depends": [{"name": "dep", "source_uri": dependency}]})
+            loader = numng.Loader(package_file, numng.BuildOptions())
+            vendor_dir: str = path.join(project, numng.VENDOR_DIR_NAME)
+            manifest: Dict[str, Dict[str, Any]] = numng.vendor_sources(loader.report.sources, vendor_dir)
+            self.assertEqual(len(manifest), 1)
+            vendored: str = path.join(vendor_dir, next(iter(manifest.values()))["path"])
+            self.assertEqual(listdir(vendored), ["numng.json"])  # only the committed files (no .git)
+            # the store copy is no longer needed
+            numng.rmtree(numng.get_git_store_path(dependency))
+            numng.rmtree(path.join(store, "dep"))
+            numng.Loader(package_file, numng.BuildOptions(offline=True))
+            self.assertFalse(path.exists(numng.get_git_store_path(dependency)))
+
+    def test_refuses_to_overwrite_other_directories(self) -> None:
+        with numng.temporary_store() as store:
+            makedirs(vendor_dir := path.join(store, numng.VENDOR_DIR_NAME))
+            with self.assertRaises(AssertionError):
+                numng.vendor_sources({}, vendor_dir)
+            self.assertEqual(listdir(store), [numng.VENDOR_DIR_NAME])
+
+
+class OutputWritingRunner(numng.CommandRunner):
+    # git runs normally, build_commands write `out.txt` into the package
+    def __init__(self) -> None:
+        self.builds: List[Optional[str]] = []
+
+    def run(self, command: List[str], cwd: Optional[str] = None, cancellation: Optional[numng.CancellationToken] = None, **kwargs) -> subprocess.CompletedProcess:
+        if command[0] != "nu":
+            return super().run(command, cwd=cwd, cancellation=cancellation, **kwargs)
+        if command[-1] != "--version":
+            self.builds.append(cwd)
+            with open(path.join(cwd or ".", "out.txt"), "w") as fp:
+                fp.write("built")
+        return subprocess.CompletedProcess(command, 0, b"0.100.0", b"")
+
+
+@unittest.skipIf(which("git") is None, "requires git")
+class ArtifactCacheTest(unittest.TestCase):
+    def build(self, store: str, python_data_filter: bool = True) -> OutputWritingRunner:
+        runner = OutputWritingRunner()
+        package_file: str = write_json(path.join(store, "numng.json"), {"name": "root", "depends": [{"name": "dep", "source_uri": "file://" + path.join(store, "dep")}]})
+        with mock.patch.object(numng.tarfile, "data_filter", numng.tarfile.data_filter if python_data_filter else None, create=True):
+            numng.Loader(package_file, numng.BuildOptions(command_runner=runner, allow_build_commands=True))
+        return runner
+
+    def test_restores_the_build_output(self) -> None:
+        for python_data_filter in (True, False):
+            with self.subTest(python_data_filter=python_data_filter), numng.temporary_store() as store:
+                dependency: str = git_repo(path.join(store, "dep"), {"name": "dep", "build_command": "save"})
+                self.assertEqual(len(self.build(store).builds), 1)
+                self.assertEqual(len(listdir(numng.data_path(numng.ARTIFACT_CACHE_DIR_NAME))), 1)
+                numng.rmtree(numng.get_git_store_path(dependency))
+                self.assertEqual(self.build(store, python_data_filter).builds, [])
+                with open(path.join(numng.get_git_ref_path(dependency, "main"), "out.txt"), "r") as fp:
+                    self.assertEqual(fp.read(), "built")
+
+
+class LoadConfigTest(unittest.TestCase):
+    def test_invalid_types(self) -> None:
+        with TemporaryDirectory() as tmpdir:
+            for key, value in (("jobs", "4"), ("jobs", True), ("build_jobs", 0), ("nu_binary", 5), ("latest_git_ref", 1), ("raw_registries", "yes"), ("registries", {})):
+                config_file: str = write_json(path.join(tmpdir, "config.json"), {key: value})
+                with self.subTest(key=key, value=value), self.assertRaises(AssertionError) as context:
+                    numng.load_config(config_file)
+                self.assertIn(config_file, context.exception.args[0])
+                self.assertIn(key, context.exception.args[0])
+
+    def test_valid(self) -> None:
+        with TemporaryDirectory() as tmpdir:
+            config_file: str = write_json(path.join(tmpdir, "config.json"), {"jobs": 8, "nu_binary": "/opt/nu", "latest_git_ref": None, "connection_policy": " Offline"})
+            config: numng.Config = numng.load_config(config_file)
+        self.assertEqual((config.jobs, config.nu_binary, config.latest_git_ref, config.connection_policy), (8, "/opt/nu", None, "offline"))
+        self.assertEqual(numng.load_config(path.join(tmpdir, "missing.json")), numng.Config())
+
+
+class MigratePackerTest(unittest.TestCase):
+    def test_packages_and_flags(self) -> None:
+        with TemporaryDirectory() as tmpdir:
+            packer_file: str = path.join(tmpdir, "packer_packages.nu")
+            with open(packer_file, "w") as fp:
+                fp.write("\n".join([
+                    "use packer_api.nu [p]",
+                    "[",
+                    "  (p jan9103/nutils)  # comment (p ignored/package)",
+                    "  (p https://gitlab.com/foo/bar.git --branch dev)",
+                    "  (p github:foo/baz -d)",
+                    "  (p foo/qux --unknown)",
+                    "]",
+                ]))
+            depends, problems = numng.migrate_packer_config(packer_file)
+        self.assertEqual(depends, [
+            {"name": "packer.nu", "source_uri": "https://github.com/jan9103/packer.nu"},
+            {"name": "jan9103/nutils", "source_uri": "https://github.com/jan9103/nutils", "package_format": "packer"},
+            {"name": "foo/bar", "source_uri": "https://gitlab.com/foo/bar.git", "package_format": "packer", "git_ref": "dev"},
+            {"name": "foo/qux", "source_uri": "https://github.com/foo/qux", "package_format": "packer"},
+        ])
+        self.assertEqual(problems, [
+            "line 5: foo/baz is disabled (not migrated)",
+            "line 6: unsupported option `--unknown` of foo/qux (ignored)",
+        ])
+
+    def test_empty_config(self) -> None:
+        with TemporaryDirectory() as tmpdir:
+            with open(packer_file := path.join(tmpdir, "packer_packages.nu"), "w") as fp:
+                fp.write("[]")
+            depends, problems = numng.migrate_packer_config(packer_file)
+        self.assertEqual(len(depends), 1)
+        self.assertEqual(len(problems), 1)
+
+
+class MigrateNupmTest(unittest.TestCase):
+    def test_modules_and_scripts(self) -> None:
+        with TemporaryDirectory() as nupm_home:
+            for directory in (path.join(nupm_home, "modules", "nu-git"), path.join(nupm_home, "modules", "shared"), path.join(nupm_home, "scripts"), path.join(nupm_home, "bin")):
+                makedirs(directory)
+            for file in ("shared.nu", "tool.nu", "README.md"):
+                open(path.join(nupm_home, "scripts", file), "w").close()
+            open(path.join(nupm_home, "bin", "tool"), "w").close()
+            depends, problems = numng.migrate_nupm_home(nupm_home)
+        self.assertEqual(depends, [{"name": "nu-git"}, {"name": "shared"}, {"name": "tool"}])
+        self.assertEqual(len(problems), 2)
+        self.assertTrue(problems[0].startswith("bin/ contains tool"))
+
+    def test_refuses_numng_environments(self) -> None:
+        with TemporaryDirectory() as nupm_home:
+            write_json(path.join(nupm_home, numng.STATE_FILE_NAME), {})
+            with self.assertRaises(AssertionError):
+                numng.migrate_nupm_home(nupm_home)
+            with self.assertRaises(AssertionError):
+                numng.migrate_nupm_home(path.join(nupm_home, "missing"))
+
+
+class DoctorTest(unittest.TestCase):
+    def test_reports_problems_with_a_fix(self) -> None:
+        with numng.temporary_store() as store, mock.patch.object(numng.current_run_context().config, "nu_binary", path.join(store, "missing-nu")):
+            makedirs(numng.config_path())
+            write_json(numng.config_path(numng.CONFIG_FILE_NAME), {"jobs": "4"})
+            results: List[Tuple[bool, str]] = numng.run_doctor()
+        failed: List[str] = [message for ok, message in results if not ok and not message.startswith("git")]
+        self.assertEqual(len(failed), 2)
+        self.assertIn("missing-nu --version failed", failed[0])
+        self.assertTrue(failed[1].startswith("invalid config") and "jobs" in failed[1])
+        self.assertIn((True, f"data directory {store} is writable"), results)
+        self.assertIn((True, "the store is not locked"), results)
+
+
+class SelfUpdateTest(unittest.TestCase):
+    def update(self, own_file: str, new_content: bytes, sha: Optional[str] = None, check_only: bool = False) -> bool:
+        responses: Dict[str, bytes] = {
+            numng.SELF_UPDATE_URL: json.dumps({"sha": sha or numng.git_blob_sha1(new_content), "download_url": "https://example.com/numng.py"}).encode(),
+            "https://example.com/numng.py": new_content,
+        }
+
+        def urlopen(request: Any) -> io.BytesIO:
+            return io.BytesIO(responses[request if isinstance(request, str) else request.full_url])
+        with mock.patch.object(numng, "__file__", own_file), mock.patch("urllib.request.urlopen", urlopen):
+            return numng.self_update(check_only=check_only)
+
+    def test_update(self) -> None:
+        with TemporaryDirectory() as tmpdir:
+            with open(own_file := path.join(tmpdir, "numng.py"), "wb") as fp:
+                fp.write(b"old")
+            self.assertFalse(self.update(own_file, b"old"))
+            self.assertTrue(self.update(own_file, b"new", check_only=True))
+            with self.assertRaises(AssertionError):
+                self.update(own_file, b"new", sha=numng.git_blob_sha1(b"other"))  # checksum mismatch
+            with open(own_file, "rb") as fp:
+                self.assertEqual(fp.read(), b"old")
+            self.assertTrue(self.update(own_file, b"new"))
+            with open(own_file, "rb") as fp:
+                self.assertEqual(fp.read(), b"new")
+            self.assertEqual(listdir(tmpdir), ["numng.py"])
+
+    def test_refuses_to_update_a_numng_package(self) -> None:
+        with numng.temporary_store():
+            with self.assertRaises(AssertionError):
+                self.update(numng.data_path("store", "git", "numng", "main", "numng.py"), b"new")
+
+
+class CompositeRegistryTest(unittest.TestCase):
+    def test_first_registry_wins(self) -> None:
+        first = numng.MemoryPackageRegistry().add("a", "1.0.0", {"source_uri": "https://example.com/first/a"})
+        second = numng.MemoryPackageRegistry() \
+            .add("a", "1.0.0", {"source_uri": "https://example.com/second/a"}) \
+            .add("a", "2.0.0", {"source_uri": "https://example.com/second/a2"}) \
+            .add("b", "1.0.0", {"source_uri": "https://example.com/second/b"})
+        registry = numng.CompositePackageRegistry([first, second])
+        self.assertEqual(registry.get_by_name("a", version="1.0.0").source_uri, "https://example.com/first/a")
+        self.assertEqual(registry.get_by_name("a", version="^2").source_uri, "https://example.com/second/a2")
+        self.assertEqual(registry.get_by_name("b").source_uri, "https://example.com/second/b")
+        self.assertIsNone(registry.get_by_name("c"))
+        self.assertEqual(registry.package_names(), ["a", "b"])
+        self.assertEqual(registry.list_versions("a"), ["1.0.0", "2.0.0"])
+
+    def test_cache_id(self) -> None:
+        first, second = numng.MemoryPackageRegistry(), numng.MemoryPackageRegistry()
+        registry = numng.CompositePackageRegistry([first, second])
+        self.assertIsNone(registry.cache_id)  # not every registry is cacheable
+        first.cache_id, first.cache_revision, second.cache_id, second.cache_revision = "first", "1", "second", "1"
+        cache_id: Optional[str] = registry.cache_id
+        revision: Optional[str] = registry.cache_revision
+        second.cache_revision = "2"
+        self.assertEqual(registry.cache_id, cache_id)
+        self.assertNotEqual(registry.cache_revision, revision)
+        self.assertIsNone(numng.CompositePackageRegistry().cache_id)
+
+
+class CountingRegistry(numng.MemoryPackageRegistry):
+    def __init__(self) -> None:
+        super().__init__()
+        self.lookups: int = 0
+
+    def get_by_name(self, name: str, version: Optional[str] = None, **kwargs) -> Optional[numng.Package]:
+        self.lookups += 1
+        return super().get_by_name(name, version=version, **kwargs)
+
+
+class CachingRegistryTest(unittest.TestCase):
+    def test_lookups_are_cached(self) -> None:
+        inner = CountingRegistry().add("a", "1.0.0", {"source_uri": "https://example.com/a"})
+        registry = numng.CachingPackageRegistry(inner)
+        for _ in range(2):
+            self.assertEqual(registry.get_by_name("a").source_uri, "https://example.com/a")
+            self.assertIsNone(registry.get_by_name("missing"))
+        self.assertEqual(inner.lookups, 2)
+        registry.get_by_name("a").source_uri = "https://example.com/modified"  # no shared objects
+        self.assertEqual(registry.get_by_name("a").source_uri, "https://example.com/a")
+        self.assertFalse(registry.changed)  # no cache_id -> nothing to save
+
+    def test_persistent_cache(self) -> None:
+        inner = CountingRegistry().add("a", "1.0.0", {"source_uri": "https://example.com/a"})
+        inner.cache_id, inner.cache_revision = "registry", "1"
+        cache: Dict[str, Dict[str, Any]] = {}
+        numng.CachingPackageRegistry(inner, cache).get_by_name("a")
+        numng.CachingPackageRegistry(inner, cache).get_by_name("a")
+        self.assertEqual(inner.lookups, 1)
+        inner.cache_revision = "2"  # the registry changed
+        numng.CachingPackageRegistry(inner, cache).get_by_name("a")
+        self.assertEqual(inner.lookups, 2)
+        cache["registry"]["time"] = 0
+        numng.CachingPackageRegistry(inner, cache, ttl=60).get_by_name("a")
+        self.assertEqual(inner.lookups, 3)
+
+
+@unittest.skipIf(which("git") is None, "requires git")
+class ResumeTest(unittest.TestCase):
+    def test_finished_builds_are_not_repeated(self) -> None:
+        with numng.temporary_store() as store:
+            package_file: str = write_json(path.join(store, "numng.json"), {"name": "root", "artifact_cache": False, "depends": [
+                {"name": "a", "source_uri": git_repo(path.join(store, "a"), {"name": "a", "build_command": "ok"})},
+                {"name": "b", "source_uri": git_repo(path.join(store, "b"), {"name": "b", "build_command": "fail"})},
+            ]})
+            nupm_home: str = path.join(store, "env", "nupm_home")
+            runner = FailingBuildRunner()
+            with self.assertRaises(numng.BuildFailures):
+                numng.Loader(package_file, numng.BuildOptions(nupm_home=nupm_home, command_runner=runner, allow_build_commands=True, keep_going=True, handle_nu_plugins=False))
+            self.assertEqual(sorted(command for command, _ in runner.builds), ["fail", "ok"])
+            self.assertEqual(len(numng.load_build_progress(nupm_home)), 1)
+            # the next run only repeats the failed build
+            runner = FailingBuildRunner()
+            with self.assertRaises(numng.BuildFailures):
+                numng.Loader(package_file, numng.BuildOptions(nupm_home=nupm_home, command_runner=runner, allow_build_commands=True, keep_going=True, handle_nu_plugins=False))
+            self.assertEqual([command for command, _ in runner.builds], ["fail"])
+            # without incremental builds everything gets built again
+            runner = FailingBuildRunner()
+            with self.assertRaises(numng.BuildFailures):
+                numng.Loader(package_file, numng.BuildOptions(nupm_home=nupm_home, command_runner=runner, allow_build_commands=True, keep_going=True, handle_nu_plugins=False, incremental=False))
+            self.assertEqual(sorted(command for command, _ in runner.builds), ["fail", "ok"])
+
+
+class SearchTest(unittest.TestCase):
+    INDEX: List[Dict[str, Any]] = [
+        {"name": "nu-git", "description": "Git helpers", "keywords": ["vcs"]},
+        {"name": "gitignore", "description": "Generate .gitignore files"},
+        {"name": "weather", "description": "Forecasts", "keywords": ["api", "git"]},
+    ]
+
+    def test_name_matches_first(self) -> None:
+        self.assertEqual([i["name"] for i in numng.search_packages(self.INDEX, "GIT")], ["gitignore", "nu-git", "weather"])
+        self.assertEqual([i["name"] for i in numng.search_packages(self.INDEX, "git vcs")], ["nu-git"])
+        self.assertEqual(numng.search_packages(self.INDEX, "missing"), [])
+
+    def test_registry_index(self) -> None:
+        with TemporaryDirectory() as registry_dir:
+            write_json(path.join(registry_dir, "nu-git.json"), {"1.0.0": {"source_uri": "https://example.com/nu-git"}})
+            self.assertEqual(numng.NumngPackageRegistry(registry_dir).search_index(), [{"name": "nu-git"}])  # only the names
+            write_json(path.join(registry_dir, numng.SEARCH_INDEX_FILE), self.INDEX)
+            registry = numng.NumngPackageRegistry(registry_dir)
+            self.assertEqual(registry.search_index(), self.INDEX)
+            self.assertEqual(registry.package_names(), ["nu-git"])  # the index is not a package
+            write_json(path.join(registry_dir, numng.SEARCH_INDEX_FILE), {"nu-git": {}})
+            with self.assertRaises(AssertionError):
+                registry.search_index()
+
+    def test_combined_index(self) -> None:
+        first = numng.MemoryPackageRegistry().add("nu-git", "1.0.0", {"source_uri": "https://example.com/nu-git"})
+        second = numng.MemoryPackageRegistry().add("nu-git", "2.0.0", {"source_uri": "https://example.com/other"}).add("weather", "1.0.0", {"source_uri": "https://example.com/weather"})
+        second.search_index = lambda: self.INDEX  # type: ignore
+        self.assertEqual(numng.CompositePackageRegistry([first, second]).search_index(), [{"name": "gitignore", "description": "Generate .gitignore files"}, {"name": "nu-git"}, self.INDEX[2]])
+
+
+if __name__ == "__main__":
+    unittest.main()